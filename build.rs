@@ -1,8 +1,24 @@
+use std::env;
 use std::process::Command;
 
+const DEFAULT_DXC: &str = "C:\\Program Files (x86)\\Windows Kits\\10\\bin\\10.0.22621.0\\x64\\dxc.exe"; // This is extreme laziness
+
 fn main() {
     println!("cargo:rerun-if-changed=src/shaders/shaders.hlsl");
-    Command::new("C:\\Program Files (x86)\\Windows Kits\\10\\bin\\10.0.22621.0\\x64\\dxc.exe") // This is extreme laziness
+    println!("cargo:rerun-if-env-changed=DXC");
+    println!("cargo:rustc-check-cfg=cfg(d3d12)");
+
+    // The D3D12 backend only exists on Windows, without it there are no shaders to compile
+    let windows = env::var("CARGO_CFG_WINDOWS").is_ok();
+    let d3d12 = env::var("CARGO_FEATURE_D3D12").is_ok();
+    if !(windows && d3d12) {
+        return;
+    }
+
+    println!("cargo:rustc-cfg=d3d12");
+
+    let dxc = env::var("DXC").unwrap_or_else(|_| DEFAULT_DXC.to_string());
+    Command::new(dxc)
         .args([
             "src/shaders/shaders.hlsl",
             "/T",
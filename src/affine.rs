@@ -0,0 +1,288 @@
+//! Affine transforms packed the way D3D12_RAYTRACING_INSTANCE_DESC's Transform packs them: the top
+//! three rows of a 4x4 matrix for transforming column vectors, row major. The bottom row of an
+//! affine transform is always (0, 0, 0, 1), so it's left out.
+
+use nalgebra::{
+    Isometry3, Matrix3, Matrix4, Point3, Rotation3, Similarity3, Translation3, UnitQuaternion,
+    Vector3,
+};
+use std::error::Error;
+use std::fmt;
+
+/// How far the columns of a similarity's rotation can be from orthonormal after scaling, to allow
+/// for rounding in whatever built it
+const TOLERANCE: f32 = 1e-4;
+
+/// An affine transform with the layout of the instance desc's Transform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Affine3x4(pub [f32; 12]);
+
+impl Affine3x4 {
+    /// Flattens everything it's applied to onto the origin
+    pub const ZERO: Self = Self([0.0; 12]);
+
+    fn from_parts(linear: &Matrix3<f32>, translation: &Vector3<f32>) -> Self {
+        Self(std::array::from_fn(|i| {
+            let (row, column) = (i / 4, i % 4);
+            if column == 3 {
+                translation[row]
+            } else {
+                linear[(row, column)]
+            }
+        }))
+    }
+
+    /// The rotation, scale and shear, without the translation
+    pub fn linear(&self) -> Matrix3<f32> {
+        let m = &self.0;
+        Matrix3::new(m[0], m[1], m[2], m[4], m[5], m[6], m[8], m[9], m[10])
+    }
+
+    pub fn translation(&self) -> Vector3<f32> {
+        Vector3::new(self.0[3], self.0[7], self.0[11])
+    }
+
+    pub fn transform_point(&self, point: &Point3<f32>) -> Point3<f32> {
+        Point3::from(self.linear() * point.coords + self.translation())
+    }
+
+    /// Transforms a direction or offset, which the translation doesn't apply to
+    pub fn transform_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        self.linear() * vector
+    }
+
+    /// The transform that undoes this one, or None if it flattens space onto a plane, line or point
+    pub fn inverse(&self) -> Option<Self> {
+        let linear = self.linear().try_inverse()?;
+        Some(Self::from_parts(&linear, &-(linear * self.translation())))
+    }
+
+    /// The inverse transpose of the linear part, which takes normals to where they stay
+    /// perpendicular to the transformed surface. They'll need normalizing again afterwards. Zero if
+    /// the transform can't be inverted, since the surface is flattened and has no normal left.
+    pub fn normal_matrix(&self) -> Matrix3<f32> {
+        self.linear()
+            .try_inverse()
+            .map_or_else(Matrix3::zeros, |inverse| inverse.transpose())
+    }
+}
+
+/// Keeps the top three rows, so `m` has to be affine
+impl From<Matrix4<f32>> for Affine3x4 {
+    fn from(m: Matrix4<f32>) -> Self {
+        Self(std::array::from_fn(|i| m[(i / 4, i % 4)]))
+    }
+}
+
+impl From<Isometry3<f32>> for Affine3x4 {
+    fn from(isometry: Isometry3<f32>) -> Self {
+        isometry.to_homogeneous().into()
+    }
+}
+
+impl From<Similarity3<f32>> for Affine3x4 {
+    fn from(similarity: Similarity3<f32>) -> Self {
+        similarity.to_homogeneous().into()
+    }
+}
+
+impl From<Affine3x4> for Matrix4<f32> {
+    fn from(affine: Affine3x4) -> Self {
+        let mut rows = [0.0; 16];
+        rows[..12].copy_from_slice(&affine.0);
+        rows[15] = 1.0;
+        Matrix4::from_row_slice(&rows)
+    }
+}
+
+/// Why an `Affine3x4` can't be turned back into an isometry or similarity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecomposeError {
+    /// It shears, mirrors or scales some axes more than others
+    Distorts,
+    /// It scales, which an isometry can't
+    Scales,
+}
+
+impl fmt::Display for DecomposeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Distorts => write!(f, "Transform shears, mirrors or scales unevenly"),
+            Self::Scales => write!(f, "Transform scales"),
+        }
+    }
+}
+
+impl Error for DecomposeError {}
+
+impl TryFrom<Affine3x4> for Similarity3<f32> {
+    type Error = DecomposeError;
+
+    fn try_from(affine: Affine3x4) -> Result<Self, DecomposeError> {
+        let linear = affine.linear();
+        let scale = linear.column(0).norm();
+        if scale == 0.0 || !scale.is_finite() {
+            return Err(DecomposeError::Distorts);
+        }
+
+        let rotation = linear / scale;
+        let error = (rotation.transpose() * rotation - Matrix3::identity())
+            .abs()
+            .max();
+        if error > TOLERANCE || rotation.determinant() < 0.0 {
+            return Err(DecomposeError::Distorts);
+        }
+
+        let rotation = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix(&rotation));
+        Ok(Similarity3::from_parts(
+            Translation3::from(affine.translation()),
+            rotation,
+            scale,
+        ))
+    }
+}
+
+impl TryFrom<Affine3x4> for Isometry3<f32> {
+    type Error = DecomposeError;
+
+    fn try_from(affine: Affine3x4) -> Result<Self, DecomposeError> {
+        let similarity = Similarity3::try_from(affine)?;
+        if (similarity.scaling() - 1.0).abs() > TOLERANCE {
+            return Err(DecomposeError::Scales);
+        }
+
+        Ok(similarity.isometry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: &[f32], b: &[f32]) {
+        let error = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 1e-5, "{a:?} != {b:?}");
+    }
+
+    /// Scales unevenly, so normals can't just be rotated along with the surface
+    fn stretched() -> Matrix4<f32> {
+        Matrix4::new_translation(&Vector3::new(1.0, -2.0, 3.0))
+            * Matrix4::new_rotation(Vector3::new(0.3, -0.2, 0.5))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(4.0, 1.0, 0.5))
+    }
+
+    #[test]
+    fn packs_rows_like_the_instance_desc() {
+        let m = stretched();
+        let affine = Affine3x4::from(m);
+        for row in 0..3 {
+            assert_eq!(
+                affine.0[row * 4..row * 4 + 4],
+                *m.row(row).transpose().as_slice()
+            );
+        }
+        assert_eq!([affine.0[3], affine.0[7], affine.0[11]], [1.0, -2.0, 3.0]);
+
+        // Both ways through the GPU layout
+        assert_eq!(Matrix4::from(Affine3x4(affine.0)), m);
+    }
+
+    #[test]
+    fn transforms_like_the_matrix() {
+        let m = stretched();
+        let affine = Affine3x4::from(m);
+        let point = Point3::new(0.5, 7.0, -1.0);
+        assert_near(
+            affine.transform_point(&point).coords.as_slice(),
+            m.transform_point(&point).coords.as_slice(),
+        );
+        assert_near(
+            affine.transform_vector(&point.coords).as_slice(),
+            m.transform_vector(&point.coords).as_slice(),
+        );
+    }
+
+    #[test]
+    fn converts_isometries_and_similarities() {
+        let rotation = UnitQuaternion::from_scaled_axis(Vector3::new(0.1, 0.9, -0.4));
+        let translation = Translation3::new(5.0, 0.0, -1.0);
+        let similarity = Similarity3::from_parts(translation, rotation, 2.5);
+
+        let affine = Affine3x4::from(similarity);
+        assert_near(
+            Matrix4::from(affine).as_slice(),
+            similarity.to_homogeneous().as_slice(),
+        );
+        let back = Similarity3::try_from(affine).unwrap();
+        assert_near(
+            back.to_homogeneous().as_slice(),
+            similarity.to_homogeneous().as_slice(),
+        );
+        assert_eq!(Isometry3::try_from(affine), Err(DecomposeError::Scales));
+
+        let isometry = Isometry3::from_parts(translation, rotation);
+        let back = Isometry3::try_from(Affine3x4::from(isometry)).unwrap();
+        assert_near(
+            back.to_homogeneous().as_slice(),
+            isometry.to_homogeneous().as_slice(),
+        );
+
+        let mirrored = Matrix4::new_nonuniform_scaling(&Vector3::new(-1.0, 1.0, 1.0));
+        for m in [stretched(), mirrored, Matrix4::zeros()] {
+            assert_eq!(
+                Similarity3::try_from(Affine3x4::from(m)),
+                Err(DecomposeError::Distorts)
+            );
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let affine = Affine3x4::from(stretched());
+        let inverse = affine.inverse().unwrap();
+        let point = Point3::new(-3.0, 0.25, 8.0);
+        assert_near(
+            inverse
+                .transform_point(&affine.transform_point(&point))
+                .coords
+                .as_slice(),
+            point.coords.as_slice(),
+        );
+        assert_near(
+            Matrix4::from(inverse).as_slice(),
+            stretched().try_inverse().unwrap().as_slice(),
+        );
+
+        let flat = Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 0.0, 1.0));
+        assert_eq!(Affine3x4::from(flat).inverse(), None);
+        assert_eq!(Affine3x4::from(flat).normal_matrix(), Matrix3::zeros());
+    }
+
+    #[test]
+    fn normals_stay_perpendicular() {
+        let affine = Affine3x4::from(stretched());
+        // A tilted plane and two directions along it
+        let normal = Vector3::new(1.0, 1.0, 1.0);
+        let along = [Vector3::new(1.0, -1.0, 0.0), Vector3::new(0.0, 1.0, -1.0)];
+
+        let world_normal = affine.normal_matrix() * normal;
+        for along in along {
+            let world_along = affine.transform_vector(&along);
+            assert!(world_normal.normalize().dot(&world_along.normalize()).abs() < 1e-5);
+        }
+
+        // Only rotating the normal with the surface gets it wrong
+        let rotated = affine.linear() * normal;
+        assert!(
+            rotated
+                .normalize()
+                .dot(&affine.transform_vector(&along[0]).normalize())
+                .abs()
+                > 0.1
+        );
+    }
+}
@@ -0,0 +1,244 @@
+pub const USAGE: &str = "\
+Usage:
+    tracer [--scene <PATH>]     Render the scene in a window
+    tracer headless [options]   Render frames to image files without creating a window
+    tracer stats <SCENE> [options]
+                                Build BVHs over the meshes of a scene and report on their quality
+    tracer bake <SCENE>         Import a scene and build its BVHs into <SCENE>.cache, which loading
+                                the scene uses instead for as long as its files don't change
+
+Options:
+    --scene <PATH>         Scene description or glTF file to render instead of the built in scene
+
+Window controls:
+    W, A, S, D             Fly forward, left, back and right
+    Q, E                   Fly down and up
+    Right mouse button     Hold to look around
+    Scroll wheel           Move along the view direction
+    Shift                  Hold to move faster
+
+Headless options:
+    --size <WIDTHxHEIGHT>  Resolution of the images (default 1280x720)
+    --frames <N>           Number of frames to render (default 1)
+    --fps <N>              Frame N is rendered N / fps seconds into the animation (default 60)
+    --output <PATH>        Where to write each frame, with {frame} replaced by the frame number.
+                           The extension picks the format: png, exr, pfm or ppm
+                           (default frame_{frame}.png)
+    --cpu                  Use the CPU backend even when D3D12 is available
+    --threads <N>          Number of threads the CPU backend renders with (default one per core)
+
+Stats options:
+    --max-leaf-size <N>    Leaves with more triangles than this are always split (default 4)
+    --bins <N>             Number of bins SAH splits are chosen from along each axis (default 16)
+    --spatial-splits <N>   Split triangles between nodes, adding up to N references per triangle,
+                           instead of what the scene sets for each mesh. 0 turns them off.
+    --wireframe <PATH>     Where to write the node bounds of each mesh as an OBJ wireframe, with
+                           {mesh} replaced by the mesh number";
+
+use crate::bvh::BvhOptions;
+
+pub struct HeadlessArgs {
+    pub scene: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    pub fps: f32,
+    pub output: String,
+    pub cpu: bool,
+    /// 0 for one per core
+    pub threads: usize,
+}
+
+pub struct StatsArgs {
+    pub scene: String,
+    pub options: BvhOptions,
+    pub wireframe: Option<String>,
+}
+
+pub enum Command {
+    Window { scene: Option<String> },
+    Headless(HeadlessArgs),
+    Stats(StatsArgs),
+    Bake { scene: String },
+}
+
+impl HeadlessArgs {
+    pub fn output_path(&self, frame: u32) -> String {
+        self.output.replace("{frame}", &format!("{frame:04}"))
+    }
+}
+
+impl StatsArgs {
+    pub fn wireframe_path(&self, mesh: usize) -> Option<String> {
+        let wireframe = self.wireframe.as_ref()?;
+        Some(wireframe.replace("{mesh}", &format!("{mesh:04}")))
+    }
+}
+
+impl Default for HeadlessArgs {
+    fn default() -> Self {
+        Self {
+            scene: None,
+            width: 1280,
+            height: 720,
+            frames: 1,
+            fps: 60.0,
+            output: "frame_{frame}.png".to_string(),
+            cpu: false,
+            threads: 0,
+        }
+    }
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    let width = width.parse().ok().filter(|&w| w > 0)?;
+    let height = height.parse().ok().filter(|&h| h > 0)?;
+    Some((width, height))
+}
+
+fn parse_headless(mut args: impl Iterator<Item = String>) -> Result<HeadlessArgs, String> {
+    let mut headless = HeadlessArgs::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} requires a value"));
+        match arg.as_str() {
+            "--size" => {
+                let size = value()?;
+                (headless.width, headless.height) =
+                    parse_size(&size).ok_or(format!("Invalid size: {size}"))?;
+            }
+            "--frames" => {
+                let frames = value()?;
+                headless.frames = frames
+                    .parse()
+                    .map_err(|_| format!("Invalid frame count: {frames}"))?;
+            }
+            "--fps" => {
+                let fps = value()?;
+                headless.fps = fps
+                    .parse()
+                    .ok()
+                    .filter(|&fps: &f32| fps > 0.0)
+                    .ok_or(format!("Invalid frame rate: {fps}"))?;
+            }
+            "--scene" => headless.scene = Some(value()?),
+            "--output" => headless.output = value()?,
+            "--cpu" => headless.cpu = true,
+            "--threads" => headless.threads = parse_count("thread count", &value()?)?,
+            _ => return Err(format!("Unknown option: {arg}")),
+        }
+    }
+
+    if headless.frames > 1 && !headless.output.contains("{frame}") {
+        return Err("--output must contain {frame} when rendering more than one frame".into());
+    }
+
+    Ok(headless)
+}
+
+fn parse_count(what: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or(format!("Invalid {what}: {value}"))
+}
+
+fn parse_stats(mut args: impl Iterator<Item = String>) -> Result<StatsArgs, String> {
+    let mut stats = StatsArgs {
+        scene: args.next().ok_or("stats requires a scene")?,
+        options: BvhOptions::default(),
+        wireframe: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} requires a value"));
+        match arg.as_str() {
+            "--max-leaf-size" => {
+                stats.options.max_leaf_size = parse_count("leaf size", &value()?)?;
+            }
+            "--bins" => stats.options.num_bins = parse_count("bin count", &value()?)?,
+            "--spatial-splits" => {
+                let value = value()?;
+                let budget = value
+                    .parse()
+                    .ok()
+                    .filter(|&b: &f32| b.is_finite() && b >= 0.0)
+                    .ok_or(format!("Invalid spatial split budget: {value}"))?;
+                stats.options.spatial_splits = Some(budget);
+            }
+            "--wireframe" => stats.wireframe = Some(value()?),
+            _ => return Err(format!("Unknown option: {arg}")),
+        }
+    }
+
+    Ok(stats)
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    match args.next().as_deref() {
+        None => Ok(Command::Window { scene: None }),
+        Some("--scene") => {
+            let scene = args.next().ok_or("--scene requires a value")?;
+            match args.next() {
+                None => Ok(Command::Window { scene: Some(scene) }),
+                Some(arg) => Err(format!("Unknown option: {arg}")),
+            }
+        }
+        Some("headless") => Ok(Command::Headless(parse_headless(args)?)),
+        Some("stats") => Ok(Command::Stats(parse_stats(args)?)),
+        Some("bake") => {
+            let scene = args.next().ok_or("bake requires a scene")?;
+            match args.next() {
+                None => Ok(Command::Bake { scene }),
+                Some(arg) => Err(format!("Unknown option: {arg}")),
+            }
+        }
+        Some(command) => Err(format!("Unknown command: {command}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless(args: &str) -> Result<HeadlessArgs, String> {
+        parse_headless(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn headless_options_are_parsed() {
+        let args = headless(
+            "--size 640x480 --frames 3 --fps 30 --output f{frame}.exr --cpu --threads 2 --scene a.ron",
+        )
+        .unwrap();
+        assert_eq!((args.width, args.height), (640, 480));
+        assert_eq!((args.frames, args.fps), (3, 30.0));
+        assert_eq!(args.output_path(12), "f0012.exr");
+        assert_eq!(args.scene.as_deref(), Some("a.ron"));
+        assert!(args.cpu);
+        assert_eq!(args.threads, 2);
+
+        let defaults = headless("").unwrap();
+        assert_eq!((defaults.width, defaults.height), (1280, 720));
+        assert_eq!(defaults.output_path(0), "frame_0000.png");
+    }
+
+    #[test]
+    fn bad_headless_options_are_rejected() {
+        let error = |args| headless(args).err().unwrap();
+        assert_eq!(error("--size 0x10"), "Invalid size: 0x10");
+        assert_eq!(error("--size 640"), "Invalid size: 640");
+        assert_eq!(
+            error("--frames 2 --output out.png"),
+            "--output must contain {frame} when rendering more than one frame"
+        );
+        assert_eq!(error("--fps 0"), "Invalid frame rate: 0");
+        assert_eq!(error("--frames"), "--frames requires a value");
+        assert_eq!(error("--gpu"), "Unknown option: --gpu");
+
+        // A single frame can go to a fixed path
+        assert!(headless("--frames 1 --output out.png").is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+//! Per-vertex normals and UVs, interpolated across the triangle a ray hits with the hit
+//! barycentrics. The D3D12 scene uploads every mesh's attributes as one `PackedAttributes` into the
+//! attribute buffers of shaders/shaders.hlsl, and `PackedAttributes::interpolate` works out what the
+//! shader's InterpolateAttributes does so the CPU tracer shades with the same normals and UVs.
+
+use crate::scene_model::Mesh;
+use nalgebra::{Vector2, Vector3};
+
+/// A mesh's attributes where a ray hit it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HitAttributes {
+    /// Unit length, in object space
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+}
+
+/// Every mesh's attributes one after another, the way the shader's attribute buffers hold them
+#[derive(Clone, Debug, Default)]
+pub struct PackedAttributes {
+    /// Each mesh's indices, offset to pick its vertices out of the combined normals and UVs. The
+    /// BLASes are built from index buffers of their own.
+    pub indices: Vec<u32>,
+    /// Meshes without normals get smooth ones
+    pub normals: Vec<[f32; 3]>,
+    /// Zero for meshes without UVs
+    pub uvs: Vec<[f32; 2]>,
+    /// Where each mesh's first triangle starts in `indices`
+    pub first_indices: Vec<u32>,
+}
+
+impl PackedAttributes {
+    pub fn new(meshes: &[Mesh]) -> Self {
+        let mut packed = Self::default();
+        for mesh in meshes {
+            let first_vertex = packed.normals.len() as u32;
+            packed.first_indices.push(packed.indices.len() as u32);
+            packed
+                .indices
+                .extend(mesh.indices.iter().map(|i| first_vertex + i));
+
+            let normals = if mesh.normals.is_empty() {
+                smooth_normals(&mesh.positions, &mesh.indices)
+            } else {
+                mesh.normals.clone()
+            };
+            packed
+                .normals
+                .extend(normals.chunks_exact(3).map(|n| [n[0], n[1], n[2]]));
+
+            let num_vertices = mesh.positions.len() / 3;
+            if mesh.uvs.is_empty() {
+                packed.uvs.resize(packed.uvs.len() + num_vertices, [0.0; 2]);
+            } else {
+                packed
+                    .uvs
+                    .extend(mesh.uvs.chunks_exact(2).map(|uv| [uv[0], uv[1]]));
+            }
+        }
+
+        packed
+    }
+
+    /// Equivalent of InterpolateAttributes, the attributes where a ray hit triangle `primitive` of
+    /// `mesh`. `barycentrics` weight the triangle's second and third vertices like
+    /// BuiltInTriangleIntersectionAttributes, and the first gets what's left.
+    pub fn interpolate(
+        &self,
+        mesh: usize,
+        primitive: usize,
+        barycentrics: Vector2<f32>,
+    ) -> HitAttributes {
+        let first = self.first_indices[mesh] as usize + primitive * 3;
+        let weights = [
+            1.0 - barycentrics.x - barycentrics.y,
+            barycentrics.x,
+            barycentrics.y,
+        ];
+
+        let mut normal = Vector3::zeros();
+        let mut uv = Vector2::zeros();
+        for (&vertex, weight) in self.indices[first..first + 3].iter().zip(weights) {
+            normal += Vector3::from(self.normals[vertex as usize]) * weight;
+            uv += Vector2::from(self.uvs[vertex as usize]) * weight;
+        }
+
+        HitAttributes {
+            normal: normal.normalize(),
+            uv,
+        }
+    }
+}
+
+/// Normals for a mesh that came without any, laid out like `Mesh::normals`. Each vertex gets the
+/// average of the normals of the triangles around it, weighted by their areas. Vertices that only
+/// touch triangles without any area are left facing +Y rather than nowhere.
+pub fn smooth_normals(positions: &[f32], indices: &[u32]) -> Vec<f32> {
+    let position = |i: u32| Vector3::from_column_slice(&positions[i as usize * 3..][..3]);
+
+    let mut normals = vec![Vector3::zeros(); positions.len() / 3];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(position);
+        // Twice the area long, and facing out of the front of a clockwise triangle like D3D's
+        let normal = (b - a).cross(&(c - a));
+        for &i in triangle {
+            normals[i as usize] += normal;
+        }
+    }
+
+    normals
+        .iter()
+        .flat_map(|n| {
+            let n = n.try_normalize(0.0).unwrap_or_else(Vector3::y);
+            [n.x, n.y, n.z]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::assert_near;
+
+    fn mesh(positions: &[f32], normals: &[f32], uvs: &[f32], indices: &[u32]) -> Mesh {
+        Mesh {
+            name: String::new(),
+            positions: positions.to_vec(),
+            normals: normals.to_vec(),
+            uvs: uvs.to_vec(),
+            indices: indices.to_vec(),
+            bvh: None,
+            spatial_splits: None,
+        }
+    }
+
+    /// A triangle in the XZ plane whose vertices lean their normals out along X and Z
+    fn bent() -> Mesh {
+        let s = 0.6;
+        mesh(
+            &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0],
+            &[0.0, 1.0, 0.0, 0.0, s, 0.8, 0.8, s, 0.0],
+            &[0.0, 0.0, 0.0, 1.0, 1.0, 0.0],
+            &[0, 1, 2],
+        )
+    }
+
+    #[test]
+    fn vertices_get_their_own_attributes() {
+        let attributes = PackedAttributes::new(&[bent()]);
+        let at = |u, v| attributes.interpolate(0, 0, Vector2::new(u, v));
+
+        assert_eq!(at(0.0, 0.0).normal, Vector3::y());
+        assert_near(at(1.0, 0.0).normal, Vector3::new(0.0, 0.6, 0.8));
+        assert_near(at(0.0, 1.0).normal, Vector3::new(0.8, 0.6, 0.0));
+        assert_eq!(at(1.0, 0.0).uv, Vector2::new(0.0, 1.0));
+        assert_eq!(at(0.0, 1.0).uv, Vector2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn normals_blend_smoothly_across_triangles() {
+        let attributes = PackedAttributes::new(&[bent()]);
+        let middle = attributes.interpolate(0, 0, Vector2::new(0.5, 0.5));
+        assert_near(middle.normal, Vector3::new(0.4, 0.6, 0.4).normalize());
+        assert_eq!(middle.uv, Vector2::new(0.5, 0.5));
+
+        let third = attributes.interpolate(0, 0, Vector2::repeat(1.0 / 3.0));
+        assert!((third.normal.norm() - 1.0).abs() < 1e-6);
+        assert!(third.normal.y > middle.normal.y);
+    }
+
+    #[test]
+    fn meshes_are_packed_one_after_another() {
+        let quad = mesh(
+            &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0, 0.0, 0.0],
+            &[],
+            &[],
+            &[0, 1, 2, 0, 2, 3],
+        );
+        let attributes = PackedAttributes::new(&[bent(), quad]);
+
+        assert_eq!(attributes.first_indices, [0, 3]);
+        assert_eq!(attributes.indices, [0, 1, 2, 3, 4, 5, 3, 5, 6]);
+        assert_eq!(attributes.normals.len(), 7);
+        assert_eq!(attributes.uvs.len(), 7);
+
+        // The second triangle of the second mesh, which has no normals or UVs of its own
+        let hit = attributes.interpolate(1, 1, Vector2::new(0.25, 0.25));
+        assert_near(hit.normal, Vector3::y());
+        assert_eq!(hit.uv, Vector2::zeros());
+    }
+
+    #[test]
+    fn smooth_normals_follow_the_area_around_each_vertex() {
+        // A big triangle facing +Y and a small one facing -X sharing an edge along Z
+        let positions = [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 4.0, 0.0, 0.0, 0.0, -1.0, 0.0];
+        let normals = smooth_normals(&positions, &[0, 1, 2, 0, 3, 1]);
+        let normal = |i: usize| Vector3::from_column_slice(&normals[i * 3..][..3]);
+
+        assert_near(normal(2), Vector3::y());
+        assert_near(normal(3), -Vector3::x());
+        let shared = normal(0);
+        assert_near(shared, Vector3::new(-1.0, 4.0, 0.0).normalize());
+        assert_eq!(normal(1), shared);
+
+        // Unused and degenerate vertices still get a normal
+        let normals = smooth_normals(&[0.0; 9], &[0, 1, 2]);
+        assert_eq!(normals, [0.0, 1.0, 0.0].repeat(3));
+    }
+}
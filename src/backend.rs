@@ -0,0 +1,40 @@
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::scene_graph::SceneGraph;
+use crate::scene_model::SceneModel;
+use std::error::Error;
+
+pub type BackendResult<T> = Result<T, Box<dyn Error>>;
+
+/// Everything the frame loop needs from a renderer. A frame is `update_transforms`, `trace_frame`
+/// and then either `present` or `readback`.
+pub trait RenderBackend {
+    /// Creates the geometry, acceleration structures and any other resources the scene needs
+    fn build_scene(&mut self, scene: &SceneModel) -> BackendResult<()>;
+
+    /// The built scene's nodes and instances, which can be added to and removed from between frames.
+    /// Changes show up from the next `update_transforms`.
+    #[allow(dead_code)]
+    fn scene_graph(&mut self) -> BackendResult<&mut SceneGraph>;
+
+    /// Traces from `camera` instead of the scene's camera from the next `trace_frame`
+    fn set_camera(&mut self, camera: &Camera) -> BackendResult<()>;
+
+    /// Moves the scene's instances to where they are `time` seconds into the animation
+    fn update_transforms(&mut self, time: f32) -> BackendResult<()>;
+
+    /// Traces the scene into the backend's render target
+    fn trace_frame(&mut self) -> BackendResult<()>;
+
+    /// Shows the last traced frame in the window
+    fn present(&mut self) -> BackendResult<()>;
+
+    /// Copies the last traced frame back to CPU memory
+    fn readback(&mut self) -> BackendResult<Image>;
+
+    /// Resizes the render target to match the window
+    fn resize(&mut self, width: u32, height: u32) -> BackendResult<()>;
+
+    /// Blocks until all submitted work has finished
+    fn wait_idle(&mut self) -> BackendResult<()>;
+}
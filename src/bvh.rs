@@ -0,0 +1,1491 @@
+//! Bounding volume hierarchies for tracing on the CPU. A `Hierarchy` is built top down over boxes with
+//! the binned surface area heuristic and stored depth first in a flat array, so a node's first child
+//! is always the node right after it. `Bvh` puts one over a triangle mesh, the CPU equivalent of a
+//! BLAS, and can also split triangles between nodes with spatial splits, as in the SBVH paper (Stich
+//! et al. 2009).
+
+use crate::ray::{intersect_triangle, HitKind, Ray};
+use crate::wide_bvh::WideHierarchy;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// Cost of visiting a node relative to intersecting a leaf's item
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// Deeper nodes are always leaves, which bounds the traversal stack
+pub const MAX_DEPTH: usize = 64;
+
+/// Spatial splits are only tried where the halves of a node's best object split overlap by more
+/// than this fraction of the root's surface area, the paper's alpha
+const MIN_OVERLAP: f32 = 1e-5;
+
+/// How many children the nodes `Bvh` traces through have
+const WIDTH: usize = 8;
+
+/// An index buffer element, like the R16_UINT and R32_UINT index formats make_blas accepts
+pub trait MeshIndex: Copy {
+    fn to_usize(self) -> usize;
+}
+
+impl MeshIndex for u16 {
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+impl MeshIndex for u32 {
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhOptions {
+    /// Nodes with more items than this are always split, smaller ones only when the SAH says it's
+    /// worth it
+    pub max_leaf_size: usize,
+    /// Number of buckets the candidate split planes along each axis are binned into
+    pub num_bins: usize,
+    /// Lets `Bvh` split triangles between nodes where that beats keeping them whole, adding up to
+    /// this many references per triangle. Long thin triangles have loose bounds that overlap a
+    /// lot, which splitting them tightens. None only splits the triangles into groups.
+    pub spatial_splits: Option<f32>,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        Self {
+            max_leaf_size: 4,
+            num_bins: 16,
+            spatial_splits: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    /// Contains nothing, growing it by anything gives that thing's bounds
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::from([f32::INFINITY; 3]),
+            max: Point3::from([f32::NEG_INFINITY; 3]),
+        }
+    }
+
+    pub fn from_points(points: &[Point3<f32>]) -> Self {
+        points
+            .iter()
+            .fold(Self::empty(), |bounds, p| bounds.grow(p))
+    }
+
+    pub fn grow(&self, point: &Point3<f32>) -> Self {
+        Self {
+            min: self.min.inf(point),
+            max: self.max.sup(point),
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    /// Whether the box has nothing in it, it can still be flat
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+
+    /// Where the boxes overlap, which is empty if they don't
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.sup(&other.min),
+            max: self.max.inf(&other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    /// Zero for empty boxes rather than negative
+    pub fn surface_area(&self) -> f32 {
+        let d = (self.max - self.min).map(|x| x.max(0.0));
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test, returning where the ray enters the box if it does between `t_min` and `t_max`.
+    /// `inv_direction` is the reciprocal of the ray direction, which may be infinite.
+    pub fn intersect(
+        &self,
+        origin: &Point3<f32>,
+        inv_direction: &Vector3<f32>,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<f32> {
+        let t0 = (self.min - origin).component_mul(inv_direction);
+        let t1 = (self.max - origin).component_mul(inv_direction);
+
+        // min and max skip the NaNs from rays starting on a slab they're parallel to
+        let near = t0.zip_map(&t1, f32::min).max().max(t_min);
+        let far = t0.zip_map(&t1, f32::max).min().min(t_max);
+        (near <= far).then_some(near)
+    }
+}
+
+/// A triangle hit, with the primitive index and barycentrics of BuiltInTriangleIntersectionAttributes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriangleHit {
+    /// Index of the triangle in the index buffer the BVH was built from
+    pub primitive: usize,
+    pub barycentrics: Vector2<f32>,
+    pub t: f32,
+    pub hit_kind: HitKind,
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    /// The first item of a leaf in leaf order, or the second child of an interior node
+    offset: u32,
+    /// Number of items in a leaf, zero for interior nodes
+    count: u32,
+}
+
+/// What a node of a `Hierarchy` holds
+pub enum NodeContents {
+    /// The leaf order positions of the node's items
+    Leaf(Range<usize>),
+    /// The indices of the node's two children
+    Interior([usize; 2]),
+}
+
+/// Measures of how well a hierarchy is built, for comparing builder settings and catching bad builds
+#[derive(Clone, Debug, PartialEq)]
+pub struct BvhStats {
+    /// Expected cost of tracing a ray that hits the root, in item intersections, by the SAH
+    pub sah_cost: f32,
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+    /// The number of leaves at each depth, starting from the root at depth 0
+    pub leaf_depths: Vec<usize>,
+    /// Number of items in the leaves, more than there are items when spatial splits put some in
+    /// several leaves
+    pub references: usize,
+    /// Mean number of items per leaf
+    pub average_leaf_size: f32,
+    /// Surface area of the overlap between the children of every interior node, relative to the
+    /// root's. Rays through the overlap have to visit both children, so lower is better.
+    pub overlap: f32,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "SAH cost:          {:.3}", self.sah_cost)?;
+        writeln!(f, "Nodes:             {}", self.num_nodes)?;
+        writeln!(f, "Leaves:            {}", self.num_leaves)?;
+        writeln!(f, "References:        {}", self.references)?;
+        writeln!(f, "Average leaf size: {:.2}", self.average_leaf_size)?;
+        writeln!(f, "Overlap:           {:.3}", self.overlap)?;
+        write!(f, "Leaves by depth:")?;
+        for (depth, count) in self.leaf_depths.iter().enumerate() {
+            write!(f, "\n  {depth:>3}: {count}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An item, or the part of one a spatial split left on one side, while the hierarchy is being built
+struct BuildItem {
+    index: u32,
+    bounds: Aabb,
+    centroid: Point3<f32>,
+}
+
+impl BuildItem {
+    fn new(index: u32, bounds: Aabb) -> Self {
+        Self {
+            index,
+            bounds,
+            centroid: bounds.centroid(),
+        }
+    }
+}
+
+/// Which of `num_bins` buckets along `axis` of `centroid_bounds` a centroid falls into
+fn bin(centroid: &Point3<f32>, axis: usize, centroid_bounds: &Aabb, num_bins: usize) -> usize {
+    let min = centroid_bounds.min[axis];
+    let extent = centroid_bounds.max[axis] - min;
+    let bin = ((centroid[axis] - min) / extent * num_bins as f32) as usize;
+    bin.min(num_bins - 1)
+}
+
+/// Splits the part of `triangle` inside `bounds` with the plane at `position` along `axis`, and
+/// returns the bounds of what's on either side of it
+fn split_triangle(
+    triangle: &[Point3<f32>; 3],
+    bounds: &Aabb,
+    axis: usize,
+    position: f32,
+) -> (Aabb, Aabb) {
+    let (mut left, mut right) = (Aabb::empty(), Aabb::empty());
+    for i in 0..3 {
+        let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+        if a[axis] <= position {
+            left = left.grow(&a);
+        }
+        if a[axis] >= position {
+            right = right.grow(&a);
+        }
+
+        // Where an edge crosses the plane is on both sides
+        if (a[axis] < position && b[axis] > position) || (a[axis] > position && b[axis] < position)
+        {
+            let mut crossing = a + (b - a) * ((position - a[axis]) / (b[axis] - a[axis]));
+            crossing[axis] = position;
+            left = left.grow(&crossing);
+            right = right.grow(&crossing);
+        }
+    }
+
+    let clip = |side: Aabb| {
+        let clipped = side.intersection(bounds);
+        if clipped.is_empty() {
+            Aabb::empty()
+        } else {
+            clipped
+        }
+    };
+    (clip(left), clip(right))
+}
+
+/// The cheapest object split of a node, by the SAH
+struct ObjectSplit {
+    cost: f32,
+    axis: usize,
+    /// The first centroid bin of the second half
+    bin: usize,
+    /// Surface area of where the two halves' bounds overlap
+    overlap: f32,
+}
+
+struct Builder<'a> {
+    options: &'a BvhOptions,
+    nodes: Vec<Node>,
+    /// The index of each item in leaf order, added to as leaves are made
+    order: Vec<u32>,
+    /// What spatial splits cut items along, None when only the items' bounds are known
+    triangles: Option<&'a [[Point3<f32>; 3]]>,
+    /// How many more references spatial splits can add
+    budget: usize,
+    /// Spatial splits are only tried where the halves of the best object split overlap by more
+    /// than this much surface area
+    min_overlap: f32,
+}
+
+impl Builder<'_> {
+    /// Adds the subtree over `items` and returns the index of its root
+    fn node(&mut self, mut items: Vec<BuildItem>, depth: usize) -> usize {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |bounds, t| bounds.union(&t.bounds));
+
+        // Leaves take their items from the end of the leaf order as they're made
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            offset: self.order.len() as u32,
+            count: items.len() as u32,
+        });
+
+        if depth + 1 < MAX_DEPTH {
+            if let Some(right) = self.split(&mut items, &bounds) {
+                self.node(items, depth + 1);
+                let right = self.node(right, depth + 1);
+                self.nodes[index].offset = right as u32;
+                self.nodes[index].count = 0;
+                return index;
+            }
+        }
+
+        self.order.extend(items.iter().map(|item| item.index));
+        index
+    }
+
+    /// Splits `items` along the cheapest binned SAH split, leaving the first half in `items` and
+    /// returning the second, or returns None if they're better off as a leaf
+    fn split(&mut self, items: &mut Vec<BuildItem>, bounds: &Aabb) -> Option<Vec<BuildItem>> {
+        if items.len() <= 1 {
+            return None;
+        }
+
+        let num_bins = self.options.num_bins.max(2);
+        let centroid_bounds = items
+            .iter()
+            .fold(Aabb::empty(), |bounds, t| bounds.grow(&t.centroid));
+        let parent_area = bounds.surface_area().max(f32::MIN_POSITIVE);
+        let object = Self::object_split(items, &centroid_bounds, parent_area, num_bins);
+
+        // Halves that barely overlap can't get much tighter by splitting items between them
+        let must_split = items.len() > self.options.max_leaf_size;
+        let overlap = object.as_ref().map_or(f32::INFINITY, |split| split.overlap);
+        if let Some(triangles) = self.triangles.filter(|_| self.budget > 0) {
+            if overlap > self.min_overlap {
+                let spatial = Self::spatial_split(items, triangles, bounds, parent_area, num_bins);
+                if let Some((cost, axis, position)) = spatial {
+                    let cheapest = object.as_ref().is_none_or(|split| cost < split.cost);
+                    if cheapest && (must_split || cost < items.len() as f32) {
+                        if let Some(right) = self.split_items(items, triangles, axis, position) {
+                            return Some(right);
+                        }
+                    }
+                }
+            }
+        }
+
+        match object {
+            Some(split) if must_split || split.cost < items.len() as f32 => {
+                let mut mid = 0;
+                for i in 0..items.len() {
+                    if bin(&items[i].centroid, split.axis, &centroid_bounds, num_bins) < split.bin {
+                        items.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+
+                Some(items.split_off(mid))
+            }
+            // Every centroid is in the same place, so any split is as good as another
+            None if must_split => Some(items.split_off(items.len() / 2)),
+            _ => None,
+        }
+    }
+
+    /// Finds the cheapest way to split `items` in two by which bin their centroids are in
+    fn object_split(
+        items: &[BuildItem],
+        centroid_bounds: &Aabb,
+        parent_area: f32,
+        num_bins: usize,
+    ) -> Option<ObjectSplit> {
+        let mut best: Option<ObjectSplit> = None;
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+                continue;
+            }
+
+            let mut bins = vec![(Aabb::empty(), 0usize); num_bins];
+            for t in items {
+                let (bounds, count) = &mut bins[bin(&t.centroid, axis, centroid_bounds, num_bins)];
+                *bounds = bounds.union(&t.bounds);
+                *count += 1;
+            }
+
+            // Everything right of each plane, swept in from the end
+            let mut right = vec![(Aabb::empty(), 0usize); num_bins];
+            let (mut bounds, mut count) = (Aabb::empty(), 0);
+            for i in (1..num_bins).rev() {
+                bounds = bounds.union(&bins[i].0);
+                count += bins[i].1;
+                right[i] = (bounds, count);
+            }
+
+            let (mut bounds, mut count) = (Aabb::empty(), 0);
+            for i in 1..num_bins {
+                bounds = bounds.union(&bins[i - 1].0);
+                count += bins[i - 1].1;
+
+                let (right_bounds, right_count) = right[i];
+                if count == 0 || right_count == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (bounds.surface_area() * count as f32
+                        + right_bounds.surface_area() * right_count as f32)
+                        / parent_area;
+                if best.as_ref().is_none_or(|best| cost < best.cost) {
+                    best = Some(ObjectSplit {
+                        cost,
+                        axis,
+                        bin: i,
+                        overlap: bounds.intersection(&right_bounds).surface_area(),
+                    });
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Finds the cheapest plane to cut `items` with, splitting the triangles it crosses between
+    /// both halves, and returns its cost, axis and position. The planes are spread evenly across
+    /// `bounds`, and each triangle is chopped up between the bins it spans to bound them tightly.
+    fn spatial_split(
+        items: &[BuildItem],
+        triangles: &[[Point3<f32>; 3]],
+        bounds: &Aabb,
+        parent_area: f32,
+        num_bins: usize,
+    ) -> Option<(f32, usize, f32)> {
+        let mut best: Option<(f32, usize, f32)> = None;
+        for axis in 0..3 {
+            let min = bounds.min[axis];
+            let extent = bounds.max[axis] - min;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let plane = |i: usize| min + extent * i as f32 / num_bins as f32;
+            let bin = |x: f32| (((x - min) / extent * num_bins as f32) as usize).min(num_bins - 1);
+
+            // The bounds of the pieces in each bin, and how many items start and end in it
+            let mut bins = vec![(Aabb::empty(), 0usize, 0usize); num_bins];
+            for item in items {
+                let triangle = &triangles[item.index as usize];
+                let (first, last) = (bin(item.bounds.min[axis]), bin(item.bounds.max[axis]));
+                let mut rest = item.bounds;
+                for (i, (bounds, ..)) in bins[first..last].iter_mut().enumerate() {
+                    let (piece, right) =
+                        split_triangle(triangle, &rest, axis, plane(first + i + 1));
+                    *bounds = bounds.union(&piece);
+                    rest = right;
+                }
+
+                bins[last].0 = bins[last].0.union(&rest);
+                bins[first].1 += 1;
+                bins[last].2 += 1;
+            }
+
+            // Everything right of each plane, swept in from the end
+            let mut right = vec![(0.0, 0usize); num_bins];
+            let (mut bounds, mut count) = (Aabb::empty(), 0);
+            for i in (1..num_bins).rev() {
+                bounds = bounds.union(&bins[i].0);
+                count += bins[i].2;
+                right[i] = (bounds.surface_area(), count);
+            }
+
+            let (mut bounds, mut count) = (Aabb::empty(), 0);
+            for i in 1..num_bins {
+                bounds = bounds.union(&bins[i - 1].0);
+                count += bins[i - 1].1;
+
+                let (right_area, right_count) = right[i];
+                if count == 0 || right_count == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (bounds.surface_area() * count as f32 + right_area * right_count as f32)
+                        / parent_area;
+                if best.is_none_or(|(best_cost, ..)| cost < best_cost) {
+                    best = Some((cost, axis, plane(i)));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Cuts `items` with the plane at `position` along `axis`, referencing the triangles it
+    /// crosses from both halves. Gives up if that would leave a half empty or go over the budget.
+    fn split_items(
+        &mut self,
+        items: &mut Vec<BuildItem>,
+        triangles: &[[Point3<f32>; 3]],
+        axis: usize,
+        position: f32,
+    ) -> Option<Vec<BuildItem>> {
+        let crossing = items
+            .iter()
+            .filter(|t| t.bounds.min[axis] < position && t.bounds.max[axis] > position)
+            .count();
+        if crossing > self.budget {
+            return None;
+        }
+
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for t in items.iter() {
+            if t.bounds.max[axis] <= position {
+                left.push(BuildItem::new(t.index, t.bounds));
+            } else if t.bounds.min[axis] >= position {
+                right.push(BuildItem::new(t.index, t.bounds));
+            } else {
+                // A triangle that only crosses the plane outside the item's bounds stays whole
+                let triangle = &triangles[t.index as usize];
+                let (l, r) = split_triangle(triangle, &t.bounds, axis, position);
+                for (half, bounds) in [(&mut left, l), (&mut right, r)] {
+                    if !bounds.is_empty() {
+                        half.push(BuildItem::new(t.index, bounds));
+                    }
+                }
+            }
+        }
+
+        if left.is_empty() || right.is_empty() {
+            return None;
+        }
+
+        self.budget -= left.len() + right.len() - items.len();
+        *items = left;
+        Some(right)
+    }
+}
+
+#[derive(Clone)]
+pub struct Hierarchy {
+    nodes: Vec<Node>,
+    /// The index of each item in leaf order, where items split by spatial splits appear more than
+    /// once
+    order: Vec<u32>,
+    /// SAH cost of the tree when it was built and after the last refit
+    built_cost: f32,
+    cost: f32,
+}
+
+impl Hierarchy {
+    /// Builds over items with the given bounds
+    pub fn build(bounds: &[Aabb], options: &BvhOptions) -> Self {
+        Self::build_with_triangles(bounds, None, options)
+    }
+
+    /// Builds over items with the given bounds, which are the bounds of `triangles` if there are any
+    /// for spatial splits to cut up
+    fn build_with_triangles(
+        bounds: &[Aabb],
+        triangles: Option<&[[Point3<f32>; 3]]>,
+        options: &BvhOptions,
+    ) -> Self {
+        let items: Vec<BuildItem> = bounds
+            .iter()
+            .enumerate()
+            .map(|(i, bounds)| BuildItem::new(i as u32, *bounds))
+            .collect();
+
+        let root_area = bounds
+            .iter()
+            .fold(Aabb::empty(), |root, bounds| root.union(bounds))
+            .surface_area();
+        let mut builder = Builder {
+            options,
+            nodes: Vec::new(),
+            order: Vec::with_capacity(items.len()),
+            triangles,
+            budget: options
+                .spatial_splits
+                .map_or(0, |budget| (budget * items.len() as f32) as usize),
+            min_overlap: MIN_OVERLAP * root_area,
+        };
+
+        // An empty hierarchy has no nodes at all, there's nothing for its root to bound
+        if !items.is_empty() {
+            builder.node(items, 0);
+        }
+
+        let mut hierarchy = Self {
+            nodes: builder.nodes,
+            order: builder.order,
+            built_cost: 0.0,
+            cost: 0.0,
+        };
+        hierarchy.cost = hierarchy.sah_cost();
+        hierarchy.built_cost = hierarchy.cost;
+        hierarchy
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |root| root.bounds)
+    }
+
+    /// Expected cost of tracing a ray that hits the root through the tree, by the SAH
+    fn sah_cost(&self) -> f32 {
+        let root_area = self.bounds().surface_area().max(f32::MIN_POSITIVE);
+        let cost: f32 = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.count > 0 {
+                    node.count as f32
+                } else {
+                    TRAVERSAL_COST
+                };
+                cost * node.bounds.surface_area()
+            })
+            .sum();
+
+        cost / root_area
+    }
+
+    /// Updates every node's bounds to fit its items' new bounds while keeping the tree's topology,
+    /// like a PERFORM_UPDATE build. `item_bounds` is given each item's leaf order position, so items
+    /// spatial splits cut up get their whole bounds back wherever they are.
+    pub fn refit(&mut self, item_bounds: impl Fn(usize) -> Aabb) {
+        // Children always come after their parents
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bounds = if node.count > 0 {
+                let first = node.offset as usize;
+                (first..first + node.count as usize).fold(Aabb::empty(), |bounds, item| {
+                    bounds.union(&item_bounds(item))
+                })
+            } else {
+                self.nodes[i + 1]
+                    .bounds
+                    .union(&self.nodes[node.offset as usize].bounds)
+            };
+        }
+
+        self.cost = self.sah_cost();
+    }
+
+    /// How many times more the tree costs to trace than when it was built, by the SAH. Refits grow
+    /// this as moving items make nodes looser and overlap more, until the tree is worth rebuilding.
+    pub fn degradation(&self) -> f32 {
+        if self.nodes.is_empty() {
+            1.0
+        } else {
+            self.cost / self.built_cost
+        }
+    }
+
+    /// The index of each item in leaf order
+    pub fn order(&self) -> &[u32] {
+        &self.order
+    }
+
+    /// Nodes are numbered depth first, the root is node 0 unless there are no nodes at all
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, index: usize) -> (Aabb, NodeContents) {
+        let node = &self.nodes[index];
+        let contents = if node.count > 0 {
+            let first = node.offset as usize;
+            NodeContents::Leaf(first..first + node.count as usize)
+        } else {
+            NodeContents::Interior([index + 1, node.offset as usize])
+        };
+
+        (node.bounds, contents)
+    }
+
+    /// The depth of every node, starting from the root at depth 0. A node with more than one parent
+    /// gets the depth of its deepest path.
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.nodes.len()];
+        // Children always come after their parents
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                for child in [i + 1, node.offset as usize] {
+                    depths[child] = depths[child].max(depths[i] + 1);
+                }
+            }
+        }
+
+        depths
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        let depths = self.depths();
+        let mut leaf_depths = vec![0; depths.iter().max().map_or(0, |&max| max + 1)];
+        let mut overlap = 0.0;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count > 0 {
+                leaf_depths[depths[i]] += 1;
+            } else {
+                let [a, b] = [i + 1, node.offset as usize].map(|child| self.nodes[child].bounds);
+                overlap += a.intersection(&b).surface_area();
+            }
+        }
+
+        let num_leaves: usize = leaf_depths.iter().sum();
+        let root_area = self.bounds().surface_area().max(f32::MIN_POSITIVE);
+        BvhStats {
+            sah_cost: self.cost,
+            num_nodes: self.nodes.len(),
+            num_leaves,
+            leaf_depths,
+            references: self.order.len(),
+            average_leaf_size: self.order.len() as f32 / num_leaves.max(1) as f32,
+            overlap: overlap / root_area,
+        }
+    }
+
+    /// Writes the bounds of every node as a wireframe box in Wavefront OBJ, with a group for each
+    /// depth so the levels of the tree can be looked at one at a time
+    pub fn write_wireframe(&self, out: &mut impl Write) -> io::Result<()> {
+        #[rustfmt::skip]
+        const EDGES: [[usize; 2]; 12] = [
+            [0, 1], [2, 3], [4, 5], [6, 7], // Along X
+            [0, 2], [1, 3], [4, 6], [5, 7], // Along Y
+            [0, 4], [1, 5], [2, 6], [3, 7], // Along Z
+        ];
+
+        let depths = self.depths();
+        let mut by_depth: Vec<usize> = (0..self.nodes.len()).collect();
+        by_depth.sort_by_key(|&i| depths[i]);
+
+        let mut group = None;
+        for (box_index, &i) in by_depth.iter().enumerate() {
+            if group != Some(depths[i]) {
+                group = Some(depths[i]);
+                writeln!(out, "g depth_{}", depths[i])?;
+            }
+
+            // Corner n has bit 0 set for max X, bit 1 for max Y and bit 2 for max Z
+            let Aabb { min, max } = self.nodes[i].bounds;
+            for corner in 0..8 {
+                let pick = |bit, axis: usize| {
+                    if corner & bit != 0 {
+                        max[axis]
+                    } else {
+                        min[axis]
+                    }
+                };
+                writeln!(out, "v {} {} {}", pick(1, 0), pick(2, 1), pick(4, 2))?;
+            }
+
+            // OBJ indices start at 1
+            let first = box_index * 8 + 1;
+            for [a, b] in EDGES {
+                writeln!(out, "l {} {}", first + a, first + b)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The nodes in the layout scene caches store them in, each node's bounds as min and max
+    /// followed by its offset and count
+    pub fn packed_nodes(&self) -> Vec<[u32; 8]> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let [x0, y0, z0] = node.bounds.min.coords.into();
+                let [x1, y1, z1] = node.bounds.max.coords.into();
+                let b = [x0, y0, z0, x1, y1, z1].map(f32::to_bits);
+                [b[0], b[1], b[2], b[3], b[4], b[5], node.offset, node.count]
+            })
+            .collect()
+    }
+
+    /// Puts a hierarchy over `num_items` items back together from `packed_nodes` and `order`,
+    /// checking they make a tree traversal can't be sent out of bounds or around in circles by
+    pub fn unpack(packed: &[[u32; 8]], order: Vec<u32>, num_items: usize) -> Result<Self, String> {
+        // Spatial splits can leave items in several leaves, so only the indices can be checked
+        if order.iter().any(|&i| i as usize >= num_items) {
+            return Err(format!("The BVH's order refers past its {num_items} items"));
+        }
+
+        if packed.is_empty() != order.is_empty() {
+            return Err("The BVH has no nodes for its items".to_string());
+        }
+
+        let nodes = packed
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let [offset, count] = [node[6] as usize, node[7] as usize];
+                // Children always come after their parents, which rules out cycles
+                let valid = if count > 0 {
+                    offset + count <= order.len()
+                } else {
+                    i + 1 < offset && offset < packed.len()
+                };
+                if !valid {
+                    return Err(format!("BVH node {i} is out of bounds"));
+                }
+
+                let [x0, y0, z0, x1, y1, z1] = [0, 1, 2, 3, 4, 5].map(|j| f32::from_bits(node[j]));
+                Ok(Node {
+                    bounds: Aabb {
+                        min: Point3::new(x0, y0, z0),
+                        max: Point3::new(x1, y1, z1),
+                    },
+                    offset: node[6],
+                    count: node[7],
+                })
+            })
+            .collect::<Result<Vec<Node>, _>>()?;
+
+        // Shared children would make it a DAG, which traverses them once for each parent
+        let mut has_parent = vec![false; nodes.len()];
+        for (i, node) in nodes.iter().enumerate().filter(|(_, node)| node.count == 0) {
+            for child in [i + 1, node.offset as usize] {
+                if std::mem::replace(&mut has_parent[child], true) {
+                    return Err(format!("BVH node {child} has more than one parent"));
+                }
+            }
+        }
+
+        let mut hierarchy = Self {
+            nodes,
+            order,
+            built_cost: 0.0,
+            cost: 0.0,
+        };
+        // Deeper trees would overflow the traversal stack
+        if hierarchy.depths().iter().any(|&depth| depth >= MAX_DEPTH) {
+            return Err(format!("The BVH is deeper than {MAX_DEPTH} levels"));
+        }
+
+        hierarchy.cost = hierarchy.sah_cost();
+        hierarchy.built_cost = hierarchy.cost;
+        Ok(hierarchy)
+    }
+
+    /// Calls `leaf` with the leaf order positions of the items in each leaf `ray` reaches, roughly
+    /// front to back. `leaf` can lower the ray's `t_max` to skip anything further away, and ends the
+    /// traversal by returning true.
+    pub fn traverse(&self, ray: &Ray, mut leaf: impl FnMut(Range<usize>, &mut Ray) -> bool) {
+        let Some(root) = self.nodes.first() else {
+            return;
+        };
+
+        let inv_direction = ray.direction.map(|d| 1.0 / d);
+        let mut ray = *ray;
+
+        // Subtrees still to visit, with where the ray enters them. Only the far child of each node
+        // on the current path is pushed, so the depth limit is enough.
+        let mut stack = [(0usize, 0.0f32); MAX_DEPTH];
+        let mut stack_size = 0;
+        if let Some(t) = root
+            .bounds
+            .intersect(&ray.origin, &inv_direction, ray.t_min, ray.t_max)
+        {
+            stack[0] = (0, t);
+            stack_size = 1;
+        }
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (mut index, t) = stack[stack_size];
+
+            // Hits found since this subtree was pushed may have put it out of reach
+            if t > ray.t_max {
+                continue;
+            }
+
+            loop {
+                let node = &self.nodes[index];
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    if leaf(first..first + node.count as usize, &mut ray) {
+                        return;
+                    }
+
+                    break;
+                }
+
+                let children = [index + 1, node.offset as usize].map(|child| {
+                    let bounds = &self.nodes[child].bounds;
+                    let t = bounds.intersect(&ray.origin, &inv_direction, ray.t_min, ray.t_max);
+                    t.map(|t| (child, t))
+                });
+
+                // Visit the nearer child first so the other is more likely to be skipped
+                index = match children {
+                    [Some(a), Some(b)] => {
+                        let (near, far) = if a.1 <= b.1 { (a, b) } else { (b, a) };
+                        stack[stack_size] = far;
+                        stack_size += 1;
+                        near.0
+                    }
+                    [Some((child, _)), None] | [None, Some((child, _))] => child,
+                    [None, None] => break,
+                };
+            }
+        }
+    }
+
+    /// Like `traverse` for a packet of rays going roughly the same way, such as the primary rays of
+    /// a tile of pixels. The packet visits each node any of its rays reach once, so the node is
+    /// fetched and ordered once for all of them. `leaf` is given the range of rays from the first to
+    /// the last that reach the leaf, some of which may miss it in between, and can lower their
+    /// `t_max`.
+    pub fn traverse_packet(
+        &self,
+        rays: &mut [Ray],
+        mut leaf: impl FnMut(Range<usize>, Range<usize>, &mut [Ray]),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_directions: Vec<Vector3<f32>> = rays.iter().map(Ray::inv_direction).collect();
+        let enters = |rays: &[Ray], node: &Node, i: usize| {
+            let ray = &rays[i];
+            let bounds = &node.bounds;
+            bounds.intersect(&ray.origin, &inv_directions[i], ray.t_min, ray.t_max)
+        };
+
+        // The first ray from `first` on that reaches `node`, and where it enters it. Rays before
+        // `first` are known to miss the node's parent already.
+        let first_hit = |rays: &[Ray], node: usize, first: usize| {
+            let node = &self.nodes[node];
+            (first..rays.len()).find_map(|i| enters(rays, node, i).map(|t| (i, t)))
+        };
+
+        // Subtrees still to visit with the first ray that reached them, which has to be checked
+        // again in case it's found something closer since
+        let mut stack = [(0usize, 0usize); MAX_DEPTH];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (mut index, first) = stack[stack_size];
+            let Some((mut first, _)) = first_hit(rays, index, first) else {
+                continue;
+            };
+
+            loop {
+                let node = &self.nodes[index];
+                if node.count > 0 {
+                    // Leaves are where the time goes, so trailing rays that miss them are worth
+                    // looking for too
+                    let last = (first + 1..rays.len())
+                        .rev()
+                        .find(|&i| enters(rays, node, i).is_some())
+                        .unwrap_or(first);
+                    let items = node.offset as usize..(node.offset + node.count) as usize;
+                    leaf(items, first..last + 1, rays);
+                    break;
+                }
+
+                let children = [index + 1, node.offset as usize]
+                    .map(|child| first_hit(rays, child, first).map(|hit| (child, hit)));
+
+                // Rays earlier in the packet go first, and the nearer child for the same ray
+                let (near, far) = match children {
+                    [Some(a), Some(b)] if a.1 <= b.1 => (a, Some(b)),
+                    [Some(a), Some(b)] => (b, Some(a)),
+                    [Some(child), None] | [None, Some(child)] => (child, None),
+                    [None, None] => break,
+                };
+
+                if let Some((child, (first, _))) = far {
+                    stack[stack_size] = (child, first);
+                    stack_size += 1;
+                }
+                (index, (first, _)) = near;
+            }
+        }
+    }
+}
+
+/// The vertices of triangle `primitive` of an indexed triangle list
+fn triangle<I: MeshIndex>(positions: &[f32], indices: &[I], primitive: usize) -> [Point3<f32>; 3] {
+    let vertex = |i: I| {
+        let i = i.to_usize() * 3;
+        Point3::new(positions[i], positions[i + 1], positions[i + 2])
+    };
+
+    let t = &indices[primitive * 3..primitive * 3 + 3];
+    [vertex(t[0]), vertex(t[1]), vertex(t[2])]
+}
+
+#[derive(Clone)]
+pub struct Bvh {
+    hierarchy: Hierarchy,
+    /// Collapsed from `hierarchy` for tracing, with the same leaf order
+    wide: WideHierarchy<WIDTH>,
+    /// Triangle vertices in leaf order
+    triangles: Vec<[Point3<f32>; 3]>,
+}
+
+impl Bvh {
+    /// Builds over an indexed triangle list in the formats make_blas gives D3D12, tightly packed
+    /// R32G32B32 positions and 16 or 32 bit indices
+    pub fn build<I: MeshIndex>(positions: &[f32], indices: &[I], options: &BvhOptions) -> Self {
+        let triangles: Vec<[Point3<f32>; 3]> = (0..indices.len() / 3)
+            .map(|primitive| triangle(positions, indices, primitive))
+            .collect();
+
+        let bounds: Vec<Aabb> = triangles.iter().map(|t| Aabb::from_points(t)).collect();
+        let hierarchy = Hierarchy::build_with_triangles(&bounds, Some(&triangles), options);
+
+        Self {
+            triangles: hierarchy
+                .order()
+                .iter()
+                .map(|&i| triangles[i as usize])
+                .collect(),
+            wide: WideHierarchy::build(&hierarchy),
+            hierarchy,
+        }
+    }
+
+    /// Puts a BVH from `Hierarchy::packed_nodes` back over the mesh it was built from
+    pub fn unpack<I: MeshIndex>(
+        positions: &[f32],
+        indices: &[I],
+        packed: &[[u32; 8]],
+        order: Vec<u32>,
+    ) -> Result<Self, String> {
+        let hierarchy = Hierarchy::unpack(packed, order, indices.len() / 3)?;
+        Ok(Self {
+            triangles: hierarchy
+                .order()
+                .iter()
+                .map(|&i| triangle(positions, indices, i as usize))
+                .collect(),
+            wide: WideHierarchy::build(&hierarchy),
+            hierarchy,
+        })
+    }
+
+    pub fn hierarchy(&self) -> &Hierarchy {
+        &self.hierarchy
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.hierarchy.bounds()
+    }
+
+    /// Moves the triangles to new vertex positions without rebuilding the tree, for deforming
+    /// meshes. `indices` have to be the ones the BVH was built with.
+    #[allow(dead_code)] // Nothing deforms meshes yet
+    pub fn refit<I: MeshIndex>(&mut self, positions: &[f32], indices: &[I]) {
+        for (vertices, &primitive) in self.triangles.iter_mut().zip(self.hierarchy.order()) {
+            *vertices = triangle(positions, indices, primitive as usize);
+        }
+
+        let triangles = &self.triangles;
+        self.hierarchy.refit(|i| Aabb::from_points(&triangles[i]));
+        self.wide = WideHierarchy::build(&self.hierarchy);
+    }
+
+    /// See `Hierarchy::degradation`
+    #[allow(dead_code)] // Nothing deforms meshes yet
+    pub fn degradation(&self) -> f32 {
+        self.hierarchy.degradation()
+    }
+
+    /// The closest hit along `ray`, like TraceRay without any flags
+    pub fn closest_hit(&self, ray: &Ray) -> Option<TriangleHit> {
+        self.traverse(ray, false)
+    }
+
+    /// The closest hit along each of a packet of `rays`, like `closest_hit` for each of them. Rays
+    /// that hit something have their `t_max` lowered to the hit. `hits` has to start out empty.
+    pub fn closest_hits(&self, rays: &mut [Ray], hits: &mut [Option<TriangleHit>]) {
+        self.hierarchy.traverse_packet(rays, |leaf, active, rays| {
+            for i in leaf {
+                let primitive = self.hierarchy.order[i] as usize;
+                for (ray, closest) in rays[active.clone()]
+                    .iter_mut()
+                    .zip(&mut hits[active.clone()])
+                {
+                    // The same tie break on shared edges as `traverse`
+                    if let Some(hit) = intersect_triangle(ray, self.triangles[i]) {
+                        if closest.is_some_and(|c| c.t == hit.t && c.primitive < primitive) {
+                            continue;
+                        }
+
+                        ray.t_max = hit.t;
+                        *closest = Some(TriangleHit {
+                            primitive,
+                            barycentrics: hit.barycentrics,
+                            t: hit.t,
+                            hit_kind: hit.hit_kind,
+                        });
+                    }
+                }
+            }
+        });
+    }
+
+    /// The first hit found along `ray`, which isn't necessarily the closest, like
+    /// RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH
+    pub fn any_hit(&self, ray: &Ray) -> Option<TriangleHit> {
+        self.traverse(ray, true)
+    }
+
+    fn traverse(&self, ray: &Ray, accept_first_hit: bool) -> Option<TriangleHit> {
+        let mut closest: Option<TriangleHit> = None;
+        self.wide.traverse(ray, |leaf, ray| {
+            for i in leaf {
+                // ray.t_max shrinks with every hit, so any intersection is the new closest or as
+                // close. Triangles sharing an edge both get hit on it, and going by the lowest
+                // primitive index keeps which one wins from depending on the traversal order.
+                if let Some(hit) = intersect_triangle(ray, self.triangles[i]) {
+                    let primitive = self.hierarchy.order[i] as usize;
+                    if closest.is_some_and(|c| c.t == hit.t && c.primitive < primitive) {
+                        continue;
+                    }
+
+                    ray.t_max = hit.t;
+                    closest = Some(TriangleHit {
+                        primitive,
+                        barycentrics: hit.barycentrics,
+                        t: hit.t,
+                        hit_kind: hit.hit_kind,
+                    });
+
+                    if accept_first_hit {
+                        return true;
+                    }
+                }
+            }
+
+            false
+        });
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{CUBE_IDX, CUBE_VTX};
+    use proptest::prelude::*;
+
+    fn down_at(x: f32, z: f32) -> Ray {
+        Ray {
+            origin: Point3::new(x, 10.0, z),
+            direction: -Vector3::y(),
+            t_min: 0.001,
+            t_max: 1000.0,
+        }
+    }
+
+    #[test]
+    fn refit_follows_deformed_vertices() {
+        let options = BvhOptions {
+            max_leaf_size: 1,
+            ..Default::default()
+        };
+        let mut bvh = Bvh::build(&CUBE_VTX, &CUBE_IDX, &options);
+
+        // Stretch the top of the cube up and out
+        let stretched: Vec<f32> = CUBE_VTX
+            .chunks_exact(3)
+            .flat_map(|v| {
+                if v[1] > 0.0 {
+                    [v[0] * 2.0, 3.0, v[2] * 2.0]
+                } else {
+                    [v[0], v[1], v[2]]
+                }
+            })
+            .collect();
+        bvh.refit(&stretched, &CUBE_IDX);
+        let rebuilt = Bvh::build(&stretched, &CUBE_IDX, &options);
+
+        assert_eq!(bvh.bounds(), rebuilt.bounds());
+        for (x, z) in [(0.0, 0.0), (1.5, 1.5), (-1.9, 0.5), (2.5, 0.0)] {
+            let hit = bvh.closest_hit(&down_at(x, z));
+            assert_eq!(hit, rebuilt.closest_hit(&down_at(x, z)));
+        }
+        assert_eq!(bvh.closest_hit(&down_at(1.5, 1.5)).unwrap().t, 7.0);
+        assert!(bvh.degradation() >= 1.0);
+    }
+
+    #[test]
+    fn stats_describe_the_tree() {
+        let options = BvhOptions {
+            max_leaf_size: 1,
+            ..Default::default()
+        };
+        let stats = Bvh::build(&CUBE_VTX, &CUBE_IDX, &options)
+            .hierarchy()
+            .stats();
+
+        // A binary tree with a leaf for each of the 12 triangles
+        assert_eq!(stats.num_leaves, 12);
+        assert_eq!(stats.num_nodes, 23);
+        assert_eq!(stats.average_leaf_size, 1.0);
+        assert_eq!(stats.leaf_depths.iter().sum::<usize>(), 12);
+        assert_eq!(stats.leaf_depths[0], 0);
+        assert!(stats.sah_cost > 1.0);
+        // The two triangles of each face share their box, so siblings overlap
+        assert!(stats.overlap > 0.0);
+
+        let single = Hierarchy::build(&[Aabb::from_points(&[Point3::origin()])], &options);
+        let stats = single.stats();
+        assert_eq!((stats.num_nodes, stats.num_leaves), (1, 1));
+        assert_eq!(stats.leaf_depths, vec![1]);
+        assert_eq!(stats.overlap, 0.0);
+    }
+
+    #[test]
+    fn wireframe_has_a_box_per_node() {
+        let hierarchy = Bvh::build(&CUBE_VTX, &CUBE_IDX, &BvhOptions::default()).hierarchy;
+        let mut obj = Vec::new();
+        hierarchy.write_wireframe(&mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        let count = |prefix| obj.lines().filter(|l| l.starts_with(prefix)).count();
+        let num_nodes = hierarchy.stats().num_nodes;
+        assert_eq!(count("v "), num_nodes * 8);
+        assert_eq!(count("l "), num_nodes * 12);
+        assert_eq!(obj.lines().next(), Some("g depth_0"));
+        // The root's box is the first 8 vertices
+        assert!(obj.contains("v -1 -1 -1\n") && obj.contains("v 1 1 1\n"));
+    }
+
+    #[test]
+    fn unpacked_bvh_traces_the_same() {
+        let bvh = Bvh::build(&CUBE_VTX, &CUBE_IDX, &BvhOptions::default());
+        let packed = bvh.hierarchy().packed_nodes();
+        let order = bvh.hierarchy().order().to_vec();
+
+        let unpacked = Bvh::unpack(&CUBE_VTX, &CUBE_IDX, &packed, order.clone()).unwrap();
+        assert_eq!(unpacked.bounds(), bvh.bounds());
+        for (x, z) in [(0.0, 0.0), (0.5, -0.9), (-1.0, 1.0), (2.0, 0.0)] {
+            assert_eq!(
+                unpacked.closest_hit(&down_at(x, z)),
+                bvh.closest_hit(&down_at(x, z))
+            );
+        }
+
+        let mut looping = packed.clone();
+        let interior = looping.iter().position(|node| node[7] == 0).unwrap();
+        looping[interior][6] = interior as u32;
+        assert!(Bvh::unpack(&CUBE_VTX, &CUBE_IDX, &looping, order.clone()).is_err());
+        assert!(Bvh::unpack(&CUBE_VTX, &CUBE_IDX[..30], &packed, order).is_err());
+    }
+
+    #[test]
+    fn shared_nodes_are_rejected() {
+        let bounds = [-1.0f32, -1.0, -1.0, 1.0, 1.0, 1.0].map(f32::to_bits);
+        let node = |offset, count| {
+            let [x0, y0, z0, x1, y1, z1] = bounds;
+            [x0, y0, z0, x1, y1, z1, offset, count]
+        };
+        // Nodes 2 and 4 both have node 7 as their second child, and 4 is nearer the root
+        let dag = [
+            node(4, 0),
+            node(6, 0),
+            node(7, 0),
+            node(0, 3),
+            node(7, 0),
+            node(3, 3),
+            node(6, 3),
+            node(9, 3),
+        ];
+        let order: Vec<u32> = (0..12).collect();
+        assert_eq!(
+            Bvh::unpack(&CUBE_VTX, &CUBE_IDX, &dag, order.clone()).err(),
+            Some("BVH node 7 has more than one parent".to_string())
+        );
+
+        // Whichever parent comes last, shared nodes are as deep as their deepest path
+        let hierarchy = Hierarchy {
+            nodes: dag
+                .iter()
+                .map(|node| Node {
+                    bounds: Aabb::empty(),
+                    offset: node[6],
+                    count: node[7],
+                })
+                .collect(),
+            order,
+            built_cost: 0.0,
+            cost: 0.0,
+        };
+        assert_eq!(hierarchy.depths(), [0, 1, 2, 3, 1, 2, 2, 3]);
+    }
+
+    /// Small triangles on a grid over the XZ plane, crossed by long thin diagonal slivers above it
+    fn slivers_over_grid() -> (Vec<f32>, Vec<u32>) {
+        let mut positions = Vec::new();
+        for i in 0..16 {
+            for j in 0..16 {
+                let (x, z) = (i as f32, j as f32);
+                positions.extend([x, 0.0, z, x + 0.5, 0.0, z, x, 0.0, z + 0.5]);
+            }
+        }
+        for k in 0..8 {
+            let offset = k as f32 * 2.0 - 8.0;
+            positions.extend([0.0, 0.5, offset, 16.0, 0.5, offset + 16.0]);
+            positions.extend([16.0, 0.6, offset + 16.1]);
+        }
+
+        let indices = (0..positions.len() as u32 / 3).collect();
+        (positions, indices)
+    }
+
+    #[test]
+    fn spatial_splits_tighten_slivers() {
+        let (positions, indices) = slivers_over_grid();
+        let num_triangles = indices.len() / 3;
+        let objects = Bvh::build(&positions, &indices, &BvhOptions::default());
+        let options = BvhOptions {
+            spatial_splits: Some(0.5),
+            ..Default::default()
+        };
+        let spatial = Bvh::build(&positions, &indices, &options);
+
+        let [object_stats, spatial_stats] = [&objects, &spatial].map(|b| b.hierarchy().stats());
+        assert_eq!(object_stats.references, num_triangles);
+        assert!(spatial_stats.references > num_triangles);
+        assert!(spatial_stats.references <= num_triangles + num_triangles / 2);
+        assert!(spatial_stats.sah_cost < object_stats.sah_cost);
+        assert!(spatial_stats.overlap < object_stats.overlap);
+
+        // Split triangles are in several leaves and come out of cache the same way
+        let order = spatial.hierarchy().order().to_vec();
+        let packed = spatial.hierarchy().packed_nodes();
+        let unpacked = Bvh::unpack(&positions, &indices, &packed, order).unwrap();
+
+        for i in 0..64 {
+            for j in 0..64 {
+                let ray = down_at(i as f32 * 0.25 + 0.1, j as f32 * 0.25 + 0.1);
+                let hit = objects.closest_hit(&ray);
+                assert_eq!(spatial.closest_hit(&ray), hit);
+                assert_eq!(unpacked.closest_hit(&ray), hit);
+                assert_eq!(spatial.any_hit(&ray).is_some(), hit.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn spatial_splits_need_a_budget() {
+        let (positions, indices) = slivers_over_grid();
+        let options = BvhOptions {
+            spatial_splits: Some(0.0),
+            ..Default::default()
+        };
+        let spatial = Bvh::build(&positions, &indices, &options);
+        let objects = Bvh::build(&positions, &indices, &BvhOptions::default());
+        assert_eq!(spatial.hierarchy().order(), objects.hierarchy().order());
+        assert_eq!(
+            spatial.hierarchy().packed_nodes(),
+            objects.hierarchy().packed_nodes()
+        );
+    }
+
+    #[test]
+    fn packets_hit_what_single_rays_do() {
+        let (positions, indices) = slivers_over_grid();
+        let options = BvhOptions {
+            spatial_splits: Some(0.5),
+            ..Default::default()
+        };
+        for bvh in [
+            Bvh::build(&positions, &indices, &BvhOptions::default()),
+            Bvh::build(&positions, &indices, &options),
+        ] {
+            // Tiles of rays fanning out from above the grid, some of which miss it
+            for tile in 0..16 {
+                let mut rays: Vec<Ray> = (0..64)
+                    .map(|i| Ray {
+                        origin: Point3::new(8.0, 4.0, 8.0),
+                        direction: Vector3::new(
+                            (tile % 4 * 8 + i % 8) as f32 - 16.0,
+                            -8.0,
+                            (tile / 4 * 8 + i / 8) as f32 - 16.0,
+                        ),
+                        t_min: 0.001,
+                        t_max: 1000.0,
+                    })
+                    .collect();
+                let single: Vec<_> = rays.iter().map(|ray| bvh.closest_hit(ray)).collect();
+
+                let mut hits = vec![None; rays.len()];
+                bvh.closest_hits(&mut rays, &mut hits);
+                assert_eq!(hits, single);
+                for (ray, hit) in rays.iter().zip(&hits) {
+                    assert_eq!(ray.t_max, hit.map_or(1000.0, |hit| hit.t));
+                }
+            }
+        }
+    }
+
+    fn point(extent: f32) -> impl Strategy<Value = Point3<f32>> {
+        [-extent..extent, -extent..extent, -extent..extent].prop_map(Point3::from)
+    }
+
+    /// A soup of random triangles, indexed back to front so the indices aren't just their order
+    fn random_mesh() -> impl Strategy<Value = (Vec<f32>, Vec<u32>)> {
+        prop::collection::vec([point(10.0), point(10.0), point(10.0)], 1..64).prop_map(
+            |triangles| {
+                let positions = triangles
+                    .iter()
+                    .flatten()
+                    .flat_map(|p| [p.x, p.y, p.z])
+                    .collect();
+                let indices = (0..triangles.len() as u32 * 3).rev().collect();
+                (positions, indices)
+            },
+        )
+    }
+
+    fn ray_towards(origin: Point3<f32>, target: Point3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: target - origin,
+            t_min: 0.001,
+            t_max: 1000.0,
+        }
+    }
+
+    /// Tests every triangle, keeping the nearest hit and the lowest primitive on ties like the BVH
+    fn brute_force_closest_hit(
+        positions: &[f32],
+        indices: &[u32],
+        ray: &Ray,
+    ) -> Option<TriangleHit> {
+        let mut closest: Option<TriangleHit> = None;
+        for primitive in 0..indices.len() / 3 {
+            if let Some(hit) = intersect_triangle(ray, triangle(positions, indices, primitive)) {
+                if closest.is_none_or(|c| hit.t < c.t) {
+                    closest = Some(TriangleHit {
+                        primitive,
+                        barycentrics: hit.barycentrics,
+                        t: hit.t,
+                        hit_kind: hit.hit_kind,
+                    });
+                }
+            }
+        }
+
+        closest
+    }
+
+    proptest! {
+        #[test]
+        fn closest_hit_matches_brute_force(
+            (positions, indices) in random_mesh(),
+            max_leaf_size in 1..6usize,
+            spatial_splits in prop_oneof![Just(None), Just(Some(0.5))],
+            origin in point(12.0),
+            target in point(12.0),
+        ) {
+            let options = BvhOptions { max_leaf_size, spatial_splits, ..Default::default() };
+            let bvh = Bvh::build(&positions, &indices, &options);
+            let ray = ray_towards(origin, target);
+            let closest = bvh.closest_hit(&ray);
+            prop_assert_eq!(closest, brute_force_closest_hit(&positions, &indices, &ray));
+            prop_assert_eq!(bvh.any_hit(&ray).is_some(), closest.is_some());
+        }
+
+        #[test]
+        fn index_widths_build_the_same_bvh(
+            (positions, indices) in random_mesh(),
+            origin in point(12.0),
+            target in point(12.0),
+        ) {
+            let narrow: Vec<u16> = indices.iter().map(|&i| i as u16).collect();
+            let wide = Bvh::build(&positions, &indices, &BvhOptions::default());
+            let bvh = Bvh::build(&positions, &narrow, &BvhOptions::default());
+            prop_assert_eq!(bvh.hierarchy().order(), wide.hierarchy().order());
+            prop_assert_eq!(bvh.hierarchy().packed_nodes(), wide.hierarchy().packed_nodes());
+
+            let ray = ray_towards(origin, target);
+            prop_assert_eq!(bvh.closest_hit(&ray), wide.closest_hit(&ray));
+            prop_assert_eq!(bvh.any_hit(&ray), wide.any_hit(&ray));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! The pinhole camera primary rays start from. RayGeneration in shaders/shaders.hlsl reads it from
+//! the CameraConstants cbuffer, and the CPU tracer generates its rays from the same constants.
+
+use crate::ray::{Ray, T_MAX, T_MIN};
+use nalgebra::{Point3, UnitQuaternion, Vector2, Vector3};
+use std::mem::size_of;
+
+/// Vertical field of view in degrees of cameras that don't set one. It frames the default scene
+/// about the way the shader's fixed camera used to.
+pub const DEFAULT_FOV: f32 = 32.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Point3<f32>,
+    /// Turns +Z to the view direction and +Y to the top of the image, leaving +X pointing right
+    pub orientation: UnitQuaternion<f32>,
+    /// Vertical field of view in radians. The horizontal one follows from the image's aspect ratio.
+    pub fov_y: f32,
+    /// Distances along the view direction to the near and far planes, nothing closer or further
+    /// away is hit
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    /// Looking along +Z from `position`
+    pub fn new(position: Point3<f32>) -> Self {
+        Self {
+            position,
+            orientation: UnitQuaternion::identity(),
+            fov_y: DEFAULT_FOV.to_radians(),
+            near: T_MIN,
+            far: T_MAX,
+        }
+    }
+
+    /// Turns to look along `direction`, rolled so `up` points as close to the top of the image as it
+    /// can. Looking straight along `up` picks some other up instead. A zero or non-finite
+    /// `direction`, like one from a node scaled to nothing, leaves the camera facing where it was.
+    pub fn look_to(&mut self, direction: &Vector3<f32>, up: &Vector3<f32>) {
+        let Some(direction) = direction
+            .try_normalize(f32::EPSILON)
+            .filter(|direction| direction.iter().all(|c| c.is_finite()))
+        else {
+            return;
+        };
+        let up = [*up, Vector3::z(), Vector3::x()]
+            .into_iter()
+            .find(|up| direction.cross(&up.normalize()).norm() > 1e-6)
+            .unwrap();
+        self.orientation = UnitQuaternion::face_towards(&direction, &up);
+    }
+
+    /// The view direction
+    pub fn forward(&self) -> Vector3<f32> {
+        self.orientation * Vector3::z()
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.orientation * Vector3::x()
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.orientation * Vector3::y()
+    }
+
+    /// What RayGeneration needs to render a `width` by `height` image
+    pub fn constants(&self, width: u32, height: u32) -> CameraConstants {
+        let half_height = (self.fov_y / 2.0).tan();
+        let half_width = half_height * width as f32 / height as f32;
+        CameraConstants {
+            position: self.position.into(),
+            near: self.near,
+            right: (self.right() * half_width).into(),
+            far: self.far,
+            up: (self.up() * half_height).into(),
+            _padding: 0.0,
+            forward: self.forward().into(),
+            _padding2: 0.0,
+        }
+    }
+}
+
+/// The CameraConstants cbuffer. HLSL starts a new 16 byte register for a float3 that won't fit in
+/// what's left of the last one, which the padding stands in for.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraConstants {
+    pub position: [f32; 3],
+    pub near: f32,
+    /// From the centre of the image to its right edge, one unit along the view direction
+    pub right: [f32; 3],
+    pub far: f32,
+    /// From the centre of the image to its top edge, one unit along the view direction
+    pub up: [f32; 3],
+    _padding: f32,
+    /// Unit length
+    pub forward: [f32; 3],
+    _padding2: f32,
+}
+
+impl CameraConstants {
+    /// What the root signature's constants parameter for the camera holds
+    #[cfg_attr(not(d3d12), allow(dead_code))]
+    pub const NUM_32BIT_VALUES: u32 = (size_of::<Self>() / 4) as u32;
+
+    /// The ray RayGeneration traces through `uv`, which goes from (0, 0) at the top left corner of
+    /// the image to (1, 1) at the bottom right. Directions are one unit long along the view
+    /// direction, so TMin and TMax clip at the near and far planes.
+    pub fn ray(&self, uv: Vector2<f32>) -> Ray {
+        let ndc = uv * 2.0 - Vector2::repeat(1.0);
+        let direction = Vector3::from(self.forward) + Vector3::from(self.right) * ndc.x
+            - Vector3::from(self.up) * ndc.y;
+
+        Ray {
+            origin: self.position.into(),
+            direction,
+            t_min: self.near,
+            t_max: self.far,
+        }
+    }
+
+    /// The ray through the centre of pixel (`x`, `y`) of a `width` by `height` image
+    pub fn pixel_ray(&self, x: u32, y: u32, width: u32, height: u32) -> Ray {
+        let size = Vector2::new(width as f32, height as f32);
+        let uv = (Vector2::new(x as f32, y as f32) + Vector2::repeat(0.5)).component_div(&size);
+        self.ray(uv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::assert_near;
+    use std::mem::offset_of;
+
+    #[test]
+    fn corners_are_at_the_edges_of_the_field_of_view() {
+        let mut camera = Camera::new(Point3::new(1.0, 2.0, 3.0));
+        camera.fov_y = 90.0_f32.to_radians();
+        let constants = camera.constants(200, 100);
+
+        let corner = |u, v| constants.ray(Vector2::new(u, v)).direction;
+        assert_near(corner(0.0, 0.0), Vector3::new(-2.0, 1.0, 1.0));
+        assert_near(corner(1.0, 0.0), Vector3::new(2.0, 1.0, 1.0));
+        assert_near(corner(0.0, 1.0), Vector3::new(-2.0, -1.0, 1.0));
+        assert_near(corner(1.0, 1.0), Vector3::new(2.0, -1.0, 1.0));
+        assert_near(corner(0.5, 0.5), Vector3::z());
+
+        let ray = constants.ray(Vector2::zeros());
+        assert_eq!(ray.origin, camera.position);
+        assert_eq!((ray.t_min, ray.t_max), (T_MIN, T_MAX));
+    }
+
+    #[test]
+    fn top_and_bottom_edges_are_the_field_of_view_apart() {
+        let mut camera = Camera::new(Point3::origin());
+        camera.fov_y = 40.0_f32.to_radians();
+        camera.look_to(&Vector3::new(1.0, -1.0, 2.0), &Vector3::y());
+        let constants = camera.constants(640, 480);
+
+        let top = constants.ray(Vector2::new(0.5, 0.0)).direction;
+        let bottom = constants.ray(Vector2::new(0.5, 1.0)).direction;
+        let angle = top.normalize().dot(&bottom.normalize()).acos();
+        assert!((angle - camera.fov_y).abs() < 1e-5);
+
+        let left = constants.ray(Vector2::new(0.0, 0.5)).direction;
+        let right = constants.ray(Vector2::new(1.0, 0.5)).direction;
+        let angle = left.normalize().dot(&right.normalize()).acos();
+        let fov_x = 2.0 * ((camera.fov_y / 2.0).tan() * 640.0 / 480.0).atan();
+        assert!((angle - fov_x).abs() < 1e-5);
+    }
+
+    #[test]
+    fn looks_where_it_is_told() {
+        let mut camera = Camera::new(Point3::origin());
+        camera.look_to(&Vector3::new(10.0, 0.0, 0.0), &Vector3::y());
+        assert_near(camera.forward(), Vector3::x());
+        assert_near(camera.up(), Vector3::y());
+        // Left handed, so turning right from +Z leaves -Z on the right
+        assert_near(camera.right(), -Vector3::z());
+
+        camera.look_to(&Vector3::y(), &Vector3::y());
+        assert_near(camera.forward(), Vector3::y());
+        assert!(camera.up().iter().all(|c| c.is_finite()));
+    }
+
+    #[test]
+    fn looking_nowhere_keeps_the_orientation() {
+        let mut camera = Camera::new(Point3::origin());
+        camera.look_to(&Vector3::x(), &Vector3::y());
+        let orientation = camera.orientation;
+
+        camera.look_to(&Vector3::zeros(), &Vector3::y());
+        assert_eq!(camera.orientation, orientation);
+        camera.look_to(&Vector3::new(f32::NAN, 0.0, 1.0), &Vector3::y());
+        assert_eq!(camera.orientation, orientation);
+    }
+
+    #[test]
+    fn pixel_rays_go_through_pixel_centres() {
+        let constants = Camera::new(Point3::origin()).constants(4, 2);
+        let ray = constants.pixel_ray(0, 1, 4, 2);
+        let expected = constants.ray(Vector2::new(0.125, 0.75));
+        assert_eq!(ray.direction, expected.direction);
+    }
+
+    #[test]
+    fn constants_are_packed_like_the_cbuffer() {
+        assert_eq!(size_of::<CameraConstants>(), 64);
+        assert_eq!(offset_of!(CameraConstants, right), 16);
+        assert_eq!(offset_of!(CameraConstants, up), 32);
+        assert_eq!(offset_of!(CameraConstants, forward), 48);
+    }
+}
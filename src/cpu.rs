@@ -0,0 +1,445 @@
+//! CPU reference implementation of the ray tracing pipeline in shaders/shaders.hlsl. Every function
+//! here mirrors one in the shader so the output can be compared against the GPU path pixel by pixel.
+
+use crate::attributes::{HitAttributes, PackedAttributes};
+use crate::bvh::Bvh;
+use crate::camera::{Camera, CameraConstants};
+use crate::image::Image;
+use crate::light::{LightSample, PackedLight};
+use crate::material::{self, PackedMaterial};
+use crate::ray::{Ray, T_MAX, T_MIN};
+use crate::scene_graph::SceneGraph;
+use crate::scene_model::SceneModel;
+use crate::tile_renderer::{CancelFlag, Cancelled, Progress, Tile, TileRenderer};
+use crate::tlas::{InstanceDesc, InstanceHit as Hit, Tlas};
+use crate::tlas_policy::{TlasBuild, TlasPolicy};
+use nalgebra::{Matrix3, Point3, Vector3};
+
+const SKY_TOP: [f32; 3] = [0.24, 0.44, 0.72];
+const SKY_BOTTOM: [f32; 3] = [0.75, 0.86, 0.93];
+
+/// Every TraceRay in the shader includes all instances
+const INCLUSION_MASK: u8 = 0xFF;
+
+/// Primary rays are traced in packets covering a square of pixels this wide
+const PACKET_SIZE: u32 = 8;
+
+/// The shader's MAX_BOUNCES, past which mirrors and glass come out black
+const MAX_BOUNCES: u32 = 6;
+
+#[derive(Clone, Copy)]
+struct Payload {
+    color: Vector3<f32>,
+    /// Mirror and glass bounces on the way to this ray
+    depth: u32,
+    /// Rays traced on behalf of this one, which the shader doesn't keep track of
+    rays_traced: u64,
+}
+
+fn saturate(x: f32) -> f32 {
+    x.clamp(0.0, 1.0)
+}
+
+pub struct CpuScene {
+    camera: Camera,
+    lights: Vec<PackedLight>,
+    /// One BVH per mesh, standing in for the BLASes
+    meshes: Vec<Bvh>,
+    attributes: PackedAttributes,
+    /// Indexed by InstanceID()
+    materials: Vec<PackedMaterial>,
+    /// Refit or rebuilt from the scene graph by every update
+    tlas: Tlas,
+    policy: TlasPolicy,
+    graph: SceneGraph,
+}
+
+impl CpuScene {
+    pub fn build(model: &SceneModel) -> Self {
+        let meshes: Vec<Bvh> = model
+            .meshes
+            .iter()
+            .map(|mesh| match &mesh.bvh {
+                Some(bvh) => bvh.clone(),
+                None => Bvh::build(&mesh.positions, &mesh.indices, &mesh.bvh_options()),
+            })
+            .collect();
+
+        let mut scene = Self {
+            camera: model.camera,
+            lights: model.lights.iter().map(PackedLight::from).collect(),
+            tlas: Tlas::build(&meshes, Vec::new()),
+            meshes,
+            attributes: PackedAttributes::new(&model.meshes),
+            materials: model.materials.iter().map(PackedMaterial::from).collect(),
+            policy: TlasPolicy::default(),
+            graph: SceneGraph::new(model),
+        };
+        scene.update(0.0);
+        scene
+    }
+
+    pub fn graph_mut(&mut self) -> &mut SceneGraph {
+        &mut self.graph
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+    }
+
+    pub fn update(&mut self, time: f32) {
+        let instances = self.graph.instance_descs(time);
+        let matrices: Vec<_> = instances
+            .iter()
+            .map(InstanceDesc::object_to_world)
+            .collect();
+        let degradation = Some(self.tlas.degradation());
+        match self
+            .policy
+            .decide(self.graph.revision(), &matrices, degradation)
+        {
+            TlasBuild::Rebuild => self.tlas = Tlas::build(&self.meshes, instances),
+            TlasBuild::Refit => self.tlas.refit(&self.meshes, &instances),
+        }
+    }
+
+    /// Equivalent of `(float3x3)WorldToObject3x4()` when used as `mul(n, m)`, which takes object
+    /// space normals to world space
+    fn normal_matrix(&self, hit: &Hit) -> Matrix3<f32> {
+        self.tlas.instance(hit.instance).transform.normal_matrix()
+    }
+
+    /// Equivalent of WorldNormal, the unit world space normal where `attributes` were interpolated
+    fn world_normal(&self, hit: &Hit, attributes: &HitAttributes) -> Vector3<f32> {
+        (self.normal_matrix(hit) * attributes.normal).normalize()
+    }
+
+    fn trace_ray(&self, ray: &Ray, payload: &mut Payload) {
+        payload.rays_traced += 1;
+        let hit = self.tlas.closest_hit(&self.meshes, ray, INCLUSION_MASK);
+        self.shade(ray, hit.as_ref(), payload);
+    }
+
+    /// Runs the closest hit or miss shader for a ray that's been traced
+    fn shade(&self, ray: &Ray, hit: Option<&Hit>, payload: &mut Payload) {
+        match hit {
+            Some(hit) => self.closest_hit(ray, hit, payload),
+            None => Self::miss(ray, payload),
+        }
+    }
+
+    fn miss(ray: &Ray, payload: &mut Payload) {
+        let slope = ray.direction.normalize().y;
+        let t = saturate(slope * 5.0 + 0.5);
+        let bottom = Vector3::from(SKY_BOTTOM);
+        let top = Vector3::from(SKY_TOP);
+        payload.color = bottom.lerp(&top, t);
+    }
+
+    fn hit_faces(&self, ray: &Ray, hit: &Hit, attributes: &HitAttributes, payload: &mut Payload) {
+        let pos = ray.origin + ray.direction * hit.triangle.t;
+        let mut color = attributes.normal.abs() / 3.0 + Vector3::repeat(0.5);
+        let uv = attributes.uv;
+        if uv.min() < 0.03 || uv.max() > 0.97 {
+            color = Vector3::repeat(0.25);
+        }
+
+        let normal = self.world_normal(hit, attributes);
+        let light = self.direct_light(pos, normal, payload) + Vector3::repeat(0.33);
+        payload.color = color.component_mul(&light);
+    }
+
+    fn hit_mirror(&self, ray: &Ray, hit: &Hit, attributes: &HitAttributes, payload: &mut Payload) {
+        if payload.depth == MAX_BOUNCES {
+            payload.color = Vector3::zeros();
+            return;
+        }
+
+        let pos = ray.origin + ray.direction * hit.triangle.t;
+        let normal = self.world_normal(hit, attributes);
+        let reflected = material::reflect(ray.direction.normalize(), normal);
+
+        let ray = Ray {
+            origin: pos,
+            direction: reflected,
+            t_min: T_MIN,
+            t_max: T_MAX,
+        };
+
+        payload.depth += 1;
+        self.trace_ray(&ray, payload);
+    }
+
+    fn in_shadow(&self, pos: Point3<f32>, light: &LightSample, payload: &mut Payload) -> bool {
+        payload.rays_traced += 1;
+        let shadow_ray = Ray {
+            origin: pos,
+            direction: light.direction,
+            t_min: T_MIN,
+            t_max: light.distance,
+        };
+
+        // The shader only checks whether the shadow ray missed, so any intersection will do
+        self.tlas
+            .any_hit(&self.meshes, &shadow_ray, INCLUSION_MASK)
+            .is_some()
+    }
+
+    /// Equivalent of DirectLight, the light from every light that reaches `pos` on a surface facing
+    /// `normal`, scaled by the cosine of the angle it comes in at
+    fn direct_light(
+        &self,
+        pos: Point3<f32>,
+        normal: Vector3<f32>,
+        payload: &mut Payload,
+    ) -> Vector3<f32> {
+        let mut total = Vector3::zeros();
+        for light in &self.lights {
+            let sample = light.sample(&pos);
+            let cosine = saturate(normal.dot(&sample.direction));
+            let lit = cosine > 0.0 && sample.radiance != Vector3::zeros();
+            if lit && !self.in_shadow(pos, &sample, payload) {
+                total += sample.radiance * cosine;
+            }
+        }
+
+        total
+    }
+
+    fn hit_checker(&self, ray: &Ray, hit: &Hit, attributes: &HitAttributes, payload: &mut Payload) {
+        let pos = ray.origin + ray.direction * hit.triangle.t;
+        let normal = self.world_normal(hit, attributes);
+        let light = self.direct_light(pos, normal, payload);
+        payload.color = material::diffuse(material::checker(&pos), light);
+    }
+
+    fn hit_diffuse(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        attributes: &HitAttributes,
+        material: &PackedMaterial,
+        payload: &mut Payload,
+    ) {
+        let pos = ray.origin + ray.direction * hit.triangle.t;
+        let normal = self.world_normal(hit, attributes);
+        let light = self.direct_light(pos, normal, payload);
+        payload.color = material::diffuse(material.color.into(), light);
+    }
+
+    fn hit_glass(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        attributes: &HitAttributes,
+        material: &PackedMaterial,
+        payload: &mut Payload,
+    ) {
+        if payload.depth == MAX_BOUNCES {
+            payload.color = Vector3::zeros();
+            return;
+        }
+
+        let pos = ray.origin + ray.direction * hit.triangle.t;
+        let direction = ray.direction.normalize();
+        let mut normal = self.world_normal(hit, attributes);
+        let mut cosine = -direction.dot(&normal);
+        let mut eta = 1.0 / material.ior;
+        // Leaving the glass
+        if cosine < 0.0 {
+            normal = -normal;
+            cosine = -cosine;
+            eta = material.ior;
+        }
+
+        let reflectance = material::fresnel(cosine, eta);
+        payload.depth += 1;
+        let mut reflection = *payload;
+        let mut transmission = *payload;
+
+        let trace = |direction, payload: &mut Payload| {
+            let ray = Ray {
+                origin: pos,
+                direction,
+                t_min: T_MIN,
+                t_max: T_MAX,
+            };
+            payload.rays_traced = 0;
+            self.trace_ray(&ray, payload);
+        };
+        trace(material::reflect(direction, normal), &mut reflection);
+        if let Some(refracted) = material::refract(direction, normal, eta) {
+            trace(refracted, &mut transmission);
+        }
+
+        let tinted = transmission
+            .color
+            .component_mul(&Vector3::from(material.color));
+        payload.color = tinted.lerp(&reflection.color, reflectance);
+        payload.rays_traced += reflection.rays_traced + transmission.rays_traced;
+    }
+
+    fn closest_hit(&self, ray: &Ray, hit: &Hit, payload: &mut Payload) {
+        let instance = self.tlas.instance(hit.instance);
+        let material = &self.materials[instance.instance_id() as usize];
+        let attributes = self.attributes.interpolate(
+            instance.blas,
+            hit.triangle.primitive,
+            hit.triangle.barycentrics,
+        );
+
+        match material.kind {
+            material::FACES => self.hit_faces(ray, hit, &attributes, payload),
+            material::MIRROR => self.hit_mirror(ray, hit, &attributes, payload),
+            material::CHECKER => self.hit_checker(ray, hit, &attributes, payload),
+            material::DIFFUSE => self.hit_diffuse(ray, hit, &attributes, material, payload),
+            material::GLASS => self.hit_glass(ray, hit, &attributes, material, payload),
+            material::EMISSIVE => payload.color = material.color.into(),
+            _ => payload.color = Vector3::new(1.0, 0.0, 1.0),
+        }
+    }
+
+    /// Renders `tile` of a `width` by `height` frame into `pixels`, row by row, and returns how
+    /// many rays that took. Primary rays go through the scene in packets, every ray after them on
+    /// its own since reflections and shadow rays head off in all directions.
+    fn render_tile(
+        &self,
+        camera: &CameraConstants,
+        tile: Tile,
+        width: u32,
+        height: u32,
+        pixels: &mut [[f32; 4]],
+    ) -> u64 {
+        let mut rays_traced = 0;
+        let mut packet = Vec::new();
+        let mut rays = Vec::new();
+        for packet_y in (tile.y..tile.y + tile.height).step_by(PACKET_SIZE as usize) {
+            for packet_x in (tile.x..tile.x + tile.width).step_by(PACKET_SIZE as usize) {
+                packet.clear();
+                for y in packet_y..(packet_y + PACKET_SIZE).min(tile.y + tile.height) {
+                    for x in packet_x..(packet_x + PACKET_SIZE).min(tile.x + tile.width) {
+                        packet.push((x, y));
+                    }
+                }
+
+                rays.clear();
+                rays.extend(
+                    packet
+                        .iter()
+                        .map(|&(x, y)| camera.pixel_ray(x, y, width, height)),
+                );
+                let hits = self.tlas.closest_hits(&self.meshes, &rays, INCLUSION_MASK);
+
+                for ((&(x, y), ray), hit) in packet.iter().zip(&rays).zip(&hits) {
+                    let mut payload = Payload {
+                        color: Vector3::zeros(),
+                        depth: 0,
+                        rays_traced: 1,
+                    };
+                    self.shade(ray, hit.as_ref(), &mut payload);
+                    rays_traced += payload.rays_traced;
+
+                    let color = payload.color;
+                    let i = (y - tile.y) * tile.width + x - tile.x;
+                    pixels[i as usize] = [color.x, color.y, color.z, 1.0];
+                }
+            }
+        }
+
+        rays_traced
+    }
+
+    /// Renders a frame on `renderer`'s threads, see `TileRenderer::render`
+    pub fn render(
+        &self,
+        renderer: &TileRenderer,
+        width: u32,
+        height: u32,
+        cancel: &CancelFlag,
+        progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<Image, Cancelled> {
+        let camera = self.camera.constants(width, height);
+        renderer.render(width, height, cancel, progress, |tile, pixels| {
+            self.render_tile(&camera, tile, width, height, pixels)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(source: &str) -> CpuScene {
+        CpuScene::build(&SceneModel::parse("test.ron", source).unwrap())
+    }
+
+    fn down_at(x: f32, z: f32) -> Ray {
+        Ray {
+            origin: Point3::new(x, 10.0, z),
+            direction: -Vector3::y(),
+            t_min: T_MIN,
+            t_max: T_MAX,
+        }
+    }
+
+    #[test]
+    fn closest_hit_is_the_nearest_triangle_in_a_mesh() {
+        // The near triangle comes first, so going by the last one hit would find the far one
+        let scene = scene(
+            r#"Scene(
+                camera: (position: (0.0, 0.0, -5.0)),
+                lights: [Directional(direction: (0.0, -1.0, 0.0))],
+                materials: [(name: "gray", kind: Diffuse(color: (0.5, 0.5, 0.5)))],
+                meshes: [(name: "layers", source: Triangles(positions: [
+                    (-1.0, 1.0, -1.0), (-1.0, 1.0, 1.0), (1.0, 1.0, -1.0),
+                    (-1.0, 0.0, -1.0), (-1.0, 0.0, 1.0), (1.0, 0.0, -1.0),
+                ]))],
+                instances: [(mesh: "layers", material: "gray")],
+            )"#,
+        );
+
+        let hit = scene
+            .tlas
+            .closest_hit(&scene.meshes, &down_at(-0.5, -0.5), INCLUSION_MASK);
+        let hit = hit.unwrap();
+        assert_eq!(hit.triangle.primitive, 0);
+        assert_eq!(hit.triangle.t, 9.0);
+    }
+
+    #[test]
+    fn glass_cubes_show_what_is_behind_them() {
+        let scene = scene(
+            r#"Scene(
+                camera: (position: (0.0, 0.0, -5.0)),
+                lights: [Directional(direction: (0.0, -1.0, 0.0))],
+                materials: [
+                    (name: "glass", kind: Glass()),
+                    (name: "red", kind: Emissive(color: (1.0, 0.0, 0.0))),
+                ],
+                meshes: [(name: "cube", source: Cube), (name: "wall", source: Quad)],
+                instances: [
+                    (mesh: "cube", material: "glass"),
+                    (
+                        mesh: "wall",
+                        material: "red",
+                        translation: (0.0, 0.0, 3.0),
+                        rotation: (-1.5707964, 0.0, 0.0),
+                        scale: (5.0, 5.0, 5.0),
+                    ),
+                ],
+            )"#,
+        );
+
+        // Straight in through the middle of a face and out the other side, losing a little to
+        // reflection each time
+        let ray = scene.camera.constants(1, 1).pixel_ray(0, 0, 1, 1);
+        let mut payload = Payload {
+            color: Vector3::zeros(),
+            depth: 0,
+            rays_traced: 1,
+        };
+        scene.trace_ray(&ray, &mut payload);
+        assert!(payload.color.x > 0.9, "{:?}", payload.color);
+        assert!(payload.color.y < 0.1, "{:?}", payload.color);
+    }
+}
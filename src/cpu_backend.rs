@@ -0,0 +1,181 @@
+use crate::backend::{BackendResult, RenderBackend};
+use crate::camera::Camera;
+use crate::cpu::CpuScene;
+use crate::image::Image;
+use crate::scene_graph::SceneGraph;
+use crate::scene_model::SceneModel;
+use crate::tile_renderer::{CancelFlag, Progress, TileRenderer};
+use softbuffer::{Context, Surface};
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use winit::window::Window;
+
+const SCENE_NOT_BUILT: &str = "build_scene must be called before rendering";
+
+type WindowSurface = Surface<Rc<Window>, Rc<Window>>;
+
+/// Portable backend that traces with the CPU reference implementation and presents through a
+/// software framebuffer, for platforms and machines without D3D12 ray tracing
+pub struct CpuBackend {
+    width: u32,
+    height: u32,
+    surface: Option<WindowSurface>,
+    scene: Option<CpuScene>,
+    frame: Option<Image>,
+    renderer: TileRenderer,
+    progress: Box<dyn Fn(&Progress) + Send + Sync>,
+}
+
+impl CpuBackend {
+    /// Creates a backend with no window, which can only be read back from. It renders on `threads`
+    /// threads, or one per core for 0.
+    pub fn headless(width: u32, height: u32, threads: usize) -> BackendResult<Self> {
+        Ok(Self {
+            width,
+            height,
+            surface: None,
+            scene: None,
+            frame: None,
+            renderer: TileRenderer::new(threads)?,
+            progress: Box::new(|_| ()),
+        })
+    }
+
+    /// Has `progress` called as each frame is traced
+    pub fn on_progress(&mut self, progress: impl Fn(&Progress) + Send + Sync + 'static) {
+        self.progress = Box::new(progress);
+    }
+
+    pub fn from_window(window: Rc<Window>) -> BackendResult<Self> {
+        let size = window.inner_size();
+        let context = Context::new(window.clone())?;
+        let surface = Surface::new(&context, window)?;
+
+        let mut backend = Self::headless(size.width, size.height, 0)?;
+        backend.surface = Some(surface);
+        backend.resize(size.width, size.height)?;
+        Ok(backend)
+    }
+}
+
+impl RenderBackend for CpuBackend {
+    fn build_scene(&mut self, scene: &SceneModel) -> BackendResult<()> {
+        self.scene = Some(CpuScene::build(scene));
+        Ok(())
+    }
+
+    fn scene_graph(&mut self) -> BackendResult<&mut SceneGraph> {
+        Ok(self.scene.as_mut().ok_or(SCENE_NOT_BUILT)?.graph_mut())
+    }
+
+    fn set_camera(&mut self, camera: &Camera) -> BackendResult<()> {
+        self.scene
+            .as_mut()
+            .ok_or(SCENE_NOT_BUILT)?
+            .set_camera(camera);
+        Ok(())
+    }
+
+    fn update_transforms(&mut self, time: f32) -> BackendResult<()> {
+        let scene = self.scene.as_mut().ok_or(SCENE_NOT_BUILT)?;
+        scene.update(time);
+        Ok(())
+    }
+
+    fn trace_frame(&mut self) -> BackendResult<()> {
+        let scene = self.scene.as_ref().ok_or(SCENE_NOT_BUILT)?;
+        // Nothing cancels frames yet, they're traced one after the other on the event loop's thread
+        let frame = scene.render(
+            &self.renderer,
+            self.width,
+            self.height,
+            &CancelFlag::default(),
+            &self.progress,
+        )?;
+        self.frame = Some(frame);
+        Ok(())
+    }
+
+    fn present(&mut self) -> BackendResult<()> {
+        let (Some(surface), Some(frame)) = (&mut self.surface, &self.frame) else {
+            return Ok(());
+        };
+
+        let mut buffer = surface.buffer_mut()?;
+        if buffer.len() != frame.pixels.len() {
+            // The window was resized after this frame was traced
+            return Ok(());
+        }
+
+        for (pixel, [r, g, b, _]) in buffer.iter_mut().zip(frame.rgba8()) {
+            *pixel = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        }
+
+        Ok(buffer.present()?)
+    }
+
+    fn readback(&mut self) -> BackendResult<Image> {
+        Ok(self
+            .frame
+            .clone()
+            .ok_or("trace_frame must be called before readback")?)
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        self.width = width.max(1);
+        self.height = height.max(1);
+
+        if let Some(surface) = &mut self.surface {
+            let width = NonZeroU32::new(self.width).unwrap();
+            let height = NonZeroU32::new(self.height).unwrap();
+            surface.resize(width, height)?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_idle(&mut self) -> BackendResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_model::{Instance, Node};
+
+    fn frame(backend: &mut CpuBackend) -> Vec<[f32; 4]> {
+        backend.update_transforms(0.0).unwrap();
+        backend.trace_frame().unwrap();
+        backend.readback().unwrap().pixels
+    }
+
+    #[test]
+    fn scene_graph_edits_show_up_from_the_next_update() {
+        let mut backend = CpuBackend::headless(32, 18, 1).unwrap();
+        assert!(backend.scene_graph().is_err());
+
+        let scene = SceneModel::default_scene();
+        backend.build_scene(&scene).unwrap();
+        let before = frame(&mut backend);
+
+        // Another of the first instance, hanging off its node
+        let graph = backend.scene_graph().unwrap();
+        let first = scene.instances[0];
+        let node = graph
+            .add_node(Node {
+                parent: Some(first.node),
+                ..scene.nodes[first.node]
+            })
+            .unwrap();
+        let handle = graph.add_instance(Instance { node, ..first }).unwrap();
+        assert_ne!(frame(&mut backend), before);
+
+        backend
+            .scene_graph()
+            .unwrap()
+            .remove_instance(handle)
+            .unwrap();
+        assert_eq!(frame(&mut backend), before);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::backend::{BackendResult, RenderBackend};
+use crate::camera::Camera;
+use crate::device_interface::DeviceInterface;
+use crate::image::Image;
+use crate::imports::*;
+use crate::pipeline::Pipeline;
+use crate::scene::Scene;
+use crate::scene_graph::SceneGraph;
+use crate::scene_model::SceneModel;
+use crate::surface::Surface;
+
+const SCENE_NOT_BUILT: &str = "build_scene must be called before rendering";
+
+pub struct D3D12Backend {
+    interface: DeviceInterface,
+    surface: Surface,
+    pipeline: Pipeline,
+    scene: Option<Scene>,
+}
+
+impl D3D12Backend {
+    pub fn create(window: HWND) -> Result<Self> {
+        let interface = DeviceInterface::create()?;
+        let surface = Surface::from_handle(&interface, window)?;
+        let pipeline = Pipeline::create(&interface)?;
+
+        Ok(Self {
+            interface,
+            surface,
+            pipeline,
+            scene: None,
+        })
+    }
+
+    /// Creates a backend that renders into an offscreen target and can only be read back from
+    pub fn offscreen(width: u32, height: u32) -> Result<Self> {
+        let interface = DeviceInterface::create()?;
+        let surface = Surface::offscreen(&interface, width, height)?;
+        let pipeline = Pipeline::create(&interface)?;
+
+        Ok(Self {
+            interface,
+            surface,
+            pipeline,
+            scene: None,
+        })
+    }
+}
+
+impl RenderBackend for D3D12Backend {
+    fn build_scene(&mut self, scene: &SceneModel) -> BackendResult<()> {
+        self.scene = Some(Scene::build(&self.interface, scene)?);
+        Ok(())
+    }
+
+    fn set_camera(&mut self, camera: &Camera) -> BackendResult<()> {
+        self.scene
+            .as_mut()
+            .ok_or(SCENE_NOT_BUILT)?
+            .set_camera(camera);
+        Ok(())
+    }
+
+    fn update_transforms(&mut self, time: f32) -> BackendResult<()> {
+        // This is the first thing recorded each frame, so start a fresh command list here
+        unsafe {
+            self.interface.command_allocator.Reset()?;
+            self.interface
+                .command_list
+                .Reset(&self.interface.command_allocator, None)?;
+        }
+
+        let scene = self.scene.as_mut().ok_or(SCENE_NOT_BUILT)?;
+        Ok(scene.update(&self.interface, time)?)
+    }
+
+    fn scene_graph(&mut self) -> BackendResult<&mut SceneGraph> {
+        Ok(self.scene.as_mut().ok_or(SCENE_NOT_BUILT)?.graph_mut())
+    }
+
+    fn trace_frame(&mut self) -> BackendResult<()> {
+        let interface = &self.interface;
+        let scene = self.scene.as_ref().ok_or(SCENE_NOT_BUILT)?;
+
+        self.pipeline.bind(interface);
+        let surface_desc = self.surface.bind(interface)?;
+        scene.bind(interface, surface_desc.Width as u32, surface_desc.Height);
+
+        let rays_desc = self.pipeline.create_rays_description(&surface_desc);
+        unsafe { interface.command_list.DispatchRays(&rays_desc) };
+        Ok(())
+    }
+
+    fn present(&mut self) -> BackendResult<()> {
+        self.surface.present(&self.interface)?;
+        Ok(self.interface.wait_for_gpu()?)
+    }
+
+    fn readback(&mut self) -> BackendResult<Image> {
+        Ok(self.surface.readback(&self.interface)?)
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        Ok(self.surface.resize(&self.interface, width, height)?)
+    }
+
+    fn wait_idle(&mut self) -> BackendResult<()> {
+        Ok(self.interface.wait_for_gpu()?)
+    }
+}
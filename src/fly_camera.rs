@@ -0,0 +1,259 @@
+//! First person camera flown around the window with the keyboard and mouse. WASD moves, Q and E go
+//! down and up, holding the right mouse button looks around, the scroll wheel dollies along the view
+//! direction and holding shift speeds all the moving up.
+
+use crate::camera::Camera;
+use nalgebra::{UnitQuaternion, Vector2, Vector3};
+use std::collections::HashSet;
+use std::f32::consts::FRAC_PI_2;
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+
+/// Units per second
+const SPEED: f32 = 3.0;
+
+/// How much faster everything moves with shift held
+const FAST: f32 = 4.0;
+
+/// Radians per unit of mouse motion, which is roughly a pixel
+const LOOK_SENSITIVITY: f32 = 0.003;
+
+/// Units per line scrolled
+const DOLLY_STEP: f32 = 0.5;
+
+/// Pixels of a touchpad scroll that count as a line
+const PIXELS_PER_LINE: f32 = 20.0;
+
+/// Looking straight up or down would leave which way is forward up to chance
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+pub struct FlyCamera {
+    camera: Camera,
+    /// Radians turned right from +Z
+    yaw: f32,
+    /// Radians looking down from level
+    pitch: f32,
+    held: HashSet<KeyCode>,
+    looking: bool,
+    /// Mouse motion and scrolling since the last update
+    look: Vector2<f32>,
+    dolly: f32,
+}
+
+impl FlyCamera {
+    /// Starts off from `camera`, levelled out if it's rolled
+    pub fn new(camera: Camera) -> Self {
+        let forward = camera.forward();
+        Self {
+            camera,
+            yaw: forward.x.atan2(forward.z),
+            pitch: (-forward.y).asin().clamp(-MAX_PITCH, MAX_PITCH),
+            held: HashSet::new(),
+            looking: false,
+            look: Vector2::zeros(),
+            dolly: 0.0,
+        }
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    self.key(key, event.state);
+                }
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Right,
+                ..
+            } => self.looking = state.is_pressed(),
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.dolly += match *delta {
+                    MouseScrollDelta::LineDelta(_, lines) => lines,
+                    MouseScrollDelta::PixelDelta(pixels) => pixels.y as f32 / PIXELS_PER_LINE,
+                }
+            }
+            // Releases that happen while another window has focus never arrive
+            WindowEvent::Focused(false) => {
+                self.held.clear();
+                self.looking = false;
+            }
+            _ => (),
+        }
+    }
+
+    /// Mouse motion comes in raw from the device, so it's the same whatever the cursor is up to
+    pub fn device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = *event {
+            if self.looking {
+                self.look += Vector2::new(x as f32, y as f32);
+            }
+        }
+    }
+
+    /// Presses or releases `key`. Keyboard events can't be made up outside winit, so tests come in
+    /// through here.
+    pub fn key(&mut self, key: KeyCode, state: ElementState) {
+        if state.is_pressed() {
+            self.held.insert(key);
+        } else {
+            self.held.remove(&key);
+        }
+    }
+
+    /// -1, 0 or 1 along an axis depending on which of its keys are held
+    fn axis(&self, negative: KeyCode, positive: KeyCode) -> f32 {
+        let held = |key| if self.held.contains(&key) { 1.0 } else { 0.0 };
+        held(positive) - held(negative)
+    }
+
+    /// Applies the input since the last update, which was `dt` seconds ago, and returns whether the
+    /// camera moved. Moving with the keys goes the same distance in the same time however it's
+    /// split up into frames.
+    pub fn update(&mut self, dt: f32) -> bool {
+        let look = std::mem::take(&mut self.look) * LOOK_SENSITIVITY;
+        let dolly = std::mem::take(&mut self.dolly) * DOLLY_STEP;
+        let velocity = Vector3::new(
+            self.axis(KeyCode::KeyA, KeyCode::KeyD),
+            self.axis(KeyCode::KeyQ, KeyCode::KeyE),
+            self.axis(KeyCode::KeyS, KeyCode::KeyW),
+        );
+        if look == Vector2::zeros() && dolly == 0.0 && velocity == Vector3::zeros() {
+            return false;
+        }
+
+        self.yaw += look.x;
+        self.pitch = (self.pitch + look.y).clamp(-MAX_PITCH, MAX_PITCH);
+        self.camera.orientation = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.yaw)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.pitch);
+
+        let fast = [KeyCode::ShiftLeft, KeyCode::ShiftRight]
+            .iter()
+            .any(|key| self.held.contains(key));
+        let speed = if fast { FAST } else { 1.0 };
+        let step = velocity * SPEED * dt + Vector3::z() * dolly;
+        self.camera.position += self.camera.orientation * step * speed;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::assert_near;
+    use nalgebra::Point3;
+    use winit::event::DeviceId;
+
+    fn fly_camera() -> FlyCamera {
+        FlyCamera::new(Camera::new(Point3::new(0.0, 1.0, 0.0)))
+    }
+
+    fn mouse_button(state: ElementState) -> WindowEvent {
+        WindowEvent::MouseInput {
+            device_id: unsafe { DeviceId::dummy() },
+            state,
+            button: MouseButton::Right,
+        }
+    }
+
+    fn mouse_motion(x: f64, y: f64) -> DeviceEvent {
+        DeviceEvent::MouseMotion { delta: (x, y) }
+    }
+
+    #[test]
+    fn keys_move_the_same_distance_at_any_frame_rate() {
+        let mut slow = fly_camera();
+        let mut fast = fly_camera();
+        for camera in [&mut slow, &mut fast] {
+            camera.key(KeyCode::KeyW, ElementState::Pressed);
+            camera.key(KeyCode::KeyD, ElementState::Pressed);
+        }
+
+        assert!(slow.update(0.5));
+        for _ in 0..50 {
+            fast.update(0.01);
+        }
+
+        let moved = Vector3::new(SPEED, 0.0, SPEED) * 0.5;
+        assert_near(slow.camera().position.coords, Vector3::y() + moved);
+        assert_near(fast.camera().position.coords, slow.camera().position.coords);
+
+        slow.key(KeyCode::KeyW, ElementState::Released);
+        slow.key(KeyCode::KeyD, ElementState::Released);
+        assert!(!slow.update(0.5));
+    }
+
+    #[test]
+    fn shift_speeds_up() {
+        let mut camera = fly_camera();
+        camera.key(KeyCode::ShiftLeft, ElementState::Pressed);
+        camera.key(KeyCode::KeyE, ElementState::Pressed);
+        camera.update(1.0);
+        assert_near(
+            camera.camera().position.coords,
+            Vector3::y() * (1.0 + SPEED * FAST),
+        );
+    }
+
+    #[test]
+    fn mouse_only_looks_while_the_right_button_is_held() {
+        let mut camera = fly_camera();
+        camera.device_event(&mouse_motion(100.0, 0.0));
+        assert!(!camera.update(0.1));
+
+        camera.window_event(&mouse_button(ElementState::Pressed));
+        // A quarter turn right, over two movements
+        let steps = FRAC_PI_2 / LOOK_SENSITIVITY;
+        camera.device_event(&mouse_motion(steps as f64 / 2.0, 0.0));
+        camera.device_event(&mouse_motion(steps as f64 / 2.0, 0.0));
+        assert!(camera.update(0.1));
+        assert_near(camera.camera().forward(), Vector3::x());
+        assert_near(camera.camera().up(), Vector3::y());
+
+        // Moving the mouse down looks down, but only so far
+        camera.device_event(&mouse_motion(0.0, 10_000.0));
+        camera.update(0.1);
+        assert!(camera.camera().forward().y < -0.99);
+        assert!(camera.camera().forward().x > 0.0);
+
+        camera.window_event(&mouse_button(ElementState::Released));
+        let forward = camera.camera().forward();
+        camera.device_event(&mouse_motion(50.0, 50.0));
+        camera.update(0.1);
+        assert_eq!(camera.camera().forward(), forward);
+    }
+
+    #[test]
+    fn scrolling_dollies_along_the_view() {
+        let mut start = Camera::new(Point3::origin());
+        start.look_to(&Vector3::new(0.0, -1.0, 1.0), &Vector3::y());
+        let mut camera = FlyCamera::new(start);
+        assert_near(camera.camera().forward(), start.forward());
+
+        camera.window_event(&WindowEvent::MouseWheel {
+            device_id: unsafe { DeviceId::dummy() },
+            delta: MouseScrollDelta::LineDelta(0.0, 2.0),
+            phase: winit::event::TouchPhase::Moved,
+        });
+        camera.update(0.0);
+        assert_near(
+            camera.camera().position.coords,
+            start.forward() * 2.0 * DOLLY_STEP,
+        );
+    }
+
+    #[test]
+    fn losing_focus_lets_go_of_everything() {
+        let mut camera = fly_camera();
+        camera.key(KeyCode::KeyW, ElementState::Pressed);
+        camera.window_event(&mouse_button(ElementState::Pressed));
+        camera.window_event(&WindowEvent::Focused(false));
+
+        camera.device_event(&mouse_motion(100.0, 100.0));
+        assert!(!camera.update(1.0));
+    }
+}
@@ -0,0 +1,13 @@
+pub const QUAD_VTX: [f32; 18] = [
+    -1.0, 0.0, -1.0, -1.0, 0.0, 1.0, 1.0, 0.0, 1.0, -1.0, 0.0, -1.0, 1.0, 0.0, -1.0, 1.0, 0.0, 1.0,
+];
+
+pub const CUBE_VTX: [f32; 24] = [
+    -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 1.0, -1.0, -1.0, -1.0, 1.0, 1.0, -1.0,
+    1.0, -1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
+];
+
+pub const CUBE_IDX: [u16; 36] = [
+    4, 6, 0, 2, 0, 6, 0, 1, 4, 5, 4, 1, 0, 2, 1, 3, 1, 2, 1, 3, 5, 7, 5, 3, 2, 6, 3, 7, 3, 6, 4, 5,
+    6, 7, 6, 5,
+];
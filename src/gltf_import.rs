@@ -0,0 +1,449 @@
+//! glTF 2.0 import. Nodes map onto scene graph nodes, with an instance for every primitive of a
+//! node's mesh, and every primitive becomes its own mesh and so its own BLAS.
+
+use crate::camera::Camera;
+use crate::light::{Light, LightKind};
+use crate::scene_model::{self, Instance, Material, Mesh, Node, SceneModel, Transform};
+use gltf::camera::Projection;
+use gltf::mesh::Mode;
+use nalgebra::{Matrix4, Point3, Quaternion, UnitQuaternion, Vector3};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Where the default scene's camera is, for files without a camera
+const CAMERA: [f32; 3] = [0.0, 1.5, -7.0];
+
+/// The base color of primitives without a material
+const DEFAULT_COLOR: [f32; 3] = [1.0; 3];
+
+/// glTF is right handed, conjugating by this converts matrices to our left handed world. Node
+/// transforms get the same conversion applied to their translation and rotation.
+fn flip_z() -> Matrix4<f32> {
+    Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 1.0, -1.0))
+}
+
+/// How far metallic and roughness factors can be from the ones of our materials and still map
+/// onto them without a warning
+const FACTOR_TOLERANCE: f32 = 0.01;
+
+/// Maps a metallic-roughness material onto the closest of ours. Emissive materials glow their
+/// emissive factor, smooth metals become mirrors and everything else is diffuse in its base color,
+/// with a warning unless it's a rough dielectric like our diffuse.
+fn material(file: &str, material: gltf::Material, warnings: &mut Vec<String>) -> Material {
+    let name = material.name().unwrap_or("unnamed");
+    let pbr = material.pbr_metallic_roughness();
+    if pbr.base_color_texture().is_some() {
+        warnings.push(format!(
+            "{file}: Material {name:?} has a base color texture, only its base color factor is used"
+        ));
+    }
+
+    let emissive = material.emissive_factor();
+    if emissive.iter().any(|&c| c > 0.0) {
+        return Material::Emissive {
+            color: emissive,
+            intensity: 1.0,
+        };
+    }
+
+    let near = |factor: f32, ours: f32| (factor - ours).abs() <= FACTOR_TOLERANCE;
+    let (metallic, roughness) = (pbr.metallic_factor(), pbr.roughness_factor());
+    if near(metallic, 1.0) && near(roughness, 0.0) {
+        return Material::Mirror;
+    }
+    if !near(metallic, 0.0) || !near(roughness, 1.0) {
+        warnings.push(format!(
+            "{file}: Material {name:?} has a metallic factor of {metallic} and a roughness factor \
+             of {roughness}, it's imported as diffuse"
+        ));
+    }
+
+    let [r, g, b, _] = pbr.base_color_factor();
+    Material::Diffuse { color: [r, g, b] }
+}
+
+struct Importer<'a> {
+    file: &'a str,
+    buffers: Vec<gltf::buffer::Data>,
+    /// For every glTF mesh, the mesh and material index of each primitive that could be imported
+    primitives: Vec<Vec<(usize, usize)>>,
+    meshes: Vec<Mesh>,
+    nodes: Vec<Node>,
+    instances: Vec<Instance>,
+    camera: Option<Camera>,
+    warnings: Vec<String>,
+}
+
+impl Importer<'_> {
+    /// Reads a triangle list primitive, flipping Z and the winding order like the node matrices
+    fn primitive(&self, name: String, primitive: &gltf::Primitive) -> Result<Mesh, String> {
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()][..]));
+
+        let positions: Vec<f32> = reader
+            .read_positions()
+            .ok_or(format!("Mesh {name:?} has no positions"))?
+            .flat_map(|[x, y, z]| [x, y, -z])
+            .collect();
+
+        let normals = reader
+            .read_normals()
+            .map_or_else(Vec::new, |n| n.flat_map(|[x, y, z]| [x, y, -z]).collect());
+
+        // glTF UVs already start at the top left of the texture like D3D's
+        let uvs = reader
+            .read_tex_coords(0)
+            .map_or_else(Vec::new, |uv| uv.into_f32().flatten().collect());
+
+        let num_vertices = positions.len() as u32 / 3;
+        let mut indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..num_vertices).collect(),
+        };
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+
+        let mesh = Mesh {
+            name,
+            positions,
+            normals,
+            uvs,
+            indices,
+            bvh: None,
+            spatial_splits: None,
+        };
+        scene_model::check_mesh(&mesh)?;
+        Ok(mesh)
+    }
+
+    fn meshes(&mut self, document: &gltf::Document, default_material: usize) -> Result<(), String> {
+        for mesh in document.meshes() {
+            let mesh_name = mesh
+                .name()
+                .map_or_else(|| mesh.index().to_string(), str::to_string);
+
+            let mut primitives = Vec::new();
+            for primitive in mesh.primitives() {
+                let name = format!("{mesh_name}/{}", primitive.index());
+                if primitive.mode() != Mode::Triangles {
+                    self.warnings.push(format!(
+                        "{}: Skipping mesh {name:?}, {:?} primitives aren't supported",
+                        self.file,
+                        primitive.mode()
+                    ));
+                    continue;
+                }
+
+                let material = primitive.material().index().unwrap_or(default_material);
+                self.meshes.push(self.primitive(name, &primitive)?);
+                primitives.push((self.meshes.len() - 1, material));
+            }
+
+            self.primitives.push(primitives);
+        }
+
+        Ok(())
+    }
+
+    /// Adds `node` and its descendants to the scene graph. `parent_world` is the parent's world
+    /// matrix in glTF's right handed space, only needed to place the camera.
+    fn node(
+        &mut self,
+        node: gltf::Node,
+        parent: Option<usize>,
+        parent_world: Matrix4<f32>,
+        camera: Option<usize>,
+    ) {
+        let world = parent_world * Matrix4::from(node.transform().matrix());
+
+        let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+        let rotation = UnitQuaternion::from_quaternion(Quaternion::new(w, -x, -y, z));
+        self.nodes.push(Node {
+            parent,
+            transform: Transform {
+                translation: Vector3::new(translation[0], translation[1], -translation[2]),
+                rotation: rotation.scaled_axis(),
+                scale: Vector3::from(scale),
+                animation: None,
+            },
+        });
+        let index = self.nodes.len() - 1;
+
+        if let Some(mesh) = node.mesh() {
+            for &(mesh, material) in &self.primitives[mesh.index()] {
+                self.instances.push(Instance {
+                    mesh,
+                    material,
+                    node: index,
+                });
+            }
+        }
+
+        let first_camera = node.camera().filter(|c| Some(c.index()) == camera);
+        if let (Some(first_camera), None) = (first_camera, &self.camera) {
+            let to_world = flip_z() * world;
+            let mut imported = Camera::new(to_world.transform_point(&Point3::origin()));
+            // glTF cameras look down their -Z
+            imported.look_to(
+                &to_world.transform_vector(&-Vector3::z()),
+                &to_world.transform_vector(&Vector3::y()),
+            );
+
+            match first_camera.projection() {
+                Projection::Perspective(perspective) => {
+                    imported.fov_y = perspective.yfov();
+                    imported.near = perspective.znear();
+                    imported.far = perspective.zfar().unwrap_or(imported.far);
+                }
+                Projection::Orthographic(_) => {
+                    let name = first_camera.name().unwrap_or("unnamed");
+                    self.warnings.push(format!(
+                        "{}: Camera {name:?} is orthographic, it's imported as a perspective \
+                         camera looking the same way",
+                        self.file
+                    ));
+                }
+            }
+
+            self.camera = Some(imported);
+        }
+
+        for child in node.children() {
+            self.node(child, Some(index), world, camera);
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<SceneModel, String> {
+    let file = path.display().to_string();
+    let reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let gltf::Gltf { document, blob } =
+        gltf::Gltf::from_reader_without_validation(reader).map_err(|e| e.to_string())?;
+
+    // Before validating, which rejects the same files less clearly
+    if let Some(extension) = document.extensions_required().next() {
+        return Err(format!("Requires the unsupported extension {extension}"));
+    }
+    let document = gltf::Document::from_json(document.into_json()).map_err(|e| e.to_string())?;
+
+    let mut warnings: Vec<String> = document
+        .extensions_used()
+        .map(|extension| format!("{file}: Ignoring the unsupported extension {extension}"))
+        .collect();
+
+    let buffers = gltf::import_buffers(&document, path.parent(), blob)
+        .map_err(|e| format!("Couldn't load buffers: {e}"))?;
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut sources = vec![path.to_path_buf()];
+    sources.extend(
+        document
+            .buffers()
+            .filter_map(|buffer| match buffer.source() {
+                gltf::buffer::Source::Uri(uri) if !uri.starts_with("data:") => Some(dir.join(uri)),
+                _ => None,
+            }),
+    );
+
+    let mut materials: Vec<Material> = document
+        .materials()
+        .map(|m| material(&file, m, &mut warnings))
+        .collect();
+    let default_material = materials.len();
+    materials.push(Material::Diffuse {
+        color: DEFAULT_COLOR,
+    });
+
+    let mut importer = Importer {
+        file: &file,
+        buffers,
+        primitives: Vec::new(),
+        meshes: Vec::new(),
+        nodes: Vec::new(),
+        instances: Vec::new(),
+        camera: None,
+        warnings,
+    };
+    importer.meshes(&document, default_material)?;
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or("Has no scenes")?;
+    let camera = document.cameras().next().map(|c| c.index());
+    for node in scene.nodes() {
+        importer.node(node, None, Matrix4::identity(), camera);
+    }
+
+    Ok(SceneModel {
+        camera: importer
+            .camera
+            .unwrap_or_else(|| Camera::new(CAMERA.into())),
+        // glTF has no lights without KHR_lights_punctual, so imported scenes get the default scene's
+        // sun shining straight down
+        lights: vec![Light {
+            kind: LightKind::Directional {
+                direction: -Vector3::y(),
+            },
+            color: Vector3::repeat(1.0),
+            intensity: 1.0,
+        }],
+        materials,
+        meshes: importer.meshes,
+        nodes: importer.nodes,
+        instances: importer.instances,
+        warnings: importer.warnings,
+        sources,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A parent node rotated about a skewed axis, a child with a single triangle in it and a material
+    /// for each way materials are mapped
+    const GLTF: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}],
+        "nodes": [
+            {
+                "name": "parent",
+                "translation": [1, 2, 3],
+                "rotation": [0.09164329, 0.18328659, 0.27492988, 0.9393727],
+                "scale": [1, 2, 3],
+                "children": [1]
+            },
+            {"name": "child", "translation": [0, 0, 1], "mesh": 0}
+        ],
+        "materials": [
+            {"name": "red", "pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0}},
+            {"name": "chrome", "pbrMetallicRoughness": {"roughnessFactor": 0}},
+            {"name": "lamp", "emissiveFactor": [1, 0.5, 0]},
+            {"name": "brushed", "pbrMetallicRoughness": {"baseColorFactor": [0.5, 0.5, 0.5, 1], "roughnessFactor": 0.4}}
+        ],
+        "meshes": [{"name": "triangle", "primitives": [{"attributes": {"POSITION": 0}}]}],
+        "accessors": [{
+            "bufferView": 0,
+            "componentType": 5126,
+            "count": 3,
+            "type": "VEC3",
+            "min": [0, 0, 0],
+            "max": [1, 1, 0]
+        }],
+        "bufferViews": [{"buffer": 0, "byteLength": 36}],
+        "buffers": [{
+            "byteLength": 36,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"
+        }]
+    }"#;
+
+    /// Writes `contents` to a directory of its own for each test, which lasts as long as the guard
+    fn gltf_file(test: &str, contents: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(test);
+        let path = dir.join("scene.gltf");
+        fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn nodes_become_scene_graph_nodes() {
+        let (_dir, path) = gltf_file("gltf-nodes", GLTF);
+        let scene = load(&path).unwrap();
+
+        assert_eq!(scene.nodes.len(), 2);
+        assert_eq!(scene.nodes[0].parent, None);
+        assert_eq!(scene.nodes[1].parent, Some(0));
+
+        assert_eq!(scene.meshes.len(), 1);
+        assert_eq!(scene.meshes[0].name, "triangle/0");
+        assert_eq!(scene.instances.len(), 1);
+        let instance = scene.instances[0];
+        assert_eq!((instance.mesh, instance.node), (0, 1));
+        assert_eq!(
+            scene.materials[instance.material],
+            Material::Diffuse {
+                color: DEFAULT_COLOR
+            }
+        );
+    }
+
+    #[test]
+    fn materials_map_onto_the_closest_of_ours() {
+        let (_dir, path) = gltf_file("gltf-materials", GLTF);
+        let scene = load(&path).unwrap();
+
+        assert_eq!(
+            scene.materials[..4],
+            [
+                Material::Diffuse {
+                    color: [1.0, 0.0, 0.0]
+                },
+                Material::Mirror,
+                Material::Emissive {
+                    color: [1.0, 0.5, 0.0],
+                    intensity: 1.0
+                },
+                Material::Diffuse { color: [0.5; 3] },
+            ]
+        );
+        assert_eq!(
+            scene.warnings,
+            [format!(
+                "{}: Material \"brushed\" has a metallic factor of 1 and a roughness factor of 0.4, \
+                 it's imported as diffuse",
+                path.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn node_transforms_are_flipped_into_our_left_handed_world() {
+        let (_dir, path) = gltf_file("gltf-flip-z", GLTF);
+        let scene = load(&path).unwrap();
+
+        let parent = Matrix4::new_translation(&Vector3::new(1.0, 2.0, 3.0))
+            * UnitQuaternion::from_quaternion(Quaternion::new(
+                0.9393727, 0.09164329, 0.18328659, 0.27492988,
+            ))
+            .to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 2.0, 3.0));
+        let child = Matrix4::new_translation(&Vector3::z());
+        let expected = flip_z() * parent * child * flip_z();
+
+        let world = scene.nodes[0].transform.matrix(0.0) * scene.nodes[1].transform.matrix(0.0);
+        assert!(
+            (world - expected).abs().max() < 1e-5,
+            "{world} != {expected}"
+        );
+
+        // Flipping the winding along with Z keeps the same side of the triangle in front
+        let mesh = &scene.meshes[0];
+        assert_eq!(
+            mesh.positions,
+            [0.0, 0.0, -0.0, 1.0, 0.0, -0.0, 0.0, 1.0, -0.0]
+        );
+        assert_eq!(mesh.indices, [0, 2, 1]);
+    }
+
+    #[test]
+    fn required_extensions_are_rejected() {
+        let required = GLTF.replacen(
+            r#""asset""#,
+            r#""extensionsUsed": ["KHR_draco_mesh_compression"],
+            "extensionsRequired": ["KHR_draco_mesh_compression"],
+            "asset""#,
+            1,
+        );
+        let (_dir, path) = gltf_file("gltf-extensions", &required);
+        assert_eq!(
+            load(&path).err().as_deref(),
+            Some("Requires the unsupported extension KHR_draco_mesh_compression")
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// A float RGBA image, laid out row by row from the top left like the ray tracing output texture
+#[derive(Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+}
+
+/// Converts a float to an 8-bit UNORM value the same way the GPU does when storing to
+/// DXGI_FORMAT_R8G8B8A8_UNORM
+fn to_unorm8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0.0; 4]; width as usize * height as usize],
+        }
+    }
+
+    // Only D3D12 readbacks fill images a pixel at a time
+    #[cfg_attr(not(d3d12), allow(dead_code))]
+    pub fn set(&mut self, x: u32, y: u32, color: [f32; 4]) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    /// Iterates over the pixels as they would be stored in an R8G8B8A8_UNORM texture
+    pub fn rgba8(&self) -> impl Iterator<Item = [u8; 4]> + '_ {
+        self.pixels.iter().map(|pixel| pixel.map(to_unorm8))
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Writes the image in the format matching the file extension. PNG and PPM are quantized to
+    /// 8 bits per channel while EXR and PFM keep the full float values.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("png") => self.write_png(path),
+            Some("exr") => self.write_exr(path),
+            Some("pfm") => self.write_pfm(path),
+            Some("ppm") => self.write_ppm(path),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported image format: {}", path.display()),
+            )),
+        }
+    }
+
+    pub fn write_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data: Vec<u8> = self.rgba8().flatten().collect();
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(&data).map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+
+    pub fn write_exr(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        exr::prelude::write_rgba_file(
+            path,
+            self.width as usize,
+            self.height as usize,
+            |x, y| {
+                let [r, g, b, a] = self.get(x as u32, y as u32);
+                (r, g, b, a)
+            },
+        )
+        .map_err(io::Error::other)
+    }
+
+    /// Portable float map, which stores rows from the bottom up
+    pub fn write_pfm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write!(file, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks_exact(self.width as usize).rev() {
+            for [r, g, b, _] in row {
+                file.write_all(&r.to_le_bytes())?;
+                file.write_all(&g.to_le_bytes())?;
+                file.write_all(&b.to_le_bytes())?;
+            }
+        }
+
+        file.flush()
+    }
+
+    pub fn write_ppm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for [r, g, b, _] in self.rgba8() {
+            file.write_all(&[r, g, b])?;
+        }
+
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    /// Red increasing along each row and green down the image, so every pixel is different
+    fn gradient(width: u32, height: u32) -> Image {
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set(x, y, [x as f32, y as f32, 0.5, 1.0]);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn unorm8_rounds_and_clamps() {
+        assert_eq!(to_unorm8(0.0), 0);
+        assert_eq!(to_unorm8(1.0), 255);
+        assert_eq!(to_unorm8(0.5), 128);
+        assert_eq!(to_unorm8(0.3), 77);
+        assert_eq!(to_unorm8(-0.5), 0);
+        assert_eq!(to_unorm8(7.0), 255);
+    }
+
+    #[test]
+    fn pfms_are_little_endian_from_the_bottom_row_up() {
+        let dir = TempDir::new("image-pfm");
+        let path = dir.join("image.pfm");
+        gradient(2, 3).write_pfm(&path).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let header = b"PF\n2 3\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+
+        let floats: Vec<f32> = bytes[header.len()..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            floats,
+            [
+                0.0, 2.0, 0.5, 1.0, 2.0, 0.5, //
+                0.0, 1.0, 0.5, 1.0, 1.0, 0.5, //
+                0.0, 0.0, 0.5, 1.0, 0.0, 0.5,
+            ]
+        );
+    }
+
+    #[test]
+    fn ppms_are_quantized_from_the_top_row_down() {
+        let dir = TempDir::new("image-ppm");
+        let path = dir.join("image.ppm");
+        let mut image = Image::new(2, 1);
+        image.set(0, 0, [-0.5, 0.3, 0.5, 1.0]);
+        image.set(1, 0, [1.0, 7.0, 0.0, 0.0]);
+        image.write_ppm(&path).unwrap();
+
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            [&b"P6\n2 1\n255\n"[..], &[0, 77, 128, 255, 255, 0]].concat()
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let dir = TempDir::new("image-formats");
+        for name in ["image.bmp", "image"] {
+            let error = Image::new(1, 1).save(dir.join(name)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(!dir.join(name).exists());
+        }
+
+        // Extensions are matched whatever their case
+        Image::new(1, 1).save(dir.join("image.PPM")).unwrap();
+    }
+}
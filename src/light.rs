@@ -0,0 +1,229 @@
+//! Lights and how much of each arrives at a point. The D3D12 scene uploads every light as a
+//! `PackedLight` into the lights buffer of shaders/shaders.hlsl, and `PackedLight::sample` works out
+//! what the shader's SampleLight does so the CPU tracer lights the scene the same way.
+
+use crate::ray::T_MAX;
+use nalgebra::{Point3, Vector3};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    /// Shines every way from `position`, falling off with the square of the distance
+    Point { position: Point3<f32> },
+    /// Infinitely far away like the sun, so it's the same everywhere. `direction` is the unit
+    /// vector the light travels along.
+    Directional { direction: Vector3<f32> },
+    /// A point light that only shines in a cone around the unit vector `direction`. It's at full
+    /// brightness out to `inner_angle` from the middle and fades out by `outer_angle`, both in
+    /// radians.
+    Spot {
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Vector3<f32>,
+    /// Scales `color`. Point and spot lights are this bright one unit away.
+    pub intensity: f32,
+}
+
+/// The light arriving at a point from one light, leaving out anything in the way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightSample {
+    /// Unit vector towards the light
+    pub direction: Vector3<f32>,
+    /// How far a shadow ray has to go to reach the light
+    pub distance: f32,
+    pub radiance: Vector3<f32>,
+}
+
+/// Values of `PackedLight::kind`, the same as the shader's LIGHT_ constants
+const POINT: u32 = 0;
+const DIRECTIONAL: u32 = 1;
+const SPOT: u32 = 2;
+
+/// A light the way the shader's Light struct lays it out in the lights buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PackedLight {
+    pub position: [f32; 3],
+    pub kind: u32,
+    pub direction: [f32; 3],
+    /// Cosine of the spot's outer angle
+    pub cos_outer: f32,
+    /// Color times intensity
+    pub color: [f32; 3],
+    /// Cosine of the spot's inner angle
+    pub cos_inner: f32,
+}
+
+/// Equivalent of HLSL's smoothstep
+fn smoothstep(min: f32, max: f32, x: f32) -> f32 {
+    let t = ((x - min) / (max - min)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl From<&Light> for PackedLight {
+    fn from(light: &Light) -> Self {
+        let mut packed = Self {
+            position: [0.0; 3],
+            kind: POINT,
+            direction: [0.0; 3],
+            cos_outer: 0.0,
+            color: (light.color * light.intensity).into(),
+            cos_inner: 0.0,
+        };
+
+        match light.kind {
+            LightKind::Point { position } => packed.position = position.into(),
+            LightKind::Directional { direction } => {
+                packed.kind = DIRECTIONAL;
+                packed.direction = direction.into();
+            }
+            LightKind::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+            } => {
+                packed.kind = SPOT;
+                packed.position = position.into();
+                packed.direction = direction.into();
+                packed.cos_outer = outer_angle.cos();
+                packed.cos_inner = inner_angle.cos();
+            }
+        }
+
+        packed
+    }
+}
+
+impl PackedLight {
+    /// Equivalent of SampleLight, the light arriving at `position`
+    pub fn sample(&self, position: &Point3<f32>) -> LightSample {
+        let color = Vector3::from(self.color);
+        if self.kind == DIRECTIONAL {
+            return LightSample {
+                direction: -Vector3::from(self.direction),
+                distance: T_MAX,
+                radiance: color,
+            };
+        }
+
+        let to_light = Point3::from(self.position) - position;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+        let mut radiance = color / (distance * distance);
+        if self.kind == SPOT {
+            let cos_angle = (-direction).dot(&Vector3::from(self.direction));
+            radiance *= smoothstep(self.cos_outer, self.cos_inner, cos_angle);
+        }
+
+        LightSample {
+            direction,
+            distance,
+            radiance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    fn white(kind: LightKind, intensity: f32) -> PackedLight {
+        PackedLight::from(&Light {
+            kind,
+            color: Vector3::repeat(1.0),
+            intensity,
+        })
+    }
+
+    fn spot(inner: f32, outer: f32) -> PackedLight {
+        white(
+            LightKind::Spot {
+                position: Point3::new(0.0, 10.0, 0.0),
+                direction: -Vector3::y(),
+                inner_angle: inner.to_radians(),
+                outer_angle: outer.to_radians(),
+            },
+            100.0,
+        )
+    }
+
+    #[test]
+    fn point_lights_fall_off_with_distance_squared() {
+        let light = white(
+            LightKind::Point {
+                position: Point3::new(0.0, 4.0, 0.0),
+            },
+            32.0,
+        );
+
+        let sample = light.sample(&Point3::origin());
+        assert_eq!(sample.direction, Vector3::y());
+        assert_eq!(sample.distance, 4.0);
+        assert_eq!(sample.radiance, Vector3::repeat(2.0));
+
+        let sample = light.sample(&Point3::new(0.0, 2.0, 0.0));
+        assert_eq!(sample.radiance, Vector3::repeat(8.0));
+    }
+
+    #[test]
+    fn directional_lights_are_the_same_everywhere() {
+        let light = PackedLight::from(&Light {
+            kind: LightKind::Directional {
+                direction: Vector3::new(0.0, -0.6, 0.8),
+            },
+            color: Vector3::new(1.0, 0.5, 0.25),
+            intensity: 2.0,
+        });
+
+        for position in [Point3::origin(), Point3::new(100.0, -3.0, 7.0)] {
+            let sample = light.sample(&position);
+            assert_eq!(sample.direction, Vector3::new(0.0, 0.6, -0.8));
+            assert_eq!(sample.distance, T_MAX);
+            assert_eq!(sample.radiance, Vector3::new(2.0, 1.0, 0.5));
+        }
+    }
+
+    #[test]
+    fn spots_fade_out_between_their_angles() {
+        let light = spot(20.0, 40.0);
+        // Straight down the middle, 10 away
+        assert_eq!(
+            light.sample(&Point3::origin()).radiance,
+            Vector3::repeat(1.0)
+        );
+
+        let at_angle = |degrees: f32| {
+            let offset = 10.0 * degrees.to_radians().tan();
+            light.sample(&Point3::new(offset, 0.0, 0.0)).radiance.x
+        };
+        let falloff = [10.0, 25.0, 30.0, 35.0, 45.0].map(at_angle);
+        assert!(falloff[0] > falloff[1]);
+        assert!(falloff[1] > falloff[2] && falloff[2] > falloff[3] && falloff[3] > 0.0);
+        assert_eq!(falloff[4], 0.0);
+
+        // Halfway between in cosine is halfway through the smoothstep
+        let cos_half = (20.0_f32.to_radians().cos() + 40.0_f32.to_radians().cos()) / 2.0;
+        let distance = 10.0 / cos_half;
+        let expected = 0.5 * 100.0 / (distance * distance);
+        let sample = light.sample(&Point3::new(10.0 * cos_half.acos().tan(), 0.0, 0.0));
+        assert!((sample.radiance.x - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn packs_like_the_shader_struct() {
+        assert_eq!(size_of::<PackedLight>(), 48);
+        let light = spot(20.0, 40.0);
+        assert_eq!(light.kind, SPOT);
+        assert_eq!(light.color, [100.0; 3]);
+        assert_eq!(light.cos_outer, 40.0_f32.to_radians().cos());
+    }
+}
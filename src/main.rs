@@ -1,57 +1,209 @@
-use raw_window_handle::HasWindowHandle;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
 use winit::{
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Window, WindowBuilder},
 };
 
-use crate::device_interface::DeviceInterface;
-use crate::pipeline::Pipeline;
-use crate::scene::Scene;
-use crate::surface::Surface;
-use crate::window_handle::WindowHandle;
+use crate::args::{Command, HeadlessArgs, StatsArgs, USAGE};
+use crate::backend::{BackendResult, RenderBackend};
+use crate::bvh::{Bvh, BvhOptions};
+use crate::cpu_backend::CpuBackend;
+use crate::fly_camera::FlyCamera;
+use crate::scene_model::{SceneError, SceneModel};
 
+mod affine;
+mod args;
+mod attributes;
+mod backend;
+mod bvh;
+mod camera;
+mod cpu;
+mod cpu_backend;
+#[cfg(d3d12)]
+mod d3d12_backend;
+#[cfg(d3d12)]
 mod device_interface;
+mod fly_camera;
+mod geometry;
+mod gltf_import;
+mod image;
+#[cfg(d3d12)]
 mod imports;
+mod light;
+mod material;
+mod obj;
+#[cfg(d3d12)]
 mod pipeline;
+mod ray;
+#[cfg(d3d12)]
 mod resource;
+#[cfg(d3d12)]
 mod scene;
+mod scene_cache;
+mod scene_graph;
+mod scene_model;
+#[cfg(d3d12)]
 mod surface;
+#[cfg(test)]
+mod test_util;
+mod tile_renderer;
+mod tlas;
+mod tlas_policy;
+mod wide_bvh;
+#[cfg(d3d12)]
 mod window_handle;
 
-fn render(
-    interface: &DeviceInterface,
-    scene: &mut Scene,
-    pipeline: &Pipeline,
-    surface: &Surface,
-) -> windows::core::Result<()> {
-    scene.update(&interface);
-
-    pipeline.bind(&interface);
-    scene.bind(&interface);
-    let surface_desc = surface.bind(&interface)?;
-    let rays_desc = pipeline.create_rays_description(&surface_desc);
-    unsafe { interface.command_list.DispatchRays(&rays_desc) };
-
-    surface.present(&interface)?;
-    interface.wait_for_gpu()
+fn render(backend: &mut dyn RenderBackend, time: f32) -> BackendResult<()> {
+    backend.update_transforms(time)?;
+    backend.trace_frame()?;
+    backend.present()
+}
+
+/// Uses D3D12 where it was compiled in and a ray tracing capable device is present, otherwise
+/// falls back to the CPU backend
+fn create_backend(window: Rc<Window>) -> BackendResult<Box<dyn RenderBackend>> {
+    #[cfg(d3d12)]
+    {
+        use crate::d3d12_backend::D3D12Backend;
+        use crate::window_handle::WindowHandle;
+        use raw_window_handle::HasWindowHandle;
+
+        let window_handle: WindowHandle = window.window_handle()?.as_raw().try_into()?;
+        match D3D12Backend::create(window_handle.into()) {
+            Ok(backend) => return Ok(Box::new(backend)),
+            Err(error) => eprintln!("D3D12 unavailable, falling back to the CPU backend: {error}"),
+        }
+    }
+
+    Ok(Box::new(CpuBackend::from_window(window)?))
+}
+
+fn load_scene(path: Option<&str>) -> Result<SceneModel, SceneError> {
+    let scene = match path {
+        Some(path) => scene_cache::load(Path::new(path))?,
+        None => SceneModel::default_scene(),
+    };
+
+    for warning in &scene.warnings {
+        eprintln!("Warning: {warning}");
+    }
+
+    Ok(scene)
+}
+
+fn create_offscreen_backend(args: &HeadlessArgs) -> BackendResult<Box<dyn RenderBackend>> {
+    #[cfg(d3d12)]
+    if !args.cpu {
+        use crate::d3d12_backend::D3D12Backend;
+
+        match D3D12Backend::offscreen(args.width, args.height) {
+            Ok(backend) => return Ok(Box::new(backend)),
+            Err(error) => eprintln!("D3D12 unavailable, falling back to the CPU backend: {error}"),
+        }
+    }
+
+    let mut backend = CpuBackend::headless(args.width, args.height, args.threads)?;
+    if io::stderr().is_terminal() {
+        backend.on_progress(|progress| {
+            eprint!("\r{progress}");
+            if progress.tiles_done == progress.num_tiles {
+                eprint!("\r\x1b[K");
+            }
+        });
+    }
+
+    Ok(Box::new(backend))
+}
+
+fn run_headless(args: &HeadlessArgs) -> BackendResult<()> {
+    let scene = load_scene(args.scene.as_deref())?;
+    let mut backend = create_offscreen_backend(args)?;
+    backend.build_scene(&scene)?;
+
+    for frame in 0..args.frames {
+        backend.update_transforms(frame as f32 / args.fps)?;
+        backend.trace_frame()?;
+
+        let path = args.output_path(frame);
+        backend.readback()?.save(&path)?;
+        println!("Wrote {path}");
+    }
+
+    Ok(())
+}
+
+fn run_stats(args: &StatsArgs) -> BackendResult<()> {
+    let scene = load_scene(Some(&args.scene))?;
+    let needs_numbering = args
+        .wireframe
+        .as_ref()
+        .is_some_and(|w| !w.contains("{mesh}"));
+    if needs_numbering && scene.meshes.len() > 1 {
+        return Err("--wireframe must contain {mesh} when the scene has more than one mesh".into());
+    }
+
+    for (i, mesh) in scene.meshes.iter().enumerate() {
+        let options = BvhOptions {
+            spatial_splits: args.options.spatial_splits.or(mesh.spatial_splits),
+            ..args.options
+        };
+        let bvh = Bvh::build(&mesh.positions, &mesh.indices, &options);
+        let triangles = mesh.indices.len() / 3;
+        println!("Mesh {i} {:?}, {triangles} triangles", mesh.name);
+        println!("{}", bvh.hierarchy().stats());
+
+        // The baseline spatial splits have to beat to be worth their extra references
+        if options.spatial_splits.is_some() {
+            let object_splits = BvhOptions {
+                spatial_splits: None,
+                ..options
+            };
+            let baseline = Bvh::build(&mesh.positions, &mesh.indices, &object_splits);
+            let cost = baseline.hierarchy().stats().sah_cost;
+            println!("Without spatial splits the SAH cost would be {cost:.3}");
+        }
+        println!();
+
+        if let Some(path) = args.wireframe_path(i) {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            bvh.hierarchy().write_wireframe(&mut file)?;
+            println!("Wrote {path}\n");
+        }
+    }
+
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn run_bake(scene: &str) -> BackendResult<()> {
+    scene_cache::bake(Path::new(scene))?;
+    println!(
+        "Wrote {}",
+        scene_cache::cache_path(Path::new(scene)).display()
+    );
+    Ok(())
+}
+
+fn run_window(scene: Option<&str>) -> BackendResult<()> {
+    let scene = load_scene(scene)?;
+
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new()
-        .with_title("DirectX 11")
+        .with_title("Tracer")
         .build(&event_loop)
         .unwrap();
 
-    let window_handle: WindowHandle = window.window_handle()?.as_raw().try_into()?;
-    let window_handle = window_handle.into();
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let interface = DeviceInterface::create()?;
-    let mut surface = Surface::from_handle(&interface, window_handle)?;
-    let mut scene = Scene::build(&interface)?;
-    let pipeline = Pipeline::create(&interface)?;
+    let mut backend = create_backend(Rc::new(window))?;
+    backend.build_scene(&scene)?;
+    let mut fly_camera = FlyCamera::new(scene.camera);
+    let start = Instant::now();
+    let mut last_frame = start;
 
     event_loop
         .run(move |event, elwt| match event {
@@ -59,30 +211,51 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 event: WindowEvent::CloseRequested,
                 ..
             } => {
-                interface.wait_for_gpu().unwrap();
+                backend.wait_idle().unwrap();
                 elwt.exit();
             }
             Event::AboutToWait => {
-                unsafe {
-                    interface.command_allocator.Reset().unwrap();
-                    interface
-                        .command_list
-                        .Reset(&interface.command_allocator, None)
-                        .unwrap();
+                let now = Instant::now();
+                if fly_camera.update((now - last_frame).as_secs_f32()) {
+                    backend.set_camera(fly_camera.camera()).unwrap();
                 }
+                last_frame = now;
 
-                scene.update(&interface);
-                render(&interface, &mut scene, &pipeline, &surface).unwrap();
+                render(backend.as_mut(), (now - start).as_secs_f32()).unwrap();
             }
             Event::WindowEvent {
-                event: WindowEvent::Resized(_),
+                event: WindowEvent::Resized(size),
                 ..
             } => {
-                surface.resize(&interface).unwrap();
+                backend.resize(size.width, size.height).unwrap();
             }
+            Event::WindowEvent { event, .. } => fly_camera.window_event(&event),
+            Event::DeviceEvent { event, .. } => fly_camera.device_event(&event),
             _ => (),
         })
         .unwrap();
 
     Ok(())
 }
+
+fn main() {
+    let command = match args::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let result = match command {
+        Command::Window { scene } => run_window(scene.as_deref()),
+        Command::Headless(args) => run_headless(&args),
+        Command::Stats(args) => run_stats(&args),
+        Command::Bake { scene } => run_bake(&scene),
+    };
+
+    if let Err(error) = result {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,182 @@
+//! Materials the way the shaders see them. Every instance's InstanceID is the index of its material
+//! in the scene's materials, which the D3D12 scene uploads as `PackedMaterial`s into the materials
+//! buffer of shaders/shaders.hlsl for ClosestHit to look up. The functions here work out what the
+//! shader's functions of the same names do, so the CPU tracer shades every material the same way.
+
+use crate::scene_model::Material;
+use nalgebra::{Point3, Vector3};
+
+/// Values of `PackedMaterial::kind`, the same as the shader's MATERIAL_ constants
+pub const FACES: u32 = 0;
+pub const MIRROR: u32 = 1;
+pub const CHECKER: u32 = 2;
+pub const DIFFUSE: u32 = 3;
+pub const GLASS: u32 = 4;
+pub const EMISSIVE: u32 = 5;
+
+/// A material the way the shader's Material struct lays it out in the materials buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PackedMaterial {
+    /// Diffuse color, the tint of glass or emitted light times its intensity, and black for the
+    /// materials that don't take a color
+    pub color: [f32; 3],
+    pub kind: u32,
+    /// Glass's index of refraction
+    pub ior: f32,
+}
+
+impl From<&Material> for PackedMaterial {
+    fn from(material: &Material) -> Self {
+        let (kind, color, ior) = match *material {
+            Material::Faces => (FACES, [0.0; 3], 1.0),
+            Material::Mirror => (MIRROR, [0.0; 3], 1.0),
+            Material::Checker => (CHECKER, [0.0; 3], 1.0),
+            Material::Diffuse { color } => (DIFFUSE, color, 1.0),
+            Material::Glass { color, ior } => (GLASS, color, ior),
+            Material::Emissive { color, intensity } => {
+                (EMISSIVE, (Vector3::from(color) * intensity).into(), 1.0)
+            }
+        };
+
+        Self { color, kind, ior }
+    }
+}
+
+/// Equivalent of Diffuse, what a surface of `color` looks like with `light` arriving at it. It's
+/// fully lit by a white light of intensity 1 shining straight at it and half lit in shadow.
+pub fn diffuse(color: Vector3<f32>, light: Vector3<f32>) -> Vector3<f32> {
+    color.component_mul(&(light + Vector3::repeat(1.0))) / 2.0
+}
+
+/// Equivalent of Checker, the color of the world space checkerboard in one unit squares at `pos`
+pub fn checker(pos: &Point3<f32>) -> Vector3<f32> {
+    let pattern_x = pos.x - pos.x.floor() > 0.5;
+    let pattern_z = pos.z - pos.z.floor() > 0.5;
+    Vector3::repeat(if pattern_x ^ pattern_z { 0.6 } else { 0.4 })
+}
+
+/// Equivalent of HLSL's reflect
+pub fn reflect(incident: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    incident - 2.0 * incident.dot(&normal) * normal
+}
+
+/// Equivalent of HLSL's refract, which bends the unit vector `incident` through a surface with the
+/// unit `normal` facing it. `eta` is the index of refraction on the incident side over the one on
+/// the far side. None where HLSL returns zero, when the light is totally internally reflected.
+pub fn refract(incident: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cosine = normal.dot(&incident);
+    let k = 1.0 - eta * eta * (1.0 - cosine * cosine);
+    if k < 0.0 {
+        return None;
+    }
+
+    Some(eta * incident - (eta * cosine + k.sqrt()) * normal)
+}
+
+/// Equivalent of Fresnel, Schlick's approximation of how much light is reflected rather than
+/// refracted when it arrives at `cosine` to the normal of a surface with `eta` as in `refract`.
+/// Leaving the denser side goes by the angle on the far side, so it's 1 past the critical angle.
+pub fn fresnel(cosine: f32, eta: f32) -> f32 {
+    let r0 = ((1.0 - eta) / (1.0 + eta)).powi(2);
+    let cosine = if eta > 1.0 {
+        let k = 1.0 - eta * eta * (1.0 - cosine * cosine);
+        if k < 0.0 {
+            return 1.0;
+        }
+        k.sqrt()
+    } else {
+        cosine
+    };
+
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::assert_near;
+    use std::mem::size_of;
+
+    #[test]
+    fn packs_like_the_shader_struct() {
+        assert_eq!(size_of::<PackedMaterial>(), 20);
+
+        let glass = PackedMaterial::from(&Material::Glass {
+            color: [0.9, 1.0, 0.9],
+            ior: 1.5,
+        });
+        assert_eq!(
+            (glass.kind, glass.color, glass.ior),
+            (GLASS, [0.9, 1.0, 0.9], 1.5)
+        );
+
+        let emissive = PackedMaterial::from(&Material::Emissive {
+            color: [1.0, 0.5, 0.0],
+            intensity: 4.0,
+        });
+        assert_eq!((emissive.kind, emissive.color), (EMISSIVE, [4.0, 2.0, 0.0]));
+    }
+
+    #[test]
+    fn checks_alternate_every_half_unit() {
+        let a = checker(&Point3::new(0.25, 7.0, 0.25));
+        let b = checker(&Point3::new(0.75, 7.0, 0.25));
+        assert_eq!(a, Vector3::repeat(0.4));
+        assert_eq!(b, Vector3::repeat(0.6));
+        assert_eq!(checker(&Point3::new(0.75, 0.0, 0.75)), a);
+        assert_eq!(checker(&Point3::new(-0.25, 0.0, 0.25)), b);
+    }
+
+    #[test]
+    fn refraction_follows_snells_law() {
+        let normal = Vector3::y();
+        let angle = 30.0_f32.to_radians();
+        let incident = Vector3::new(angle.sin(), -angle.cos(), 0.0);
+
+        let refracted = refract(incident, normal, 1.0 / 1.5).unwrap();
+        assert!((refracted.norm() - 1.0).abs() < 1e-5);
+        assert!((refracted.x - angle.sin() / 1.5).abs() < 1e-5);
+        assert!(refracted.y < 0.0);
+
+        // Straight through head on, and back out the way it came in
+        assert_near(refract(-normal, normal, 1.0 / 1.5).unwrap(), -normal);
+        assert_near(refract(refracted, normal, 1.5).unwrap(), incident);
+
+        // Past the critical angle on the way out
+        let steep = 60.0_f32.to_radians();
+        let leaving = Vector3::new(steep.sin(), -steep.cos(), 0.0);
+        assert_eq!(refract(leaving, normal, 1.5), None);
+        assert_eq!(fresnel(steep.cos(), 1.5), 1.0);
+    }
+
+    #[test]
+    fn more_is_reflected_at_grazing_angles() {
+        let head_on = fresnel(1.0, 1.0 / 1.5);
+        assert!((head_on - 0.04).abs() < 1e-6);
+        // The same either way through at normal incidence
+        assert!((fresnel(1.0, 1.5) - head_on).abs() < 1e-6);
+
+        let reflectance = [0.9, 0.5, 0.1, 0.0].map(|cosine| fresnel(cosine, 1.0 / 1.5));
+        assert!(reflectance.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(reflectance[3], 1.0);
+    }
+
+    #[test]
+    fn mirrors_reflect_about_the_normal() {
+        let reflected = reflect(Vector3::new(1.0, -1.0, 0.5), Vector3::y());
+        assert_eq!(reflected, Vector3::new(1.0, 1.0, 0.5));
+    }
+
+    #[test]
+    fn diffuse_surfaces_are_half_lit_in_shadow() {
+        assert_eq!(
+            diffuse(Vector3::repeat(0.5), Vector3::zeros()),
+            Vector3::repeat(0.25)
+        );
+        assert_eq!(
+            diffuse(Vector3::repeat(0.5), Vector3::repeat(1.0)),
+            Vector3::repeat(0.5)
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Wavefront OBJ import. Polygons are fan triangulated and every distinct position, normal and UV
+//! combination becomes one vertex, so each object in the file comes out as a single indexed mesh.
+
+use crate::scene_model::{Material, Mesh};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// What MTL readers conventionally use when a material has no Kd
+const DEFAULT_DIFFUSE: [f32; 3] = [0.8; 3];
+
+pub struct ObjPart {
+    /// Named after the object or group it came from
+    pub mesh: Mesh,
+    /// Index into [`ObjFile::materials`]
+    pub material: Option<usize>,
+}
+
+pub struct ObjFile {
+    pub parts: Vec<ObjPart>,
+    /// The materials in the MTL files the OBJ file references, in declaration order
+    pub materials: Vec<Material>,
+    /// The OBJ file and the MTL files it references
+    pub sources: Vec<PathBuf>,
+}
+
+/// Maps an MTL material onto the closest of ours. The Mirror material is perfect and colorless, so
+/// reflective illumination models get their diffuse color like everything else.
+fn material(mtl: &tobj::Material) -> Material {
+    Material::Diffuse {
+        color: mtl.diffuse.unwrap_or(DEFAULT_DIFFUSE),
+    }
+}
+
+/// OBJ is right handed with V pointing up the texture. Flipping Z and the winding order keeps meshes
+/// from being mirrored in our left handed world, and V is flipped to match D3D texture coordinates.
+fn part(model: tobj::Model) -> ObjPart {
+    let mesh = model.mesh;
+
+    let mut positions = mesh.positions;
+    let mut normals = mesh.normals;
+    for v in positions
+        .chunks_exact_mut(3)
+        .chain(normals.chunks_exact_mut(3))
+    {
+        v[2] = -v[2];
+    }
+
+    let mut uvs = mesh.texcoords;
+    for uv in uvs.chunks_exact_mut(2) {
+        uv[1] = 1.0 - uv[1];
+    }
+
+    let mut indices = mesh.indices;
+    for triangle in indices.chunks_exact_mut(3) {
+        triangle.swap(1, 2);
+    }
+
+    ObjPart {
+        mesh: Mesh {
+            name: model.name,
+            positions,
+            normals,
+            uvs,
+            indices,
+            bvh: None,
+            spatial_splits: None,
+        },
+        material: mesh.material_id,
+    }
+}
+
+/// Loads every object in `path` along with the materials from its MTL files. Objects with no faces,
+/// such as ones made only of lines, are left out.
+pub fn load(path: &Path) -> Result<ObjFile, String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    // Like tobj::load_obj, but keeping track of the MTL files
+    let sources = RefCell::new(vec![path.to_path_buf()]);
+    let (models, materials) =
+        tobj::load_obj_buf(&mut BufReader::new(file), &tobj::GPU_LOAD_OPTIONS, |mtl| {
+            let mtl = dir.join(mtl);
+            sources.borrow_mut().push(mtl.clone());
+            tobj::load_mtl(mtl)
+        })
+        .map_err(|error| error.to_string())?;
+    let materials = materials.map_err(|error| format!("Couldn't load its materials: {error}"))?;
+
+    Ok(ObjFile {
+        parts: models
+            .into_iter()
+            .filter(|model| !model.mesh.indices.is_empty())
+            .map(part)
+            .collect(),
+        materials: materials.iter().map(material).collect(),
+        sources: sources.into_inner(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::smooth_normals;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    /// One triangle facing along its vertex normal, and an object made of a line
+    const OBJ: &str = "\
+mtllib parts.mtl
+o triangle
+v 0 0 0
+v 1 0 0
+v 0 1 1
+vt 0 0
+vt 1 0
+vt 0 0.25
+vn 0 -0.6 0.8
+usemtl red
+f 1/1/1 2/2/1 3/3/1
+o line
+v 0 0 0
+v 1 1 1
+l 4 5
+";
+
+    const MTL: &str = "\
+newmtl red
+Kd 1 0 0
+illum 3
+newmtl plain
+";
+
+    #[test]
+    fn objects_are_flipped_into_our_left_handed_world() {
+        let dir = TempDir::new("obj");
+        fs::write(dir.join("parts.obj"), OBJ).unwrap();
+        fs::write(dir.join("parts.mtl"), MTL).unwrap();
+
+        let obj = load(&dir.join("parts.obj")).unwrap();
+        assert_eq!(obj.sources, [dir.join("parts.obj"), dir.join("parts.mtl")]);
+
+        // The line has no faces
+        assert_eq!(obj.parts.len(), 1);
+        let part = &obj.parts[0];
+        assert_eq!(part.mesh.name, "triangle");
+        assert_eq!(part.material, Some(0));
+
+        let mesh = &part.mesh;
+        assert_eq!(
+            mesh.positions,
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, -1.0]
+        );
+        assert_eq!(mesh.normals, [0.0, -0.6, -0.8].repeat(3));
+        assert_eq!(mesh.uvs, [0.0, 1.0, 1.0, 1.0, 0.0, 0.75]);
+        assert_eq!(mesh.indices, [0, 2, 1]);
+
+        // Flipping the winding along with Z keeps the front of the triangle facing its normal
+        let facing = smooth_normals(&mesh.positions, &mesh.indices);
+        for (facing, normal) in facing.chunks_exact(3).zip(mesh.normals.chunks_exact(3)) {
+            let dot: f32 = facing.iter().zip(normal).map(|(a, b)| a * b).sum();
+            assert!(dot > 0.9, "{facing:?} faces away from {normal:?}");
+        }
+
+        assert_eq!(
+            obj.materials,
+            [
+                Material::Diffuse {
+                    color: [1.0, 0.0, 0.0]
+                },
+                Material::Diffuse {
+                    color: DEFAULT_DIFFUSE
+                },
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::camera::CameraConstants;
 use crate::device_interface::DeviceInterface;
 use crate::imports::*;
 use crate::resource::{OpaqueResource, UploadResource};
@@ -7,6 +8,20 @@ const SHADER_BYTES: &[u8] = include_bytes!("shaders/shaders.bin");
 
 const NUM_SHADER_IDS: u32 = 3;
 
+/// A buffer bound straight to the root signature as register t`register`
+fn root_srv(register: u32) -> D3D12_ROOT_PARAMETER {
+    D3D12_ROOT_PARAMETER {
+        ParameterType: D3D12_ROOT_PARAMETER_TYPE_SRV,
+        Anonymous: D3D12_ROOT_PARAMETER_0 {
+            Descriptor: D3D12_ROOT_DESCRIPTOR {
+                ShaderRegister: register,
+                RegisterSpace: 0,
+            },
+        },
+        ..Default::default()
+    }
+}
+
 fn create_root_signature(interface: &DeviceInterface) -> Result<ID3D12RootSignature> {
     let uav_range = D3D12_DESCRIPTOR_RANGE {
         RangeType: D3D12_DESCRIPTOR_RANGE_TYPE_UAV,
@@ -25,16 +40,26 @@ fn create_root_signature(interface: &DeviceInterface) -> Result<ID3D12RootSignat
             },
             ..Default::default()
         },
+        root_srv(0),
+        root_srv(1),
+        // The camera is small enough to live in the root signature itself
         D3D12_ROOT_PARAMETER {
-            ParameterType: D3D12_ROOT_PARAMETER_TYPE_SRV,
+            ParameterType: D3D12_ROOT_PARAMETER_TYPE_32BIT_CONSTANTS,
             Anonymous: D3D12_ROOT_PARAMETER_0 {
-                Descriptor: D3D12_ROOT_DESCRIPTOR {
+                Constants: D3D12_ROOT_CONSTANTS {
                     ShaderRegister: 0,
                     RegisterSpace: 0,
+                    Num32BitValues: CameraConstants::NUM_32BIT_VALUES,
                 },
             },
             ..Default::default()
         },
+        root_srv(2),
+        // The attribute buffers and where each instance's mesh starts in them
+        root_srv(3),
+        root_srv(4),
+        root_srv(5),
+        root_srv(6),
     ];
 
     let desc = D3D12_ROOT_SIGNATURE_DESC {
@@ -0,0 +1,394 @@
+//! Rays and ray/triangle intersection for tracing on the CPU, following the rules DXR applies to
+//! RayDesc and triangle geometry
+
+use nalgebra::{Point3, Vector2, Vector3};
+
+/// The TMin every RayDesc in the shader uses, to keep secondary rays off the surface they start on
+pub const T_MIN: f32 = 0.001;
+
+/// The TMax every RayDesc in the shader uses
+pub const T_MAX: f32 = 1000.0;
+
+/// Equivalent of RayDesc. Hits are only reported between `t_min` and `t_max` inclusive.
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub t_min: f32,
+    pub t_max: f32,
+}
+
+impl Ray {
+    /// The reciprocal of the direction for slab tests. Infinities would make NaNs out of rays
+    /// starting on a slab they're parallel to, the biggest finite value keeps the box tests free of
+    /// them. Like with `Aabb::intersect`, rays that lie exactly in the plane of a side of a box may
+    /// or may not get in.
+    pub fn inv_direction(&self) -> Vector3<f32> {
+        self.direction.map(|d| {
+            let inv = 1.0 / d;
+            if inv.is_finite() {
+                inv
+            } else {
+                f32::MAX.copysign(inv)
+            }
+        })
+    }
+}
+
+/// Rays in a coherent packet are all within about 25 degrees of the first one's direction
+const COHERENT_COS: f32 = 0.9;
+
+/// Whether `rays` all head much the same way, so they take much the same path through a BVH and
+/// are worth tracing as a packet. Rays spreading out in every direction, like reflections off a
+/// curved mirror, would drag each other through most of the tree.
+pub fn is_coherent(rays: &[Ray]) -> bool {
+    let Some(first) = rays.first() else {
+        return true;
+    };
+
+    let first = first.direction.normalize();
+    rays.iter()
+        .all(|ray| ray.direction.normalize().dot(&first) >= COHERENT_COS)
+}
+
+/// Equivalent of HitKind() for triangles. Like D3D12 without
+/// D3D12_RAYTRACING_INSTANCE_FLAG_TRIANGLE_FRONT_COUNTERCLOCKWISE, a triangle is front facing when
+/// its vertices go clockwise as seen from the ray origin, in object space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HitKind {
+    FrontFace,
+    BackFace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Intersection {
+    pub t: f32,
+    /// The weights of the second and third vertices, like BuiltInTriangleIntersectionAttributes
+    pub barycentrics: Vector2<f32>,
+    pub hit_kind: HitKind,
+}
+
+/// Watertight ray/triangle intersection from Woop, Benthin and Wald, "Watertight Ray/Triangle
+/// Intersection", JCGT 2013. Triangles are sheared into a space where the ray runs along +Z, so a
+/// point on an edge shared by two triangles gets exactly the same edge function in both, and hits
+/// on the edge itself count for both. Degenerate triangles and rays in a triangle's plane never hit.
+pub fn intersect_triangle(ray: &Ray, [v0, v1, v2]: [Point3<f32>; 3]) -> Option<Intersection> {
+    let d = ray.direction;
+
+    // Z is the axis the ray moves along fastest, X and Y are swapped for rays going the negative
+    // way along it to keep the winding order
+    let kz = d.iamax();
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    if d[kz] == 0.0 {
+        return None;
+    }
+
+    let shear_x = d[kx] / d[kz];
+    let shear_y = d[ky] / d[kz];
+    let shear_z = 1.0 / d[kz];
+
+    let a = v0 - ray.origin;
+    let b = v1 - ray.origin;
+    let c = v2 - ray.origin;
+
+    let ax = a[kx] - shear_x * a[kz];
+    let ay = a[ky] - shear_y * a[kz];
+    let bx = b[kx] - shear_x * b[kz];
+    let by = b[ky] - shear_y * b[kz];
+    let cx = c[kx] - shear_x * c[kz];
+    let cy = c[ky] - shear_y * c[kz];
+
+    // Scaled barycentrics of each vertex, from the edge opposite it
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+
+    // Exactly on an edge the rounding decides which side the ray is on, so redo those in double
+    // precision where the products are exact
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        let edge = |px: f32, py: f32, qx: f32, qy: f32| {
+            (px as f64 * qy as f64 - py as f64 * qx as f64) as f32
+        };
+        u = edge(cx, cy, bx, by);
+        v = edge(ax, ay, cx, cy);
+        w = edge(bx, by, ax, ay);
+    }
+
+    // Hits from either side are reported, so only mixed signs are a miss
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = shear_z * a[kz];
+    let bz = shear_z * b[kz];
+    let cz = shear_z * c[kz];
+    let t = (u * az + v * bz + w * cz) / det;
+    if !(ray.t_min..=ray.t_max).contains(&t) {
+        return None;
+    }
+
+    Some(Intersection {
+        t,
+        barycentrics: Vector2::new(v / det, w / det),
+        hit_kind: if det > 0.0 {
+            HitKind::FrontFace
+        } else {
+            HitKind::BackFace
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{CUBE_IDX, CUBE_VTX};
+    use proptest::prelude::*;
+
+    fn ray_towards(origin: Point3<f32>, target: Point3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: target - origin,
+            t_min: T_MIN,
+            t_max: T_MAX,
+        }
+    }
+
+    fn point(extent: f32) -> impl Strategy<Value = Point3<f32>> {
+        [-extent..extent, -extent..extent, -extent..extent].prop_map(Point3::from)
+    }
+
+    /// Barycentrics well inside a triangle
+    fn inside() -> impl Strategy<Value = Vector2<f32>> {
+        [0.0f32..1.0, 0.0f32..1.0].prop_map(|[x, y]| {
+            let (x, y) = if x + y > 1.0 {
+                (1.0 - x, 1.0 - y)
+            } else {
+                (x, y)
+            };
+            Vector2::new(0.05 + 0.7 * x, 0.05 + 0.7 * y)
+        })
+    }
+
+    fn cube_vertex(i: u16) -> Point3<f32> {
+        let i = i as usize * 3;
+        Point3::new(CUBE_VTX[i], CUBE_VTX[i + 1], CUBE_VTX[i + 2])
+    }
+
+    fn cube_triangles() -> impl Iterator<Item = [Point3<f32>; 3]> {
+        CUBE_IDX
+            .chunks_exact(3)
+            .map(|t| [cube_vertex(t[0]), cube_vertex(t[1]), cube_vertex(t[2])])
+    }
+
+    /// The point with barycentrics `b` on `triangle`
+    fn on_triangle([v0, v1, v2]: [Point3<f32>; 3], b: Vector2<f32>) -> Point3<f32> {
+        v0 + (v1 - v0) * b.x + (v2 - v0) * b.y
+    }
+
+    #[test]
+    fn clockwise_from_the_origin_is_front_facing() {
+        let clockwise = [
+            Point3::new(0.0, 1.0, 5.0),
+            Point3::new(1.0, -1.0, 5.0),
+            Point3::new(-1.0, -1.0, 5.0),
+        ];
+        let [v0, v1, v2] = clockwise;
+        let ray = ray_towards(Point3::origin(), Point3::new(0.0, 0.0, 1.0));
+
+        let front = intersect_triangle(&ray, clockwise).unwrap();
+        assert_eq!(front.hit_kind, HitKind::FrontFace);
+        assert_eq!(front.t, 5.0);
+
+        let back = intersect_triangle(&ray, [v0, v2, v1]).unwrap();
+        assert_eq!(back.hit_kind, HitKind::BackFace);
+
+        // From the other side the same triangle is seen counterclockwise
+        let behind = ray_towards(Point3::new(0.0, 0.0, 10.0), Point3::new(0.0, 0.0, 5.0));
+        let hit = intersect_triangle(&behind, clockwise).unwrap();
+        assert_eq!(hit.hit_kind, HitKind::BackFace);
+    }
+
+    #[test]
+    fn rays_in_the_plane_of_a_triangle_miss() {
+        let triangle = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+
+        for target in [[0.25, 0.25, 0.0], [1.0, 0.0, 0.0], [0.5, 0.5, 0.0]] {
+            let ray = ray_towards(Point3::new(-1.0, -1.0, 0.0), Point3::from(target));
+            assert_eq!(intersect_triangle(&ray, triangle), None);
+        }
+    }
+
+    proptest! {
+        /// Rays from inside the cube have to hit it wherever they go, including straight through
+        /// the diagonals CUBE_IDX splits each face along, its edges and its corners
+        #[test]
+        fn cube_has_no_cracks(
+            origin in point(0.99),
+            triangle in 0..CUBE_IDX.len() / 3,
+            edge in 0..3usize,
+            along in prop_oneof![Just(0.0f32), Just(0.5), Just(1.0), 0.0f32..=1.0],
+        ) {
+            let corners = &CUBE_IDX[triangle * 3..triangle * 3 + 3];
+            let start = cube_vertex(corners[edge]);
+            let end = cube_vertex(corners[(edge + 1) % 3]);
+            let target = start + (end - start) * along;
+
+            let ray = ray_towards(origin, target);
+            prop_assert!(cube_triangles().any(|t| intersect_triangle(&ray, t).is_some()));
+        }
+
+        /// Two triangles sharing the edge `p`-`q`, on either side of it and folded by `fold`
+        #[test]
+        fn shared_edges_have_no_cracks(
+            p in point(10.0),
+            q in point(10.0),
+            r in point(10.0),
+            fold in point(5.0),
+            origin in point(20.0),
+            along in 0.0f32..=1.0,
+        ) {
+            let s = Point3::from(p.coords + q.coords - r.coords) + fold.coords;
+            let target = p + (q - p) * along;
+            prop_assume!((target - origin).norm() > 0.1);
+
+            // Rays that almost lie in the plane of either triangle can miss both, as can rays that
+            // only touch the fold rather than crossing from one side of the surface to the other
+            let ray = ray_towards(origin, target);
+            let mut sides = Vec::new();
+            for [a, b, c] in [[p, q, r], [q, p, s]] {
+                let normal = (b - a).cross(&(c - a)).normalize();
+                prop_assume!(normal.dot(&ray.direction.normalize()).abs() > 1e-3);
+                sides.push(normal.dot(&(origin - a)) > 0.0);
+            }
+            if sides[0] != sides[1] {
+                return Ok(());
+            }
+
+            let hits = [[p, q, r], [q, p, s]].map(|t| intersect_triangle(&ray, t).is_some());
+            prop_assert!(hits[0] || hits[1]);
+        }
+
+        /// Whatever happens to rays that graze a triangle, anything they hit is on it
+        #[test]
+        fn grazing_rays_only_hit_inside(
+            triangle in [point(10.0), point(10.0), point(10.0)],
+            start in [0.0f32..1.0, 0.0f32..1.0],
+            end in [-0.5f32..1.5, -0.5f32..1.5],
+            lift in -1e-3f32..1e-3,
+        ) {
+            let [v0, v1, v2] = triangle;
+            let normal = (v1 - v0).cross(&(v2 - v0));
+            prop_assume!(normal.norm() > 1e-3);
+
+            let origin = on_triangle(triangle, Vector2::from(start)) - normal * 10.0 * lift;
+            let target = on_triangle(triangle, Vector2::from(end)) + normal * lift;
+            prop_assume!((target - origin).norm() > 0.1);
+
+            if let Some(hit) = intersect_triangle(&ray_towards(origin, target), triangle) {
+                let b = hit.barycentrics;
+                prop_assert!(hit.t.is_finite());
+                prop_assert!(b.x >= 0.0 && b.y >= 0.0 && b.x + b.y <= 1.0 + 1e-6);
+            }
+        }
+
+        #[test]
+        fn degenerate_triangles_never_hit(
+            p in point(10.0),
+            q in point(10.0),
+            origin in point(20.0),
+            target in point(20.0),
+        ) {
+            let ray = ray_towards(origin, target);
+            for triangle in [[p, p, q], [p, q, q], [q, p, q], [p, p, p]] {
+                prop_assert_eq!(intersect_triangle(&ray, triangle), None);
+            }
+
+            // Points along an axis stay exactly in line
+            let line = [p, Point3::new(p.x + 1.0, p.y, p.z), Point3::new(p.x + 3.0, p.y, p.z)];
+            prop_assert_eq!(intersect_triangle(&ray, line), None);
+        }
+
+        #[test]
+        fn huge_coordinates_still_hit(
+            triangle in [point(1.0), point(1.0), point(1.0)],
+            b in inside(),
+            origin in point(1.0),
+            scale in prop_oneof![Just(1e4f32), Just(1e8), Just(1e12), Just(1e15)],
+        ) {
+            let [v0, v1, v2] = triangle;
+            let normal = (v1 - v0).cross(&(v2 - v0));
+            let target = on_triangle(triangle, b);
+            let direction = target - origin;
+            // Slivers and grazing rays lose too much precision for the tolerances below
+            if normal.norm() < 0.05 || normal.normalize().dot(&direction.normalize()).abs() < 0.05 {
+                return Ok(());
+            }
+
+            let ray = Ray {
+                origin: origin * scale,
+                direction: direction * scale,
+                t_min: 0.0,
+                t_max: f32::MAX,
+            };
+            let hit = intersect_triangle(&ray, triangle.map(|v| v * scale));
+            prop_assert!(hit.is_some());
+
+            let hit = hit.unwrap();
+            prop_assert!((hit.t - 1.0).abs() < 1e-3);
+            prop_assert!((hit.barycentrics - b).norm() < 1e-3);
+        }
+
+        /// Hits exactly at TMin or TMax count, the next float outside them doesn't
+        #[test]
+        fn t_range_is_inclusive(
+            triangle in [point(10.0), point(10.0), point(10.0)],
+            b in inside(),
+            origin in point(20.0),
+        ) {
+            let target = on_triangle(triangle, b);
+            let ray = ray_towards(origin, target);
+            let Some(hit) = intersect_triangle(&ray, triangle) else {
+                return Ok(());
+            };
+
+            let with_range = |t_min, t_max| Ray { t_min, t_max, ..ray };
+            prop_assert!(intersect_triangle(&with_range(hit.t, T_MAX), triangle).is_some());
+            prop_assert!(intersect_triangle(&with_range(T_MIN, hit.t), triangle).is_some());
+            prop_assert!(intersect_triangle(&with_range(hit.t.next_up(), T_MAX), triangle).is_none());
+            prop_assert!(intersect_triangle(&with_range(T_MIN, hit.t.next_down()), triangle).is_none());
+        }
+
+        /// Swapping two vertices flips the winding, and with it the hit kind and barycentrics
+        #[test]
+        fn winding_picks_the_face(
+            triangle in [point(10.0), point(10.0), point(10.0)],
+            origin in point(20.0),
+            target in point(20.0),
+        ) {
+            let [v0, v1, v2] = triangle;
+            let ray = ray_towards(origin, target);
+            let hit = intersect_triangle(&ray, triangle);
+            let flipped = intersect_triangle(&ray, [v0, v2, v1]);
+            prop_assert_eq!(hit.is_some(), flipped.is_some());
+
+            if let (Some(hit), Some(flipped)) = (hit, flipped) {
+                prop_assert_ne!(hit.hit_kind, flipped.hit_kind);
+                prop_assert!((hit.t - flipped.t).abs() <= 1e-4 * hit.t.abs().max(1.0));
+                prop_assert!((hit.barycentrics.yx() - flipped.barycentrics).norm() < 1e-4);
+            }
+        }
+    }
+}
@@ -19,6 +19,10 @@ lazy_static! {
         Type: D3D12_HEAP_TYPE_DEFAULT,
         ..Default::default()
     };
+    pub static ref READBACK_HEAP: D3D12_HEAP_PROPERTIES = D3D12_HEAP_PROPERTIES {
+        Type: D3D12_HEAP_TYPE_READBACK,
+        ..Default::default()
+    };
     pub static ref BASIC_BUFFER_DESC: D3D12_RESOURCE_DESC = D3D12_RESOURCE_DESC {
         Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
         Width: 0, // Will be changed in copies
@@ -122,6 +126,10 @@ impl<T> UploadResource<T> {
     pub fn get_gpu_virtual_address(&self) -> u64 {
         unsafe { self.resource.GetGPUVirtualAddress() }
     }
+
+    pub fn get_resource(&self) -> &ID3D12Resource {
+        &self.resource
+    }
 }
 
 impl<T> From<UploadResource<T>> for ID3D12Resource {
@@ -208,6 +216,19 @@ impl ResourceFactory {
         Ok(resource)
     }
 
+    /// Readback resources are CPU mappable like upload resources, so they share the same wrapper
+    pub fn create_readback_resource<T>(&self, name: PCWSTR, size: u64) -> Result<UploadResource<T>> {
+        let resource = self.create_d3d12_resource(
+            name,
+            *READBACK_HEAP,
+            None,
+            Some(D3D12_RESOURCE_STATE_COPY_DEST),
+            size * std::mem::size_of::<T>() as u64,
+        )?;
+
+        Ok(UploadResource::from_resource(resource, size as usize))
+    }
+
     pub fn create_gpu_resource(
         &self,
         name: PCWSTR,
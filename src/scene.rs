@@ -1,25 +1,16 @@
+use crate::attributes::PackedAttributes;
+use crate::camera::{Camera, CameraConstants};
 use crate::device_interface::DeviceInterface;
 use crate::imports::*;
+use crate::light::PackedLight;
+use crate::material::PackedMaterial;
 use crate::resource::{OpaqueResource, ResourceBuffer, UploadResource};
-use nalgebra::{Matrix4, Vector3};
+use crate::scene_graph::SceneGraph;
+use crate::scene_model::SceneModel;
+use crate::tlas::InstanceDesc;
+use crate::tlas_policy::{TlasBuild, TlasPolicy};
+use nalgebra::Matrix4;
 use ouroboros::self_referencing;
-use windows::Win32::System::SystemInformation::GetTickCount;
-
-const QUAD_VTX: [f32; 18] = [
-    -1.0, 0.0, -1.0, -1.0, 0.0, 1.0, 1.0, 0.0, 1.0, -1.0, 0.0, -1.0, 1.0, 0.0, -1.0, 1.0, 0.0, 1.0,
-];
-
-const CUBE_VTX: [f32; 24] = [
-    -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 1.0, -1.0, -1.0, -1.0, 1.0, 1.0, -1.0,
-    1.0, -1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
-];
-
-const CUBE_IDX: [u16; 36] = [
-    4, 6, 0, 2, 0, 6, 0, 1, 4, 5, 4, 1, 0, 2, 1, 3, 1, 2, 1, 3, 5, 7, 5, 3, 2, 6, 3, 7, 3, 6, 4, 5,
-    6, 7, 6, 5,
-];
-
-const NUM_INSTANCES: u32 = 3;
 
 #[self_referencing]
 struct Instances {
@@ -29,24 +20,47 @@ struct Instances {
     buffer: ResourceBuffer<'this, D3D12_RAYTRACING_INSTANCE_DESC>,
 }
 
-pub struct Scene {
-    _quad_buffer: OpaqueResource,
-    _cube_buffer: OpaqueResource,
-    _cube_index_buffer: OpaqueResource,
+/// A mesh's geometry buffers, which have to outlive its BLAS
+struct MeshResources {
+    _vertex_buffer: OpaqueResource,
+    _index_buffer: OpaqueResource,
+    blas: OpaqueResource,
+}
 
-    _quad_blas: OpaqueResource,
-    _cube_blas: OpaqueResource,
+pub struct Scene {
+    _meshes: Vec<MeshResources>,
 
     tlas: OpaqueResource,
     tlas_scratch: OpaqueResource,
+    /// What the TLAS and its scratch buffer were sized for
+    tlas_sizes: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO,
 
+    /// Both instance buffers can have room for more instances than the scene has
     instances: Instances,
+    /// Where each instance's mesh starts in `attribute_indices`, indexed by InstanceIndex()
+    instance_first_indices: UploadResource<u32>,
+    /// What ClosestHit shades each instance with, indexed by InstanceID()
+    materials: UploadResource<PackedMaterial>,
+    /// What the shader's DirectLight loops over
+    lights: UploadResource<PackedLight>,
+
+    /// The buffers InterpolateAttributes reads every mesh's normals and UVs from, see
+    /// `PackedAttributes`
+    attribute_indices: UploadResource<u32>,
+    normals: UploadResource<[f32; 3]>,
+    uvs: UploadResource<[f32; 2]>,
+    /// Where each mesh starts in `attribute_indices`
+    first_indices: Vec<u32>,
+
+    graph: SceneGraph,
+    policy: TlasPolicy,
+    camera: Camera,
 }
 
 fn make_acceleration_structure(
     interface: &DeviceInterface,
     inputs: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS,
-) -> Result<(OpaqueResource, u64)> {
+) -> Result<OpaqueResource> {
     let mut prebuild_info = Default::default();
     unsafe {
         interface
@@ -54,8 +68,6 @@ fn make_acceleration_structure(
             .GetRaytracingAccelerationStructurePrebuildInfo(&inputs, &mut prebuild_info);
     }
 
-    let update_scratch_size = prebuild_info.UpdateScratchDataSizeInBytes;
-
     let scratch = interface.resource_factory.create_gpu_resource(
         w!("Scratch Buffer"),
         Some(D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS),
@@ -89,7 +101,7 @@ fn make_acceleration_structure(
 
     interface.wait_for_gpu()?;
 
-    Ok((acceleration_structure, update_scratch_size))
+    Ok(acceleration_structure)
 }
 
 fn make_blas<V, I>(
@@ -103,10 +115,10 @@ fn make_blas<V, I>(
         Anonymous: D3D12_RAYTRACING_GEOMETRY_DESC_0 {
             Triangles: D3D12_RAYTRACING_GEOMETRY_TRIANGLES_DESC {
                 Transform3x4: 0,
-                IndexFormat: if index_buffer.is_some() {
-                    DXGI_FORMAT_R16_UINT
-                } else {
-                    DXGI_FORMAT_UNKNOWN
+                IndexFormat: match index_buffer.map(|_| std::mem::size_of::<I>()) {
+                    Some(2) => DXGI_FORMAT_R16_UINT,
+                    Some(4) => DXGI_FORMAT_R32_UINT,
+                    _ => DXGI_FORMAT_UNKNOWN,
                 },
                 VertexFormat: DXGI_FORMAT_R32G32B32_FLOAT,
                 IndexCount: index_buffer.map(|ib| ib.len() as u32).unwrap_or(0),
@@ -133,138 +145,261 @@ fn make_blas<V, I>(
         ..Default::default()
     };
 
-    let (blas, _) = make_acceleration_structure(interface, inputs)?;
-    Ok(blas)
+    make_acceleration_structure(interface, inputs)
 }
 
-fn make_tlas(
-    interface: &DeviceInterface,
+/// Inputs for the TLAS over the first `num_instances` descs in `instances`, either building it from
+/// scratch or refitting it in place
+fn tlas_inputs(
     instances: &UploadResource<D3D12_RAYTRACING_INSTANCE_DESC>,
-) -> Result<(OpaqueResource, u64)> {
-    let inputs = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
+    num_instances: usize,
+    refit: bool,
+) -> D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
+    let mut flags = D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_ALLOW_UPDATE; // TODO: Add flag for fast trace
+    if refit {
+        flags |= D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_PERFORM_UPDATE;
+    }
+
+    D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
         Type: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_TYPE_TOP_LEVEL,
-        Flags: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_ALLOW_UPDATE, // TODO: Add flag for fast trace
-        NumDescs: instances.len() as u32,
+        Flags: flags,
+        NumDescs: num_instances as u32,
         DescsLayout: D3D12_ELEMENTS_LAYOUT_ARRAY,
         Anonymous: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS_0 {
             InstanceDescs: instances.get_gpu_virtual_address(),
         },
-    };
-
-    make_acceleration_structure(interface, inputs)
+    }
 }
 
-fn update_transforms(instances: &mut ResourceBuffer<D3D12_RAYTRACING_INSTANCE_DESC>) {
-    let mut set = |i: usize, m: Matrix4<f32>| {
-        instances[i]
-            .Transform
-            .copy_from_slice(&m.transpose().as_slice()[..12]);
-    };
+/// Creates a TLAS and a scratch buffer big enough to both build and refit it from `inputs`
+fn create_tlas(
+    interface: &DeviceInterface,
+    inputs: &D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS,
+) -> Result<(
+    OpaqueResource,
+    OpaqueResource,
+    D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO,
+)> {
+    let mut prebuild_info = Default::default();
+    unsafe {
+        interface
+            .device
+            .GetRaytracingAccelerationStructurePrebuildInfo(inputs, &mut prebuild_info);
+    }
 
-    let time = unsafe { GetTickCount() as f32 / 1000.0 };
-    let mut cube = Matrix4::from_euler_angles(time / 2.0, time / 3.0, time / 5.0);
-    cube = cube.append_translation(&Vector3::new(-1.5, 2.0, 2.0));
-    set(0, cube);
+    let tlas = interface.resource_factory.create_gpu_resource(
+        w!("TLAS"),
+        Some(D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS),
+        Some(D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE),
+        prebuild_info.ResultDataMaxSizeInBytes,
+    )?;
 
-    let mut mirror = Matrix4::from_scaled_axis(
-        (&Vector3::x() * -1.8) + (&Vector3::y() * (time.sin() / 8.0 + 1.0)),
-    );
-    mirror = mirror.append_translation(&Vector3::new(2.0, 2.0, 2.0));
-    set(1, mirror);
+    let scratch = interface.resource_factory.create_gpu_resource(
+        w!("TLAS Scratch"),
+        Some(D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS),
+        None,
+        prebuild_info
+            .ScratchDataSizeInBytes
+            .max(prebuild_info.UpdateScratchDataSizeInBytes),
+    )?;
 
-    let mut floor = Matrix4::new_scaling(5.0);
-    floor = floor.append_translation(&Vector3::new(0.0, 0.0, 2.0));
-    set(2, floor);
+    Ok((tlas, scratch, prebuild_info))
 }
 
-impl Scene {
-    pub fn build(interface: &DeviceInterface) -> Result<Self> {
-        let quad_buffer = interface
-            .resource_factory
-            .create_upload_resource_from_slice(w!("Quad Buffer"), None, None, &QUAD_VTX)?;
-
-        let cube_buffer = interface
-            .resource_factory
-            .create_upload_resource_from_slice(w!("Cube Buffer"), None, None, &CUBE_VTX)?;
-
-        let cube_index_buffer = interface
-            .resource_factory
-            .create_upload_resource_from_slice(w!("Cube Index Buffer"), None, None, &CUBE_IDX)?;
-
-        // TODO: Name these resources
-        let quad_blas = make_blas::<f32, ()>(interface, &quad_buffer, None)?;
-        let cube_blas = make_blas(interface, &cube_buffer, Some(&cube_index_buffer))?;
-
-        let instances = interface.resource_factory.create_upload_resource(
-            w!("Instances"),
-            None,
-            None,
-            NUM_INSTANCES as u64,
-        )?;
-
-        {
-            let mut instances_buffer = instances.get_buffer()?;
-
-            for i in 0..NUM_INSTANCES {
-                instances_buffer[i as usize] = D3D12_RAYTRACING_INSTANCE_DESC {
-                    _bitfield1: i | (1 << 24),
-                    AccelerationStructure: if i == 0 {
-                        cube_blas.get_gpu_virtual_address()
-                    } else {
-                        quad_blas.get_gpu_virtual_address()
-                    },
-                    ..Default::default()
-                }
-            }
+/// Upload buffers with room for `capacity` instances
+fn create_instance_buffers(
+    interface: &DeviceInterface,
+    capacity: usize,
+) -> Result<(Instances, UploadResource<u32>)> {
+    let instances = interface.resource_factory.create_upload_resource(
+        w!("Instances"),
+        None,
+        None,
+        capacity as u64,
+    )?;
 
-            update_transforms(&mut instances_buffer);
-        }
+    let instances = InstancesBuilder {
+        resource: instances,
+        buffer_builder: |resource| resource.get_buffer().unwrap(),
+    }
+    .build();
 
-        let (tlas, scratch_size) = make_tlas(interface, &instances)?;
-        let tlas_scratch = interface.resource_factory.create_gpu_resource(
-            w!("TLAS Scratch"),
-            Some(D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS),
-            None,
-            scratch_size,
-        )?;
-
-        let instances = InstancesBuilder {
-            resource: instances,
-            buffer_builder: |resource| resource.get_buffer().unwrap(),
-        }
-        .build();
+    let first_indices = interface.resource_factory.create_upload_resource(
+        w!("Instance First Indices"),
+        None,
+        None,
+        capacity as u64,
+    )?;
+
+    Ok((instances, first_indices))
+}
+
+/// An upload buffer holding `data`. Buffers can't be empty, so there's a default `T` in place of
+/// no data at all.
+fn create_buffer<T: Copy + Default>(
+    interface: &DeviceInterface,
+    name: PCWSTR,
+    data: &[T],
+) -> Result<UploadResource<T>> {
+    let default = [T::default()];
+    let data = if data.is_empty() { &default } else { data };
+    interface
+        .resource_factory
+        .create_upload_resource_from_slice(name, None, None, data)
+}
+
+impl Scene {
+    pub fn build(interface: &DeviceInterface, model: &SceneModel) -> Result<Self> {
+        let meshes = model
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let vertex_name = HSTRING::from(format!("{} Vertices", mesh.name));
+                let vertex_buffer = interface.resource_factory.create_upload_resource_from_slice(
+                    PCWSTR(vertex_name.as_ptr()),
+                    None,
+                    None,
+                    &mesh.positions,
+                )?;
+
+                let index_name = HSTRING::from(format!("{} Indices", mesh.name));
+                let index_buffer = interface.resource_factory.create_upload_resource_from_slice(
+                    PCWSTR(index_name.as_ptr()),
+                    None,
+                    None,
+                    &mesh.indices,
+                )?;
+
+                // TODO: Name these resources
+                let blas = make_blas(interface, &vertex_buffer, Some(&index_buffer))?;
+
+                Ok(MeshResources {
+                    _vertex_buffer: vertex_buffer.into(),
+                    _index_buffer: index_buffer.into(),
+                    blas,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let materials: Vec<PackedMaterial> =
+            model.materials.iter().map(PackedMaterial::from).collect();
+        let materials = create_buffer(interface, w!("Materials"), &materials)?;
+
+        let lights: Vec<PackedLight> = model.lights.iter().map(PackedLight::from).collect();
+        let lights = create_buffer(interface, w!("Lights"), &lights)?;
+
+        let attributes = PackedAttributes::new(&model.meshes);
+        let attribute_indices =
+            create_buffer(interface, w!("Attribute Indices"), &attributes.indices)?;
+        let normals = create_buffer(interface, w!("Normals"), &attributes.normals)?;
+        let uvs = create_buffer(interface, w!("UVs"), &attributes.uvs)?;
+
+        // Buffers can't be empty, even when the scene is
+        let num_instances = model.instances.len();
+        let (instances, instance_first_indices) =
+            create_instance_buffers(interface, num_instances.max(1))?;
+
+        // The TLAS is built by the first update, the policy always starts with a rebuild
+        let inputs = tlas_inputs(instances.borrow_resource(), num_instances, false);
+        let (tlas, tlas_scratch, tlas_sizes) = create_tlas(interface, &inputs)?;
 
         Ok(Scene {
-            _quad_buffer: quad_buffer.into(),
-            _cube_buffer: cube_buffer.into(),
-            _cube_index_buffer: cube_index_buffer.into(),
-            _quad_blas: quad_blas,
-            _cube_blas: cube_blas,
+            _meshes: meshes,
             tlas,
             tlas_scratch,
+            tlas_sizes,
             instances,
+            instance_first_indices,
+            materials,
+            lights,
+            attribute_indices,
+            normals,
+            uvs,
+            first_indices: attributes.first_indices,
+            graph: SceneGraph::new(model),
+            policy: TlasPolicy::default(),
+            camera: model.camera,
         })
     }
 
-    pub fn update(&mut self, interface: &DeviceInterface) {
-        self.instances.with_buffer_mut(|instances| {
-            update_transforms(instances);
+    pub fn graph_mut(&mut self) -> &mut SceneGraph {
+        &mut self.graph
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+    }
+
+    /// Writes every instance at `time` into the instance buffers, reallocating them with room to
+    /// spare once the scene outgrows them. Returns the instances' object to world matrices.
+    fn write_instances(
+        &mut self,
+        interface: &DeviceInterface,
+        time: f32,
+    ) -> Result<Vec<Matrix4<f32>>> {
+        let instances = self.graph.instance_descs(time);
+
+        let capacity = self.instances.borrow_resource().len();
+        if instances.len() > capacity {
+            let capacity = instances.len().next_power_of_two();
+            (self.instances, self.instance_first_indices) =
+                create_instance_buffers(interface, capacity)?;
+        }
+
+        let mut first_indices = self.instance_first_indices.get_buffer()?;
+        let meshes = &self._meshes;
+        self.instances.with_buffer_mut(|descs| {
+            for (i, desc) in instances.iter().enumerate() {
+                descs[i] = D3D12_RAYTRACING_INSTANCE_DESC {
+                    Transform: desc.transform.0,
+                    _bitfield1: desc.id_and_mask,
+                    AccelerationStructure: meshes[desc.blas].blas.get_gpu_virtual_address(),
+                    ..Default::default()
+                };
+                first_indices[i] = self.first_indices[desc.blas];
+            }
         });
 
+        Ok(instances
+            .iter()
+            .map(InstanceDesc::object_to_world)
+            .collect())
+    }
+
+    pub fn update(&mut self, interface: &DeviceInterface, time: f32) -> Result<()> {
+        let matrices = self.write_instances(interface, time)?;
+
+        // There's no telling how much a D3D12 refit has degraded the TLAS
+        let refit = self.policy.decide(self.graph.revision(), &matrices, None) == TlasBuild::Refit;
+        let inputs = tlas_inputs(self.instances.borrow_resource(), matrices.len(), refit);
+
+        if !refit {
+            let mut prebuild_info = Default::default();
+            unsafe {
+                interface
+                    .device
+                    .GetRaytracingAccelerationStructurePrebuildInfo(&inputs, &mut prebuild_info);
+            }
+
+            // Presenting waits for the GPU, so nothing is still using the old TLAS
+            let sizes = &self.tlas_sizes;
+            if prebuild_info.ResultDataMaxSizeInBytes > sizes.ResultDataMaxSizeInBytes
+                || prebuild_info.ScratchDataSizeInBytes > sizes.ScratchDataSizeInBytes
+                || prebuild_info.UpdateScratchDataSizeInBytes > sizes.UpdateScratchDataSizeInBytes
+            {
+                (self.tlas, self.tlas_scratch, self.tlas_sizes) = create_tlas(interface, &inputs)?;
+            }
+        }
+
         let desc = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_DESC {
             DestAccelerationStructureData: self.tlas.get_gpu_virtual_address(),
-            Inputs: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
-                Type: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_TYPE_TOP_LEVEL,
-                Flags: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_PERFORM_UPDATE,
-                NumDescs: NUM_INSTANCES,
-                DescsLayout: D3D12_ELEMENTS_LAYOUT_ARRAY,
-                Anonymous: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS_0 {
-                    InstanceDescs: self.instances.borrow_resource().get_gpu_virtual_address(),
-                },
+            Inputs: inputs,
+            SourceAccelerationStructureData: if refit {
+                self.tlas.get_gpu_virtual_address()
+            } else {
+                0
             },
-            SourceAccelerationStructureData: self.tlas.get_gpu_virtual_address(),
             ScratchAccelerationStructureData: self.tlas_scratch.get_gpu_virtual_address(),
-            ..Default::default()
         };
 
         let barrier = D3D12_RESOURCE_BARRIER {
@@ -283,13 +418,36 @@ impl Scene {
                 .BuildRaytracingAccelerationStructure(&desc, None);
             interface.command_list.ResourceBarrier(&[barrier]);
         }
+
+        Ok(())
     }
 
-    pub fn bind(&self, interface: &DeviceInterface) {
+    /// Binds the scene for rendering a `width` by `height` image
+    pub fn bind(&self, interface: &DeviceInterface, width: u32, height: u32) {
+        let command_list = &interface.command_list;
+        let camera = self.camera.constants(width, height);
         unsafe {
-            interface
-                .command_list
-                .SetComputeRootShaderResourceView(1, self.tlas.get_gpu_virtual_address())
+            command_list.SetComputeRootShaderResourceView(1, self.tlas.get_gpu_virtual_address());
+            command_list
+                .SetComputeRootShaderResourceView(2, self.materials.get_gpu_virtual_address());
+            command_list.SetComputeRoot32BitConstants(
+                3,
+                CameraConstants::NUM_32BIT_VALUES,
+                (&camera as *const CameraConstants).cast(),
+                0,
+            );
+            command_list.SetComputeRootShaderResourceView(4, self.lights.get_gpu_virtual_address());
+            command_list.SetComputeRootShaderResourceView(
+                5,
+                self.attribute_indices.get_gpu_virtual_address(),
+            );
+            command_list
+                .SetComputeRootShaderResourceView(6, self.normals.get_gpu_virtual_address());
+            command_list.SetComputeRootShaderResourceView(7, self.uvs.get_gpu_virtual_address());
+            command_list.SetComputeRootShaderResourceView(
+                8,
+                self.instance_first_indices.get_gpu_virtual_address(),
+            );
         }
     }
 }
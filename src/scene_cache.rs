@@ -0,0 +1,601 @@
+//! Binary scene caches, so big scenes start without importing their meshes and building BVHs again.
+//!
+//! `tracer bake <SCENE>` loads a scene, builds every mesh's BVH and writes the lot next to the scene
+//! with .cache added to its name. Loading the scene maps the cache instead from then on, as long as
+//! it was baked by this version of the tracer from files with the same contents, and rebakes it when
+//! they've changed.
+//!
+//! Caches are in the byte order of the machine that baked them and everything in them is 4 byte
+//! aligned, so arrays are copied straight out of the mapping. Meshes own their arrays, so mapping
+//! the file only saves reading all of it into a buffer first:
+//!
+//! ```text
+//! header  magic, version, content hash of the sources, source paths relative to the cache
+//! scene   warnings, camera, lights, materials, nodes, instances
+//! meshes  name, positions, normals, UVs, indices, spatial split budget, BVH nodes and leaf order,
+//!         for each mesh
+//! ```
+
+use crate::bvh::Bvh;
+use crate::camera::Camera;
+use crate::light::{Light, LightKind};
+use crate::scene_model::{
+    self, Animation, Instance, Material, Mesh, Node, SceneError, SceneModel, Transform,
+};
+use bytemuck::Pod;
+use memmap2::Mmap;
+use nalgebra::{Quaternion, UnitQuaternion, Vector3, Vector4};
+use std::fs::{self, File};
+use std::io;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 8] = *b"TRCSCENE";
+
+/// Has to change along with the layout, or anything else about what gets baked, to make older
+/// caches stale
+const VERSION: u32 = 6;
+
+/// Stands in for a node without a parent
+const NO_PARENT: u32 = u32::MAX;
+
+/// Stands in for a mesh without spatial splits, a NaN no budget can be
+const NO_SPATIAL_SPLITS: u32 = u32::MAX;
+
+/// Where `tracer bake` puts the cache of the scene at `scene`
+pub fn cache_path(scene: &Path) -> PathBuf {
+    let mut path = scene.as_os_str().to_owned();
+    path.push(".cache");
+    PathBuf::from(path)
+}
+
+/// Hashes the contents of every source in order, so changing, adding or removing any of them
+/// changes the hash
+fn content_hash(sources: &[PathBuf]) -> io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    for source in sources {
+        let contents = fs::read(source)?;
+        hasher.update(&(contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn vector(&mut self, v: [f32; 3]) {
+        self.bytes.extend_from_slice(bytemuck::cast_slice(&v));
+    }
+
+    /// Writes the length followed by the elements, padded to keep what comes next aligned
+    fn array<T: Pod>(&mut self, values: &[T]) {
+        self.u32(values.len() as u32);
+        self.bytes.extend_from_slice(bytemuck::cast_slice(values));
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.array(s.as_bytes());
+    }
+
+    fn camera(&mut self, camera: &Camera) {
+        self.vector(camera.position.into());
+        let orientation = camera.orientation.coords;
+        for value in orientation
+            .iter()
+            .chain(&[camera.fov_y, camera.near, camera.far])
+        {
+            self.u32(value.to_bits());
+        }
+    }
+
+    fn light(&mut self, light: &Light) {
+        let (kind, position, direction, [inner, outer]) = match light.kind {
+            LightKind::Point { position } => (0, position.coords, Vector3::zeros(), [0.0; 2]),
+            LightKind::Directional { direction } => (1, Vector3::zeros(), direction, [0.0; 2]),
+            LightKind::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+            } => (2, position.coords, direction, [inner_angle, outer_angle]),
+        };
+        self.u32(kind);
+        self.vector(position.into());
+        self.vector(direction.into());
+        self.u32(inner.to_bits());
+        self.u32(outer.to_bits());
+        self.vector(light.color.into());
+        self.u32(light.intensity.to_bits());
+    }
+
+    fn transform(&mut self, transform: &Transform) {
+        self.vector(transform.translation.into());
+        self.vector(transform.rotation.into());
+        self.vector(transform.scale.into());
+
+        let (kind, v, frequency) = match transform.animation {
+            None => (0, [0.0; 3], 0.0),
+            Some(Animation::Spin { rate }) => (1, rate, 0.0),
+            Some(Animation::Sway {
+                amplitude,
+                frequency,
+            }) => (2, amplitude, frequency),
+        };
+        self.u32(kind);
+        self.vector(v);
+        self.u32(frequency.to_bits());
+    }
+
+    fn mesh(&mut self, mesh: &Mesh, bvh: &Bvh) {
+        self.str(&mesh.name);
+        self.array(&mesh.positions);
+        self.array(&mesh.normals);
+        self.array(&mesh.uvs);
+        self.array(&mesh.indices);
+        self.u32(mesh.spatial_splits.map_or(NO_SPATIAL_SPLITS, f32::to_bits));
+        self.array(&bvh.hierarchy().packed_nodes());
+        self.array(bvh.hierarchy().order());
+    }
+}
+
+const TRUNCATED: &str = "it's truncated";
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err(TRUNCATED.to_string());
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn vector(&mut self) -> Result<[f32; 3], String> {
+        Ok([self.f32()?, self.f32()?, self.f32()?])
+    }
+
+    fn array<T: Pod>(&mut self) -> Result<&'a [T], String> {
+        let len = self.u32()? as usize;
+        let size = len * size_of::<T>();
+        let bytes = self.take(size)?;
+        self.take(size.next_multiple_of(4) - size)?;
+        bytemuck::try_cast_slice(bytes).map_err(|error| error.to_string())
+    }
+
+    fn str(&mut self) -> Result<&'a str, String> {
+        std::str::from_utf8(self.array()?).map_err(|error| error.to_string())
+    }
+
+    /// Reads a count followed by that many of whatever `read` reads
+    fn list<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let len = self.u32()?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    /// Reads an index into something `len` long
+    fn index(&mut self, len: usize, what: &str) -> Result<usize, String> {
+        let index = self.u32()? as usize;
+        if index < len {
+            Ok(index)
+        } else {
+            Err(format!("it refers to {what} {index}, which doesn't exist"))
+        }
+    }
+
+    fn camera(&mut self) -> Result<Camera, String> {
+        let mut camera = Camera::new(self.vector()?.into());
+        let coords = Vector4::new(self.f32()?, self.f32()?, self.f32()?, self.f32()?);
+        camera.orientation = UnitQuaternion::new_unchecked(Quaternion::from(coords));
+        camera.fov_y = self.f32()?;
+        camera.near = self.f32()?;
+        camera.far = self.f32()?;
+        Ok(camera)
+    }
+
+    fn light(&mut self) -> Result<Light, String> {
+        let kind = self.u32()?;
+        let position = self.vector()?.into();
+        let direction = self.vector()?.into();
+        let inner_angle = self.f32()?;
+        let outer_angle = self.f32()?;
+        let kind = match kind {
+            0 => LightKind::Point { position },
+            1 => LightKind::Directional { direction },
+            2 => LightKind::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+            },
+            _ => return Err(format!("it has an unknown kind of light {kind}")),
+        };
+
+        Ok(Light {
+            kind,
+            color: self.vector()?.into(),
+            intensity: self.f32()?,
+        })
+    }
+
+    fn transform(&mut self) -> Result<Transform, String> {
+        let translation = Vector3::from(self.vector()?);
+        let rotation = Vector3::from(self.vector()?);
+        let scale = Vector3::from(self.vector()?);
+
+        let kind = self.u32()?;
+        let v = self.vector()?;
+        let frequency = self.f32()?;
+        let animation = match kind {
+            0 => None,
+            1 => Some(Animation::Spin { rate: v }),
+            2 => Some(Animation::Sway {
+                amplitude: v,
+                frequency,
+            }),
+            _ => return Err(format!("it has an unknown kind of animation {kind}")),
+        };
+
+        Ok(Transform {
+            translation,
+            rotation,
+            scale,
+            animation,
+        })
+    }
+
+    fn mesh(&mut self) -> Result<Mesh, String> {
+        let mut mesh = Mesh {
+            name: self.str()?.to_string(),
+            positions: self.array()?.to_vec(),
+            normals: self.array()?.to_vec(),
+            uvs: self.array()?.to_vec(),
+            indices: self.array()?.to_vec(),
+            bvh: None,
+            spatial_splits: match self.u32()? {
+                NO_SPATIAL_SPLITS => None,
+                budget => Some(f32::from_bits(budget)),
+            },
+        };
+        scene_model::check_mesh(&mesh)?;
+
+        let packed = self.array()?;
+        let order = self.array()?.to_vec();
+        let bvh = Bvh::unpack(&mesh.positions, &mesh.indices, packed, order)?;
+        mesh.bvh = Some(bvh);
+        Ok(mesh)
+    }
+
+    fn scene(&mut self, sources: Vec<PathBuf>) -> Result<SceneModel, String> {
+        let warnings = self.list(|r| Ok(r.str()?.to_string()))?;
+        let camera = self.camera()?;
+
+        let lights = self.list(Self::light)?;
+
+        let materials = self.list(|r| {
+            let kind = r.u32()?;
+            let color = r.vector()?;
+            let value = r.f32()?;
+            match kind {
+                0 => Ok(Material::Faces),
+                1 => Ok(Material::Mirror),
+                2 => Ok(Material::Checker),
+                3 => Ok(Material::Diffuse { color }),
+                4 => Ok(Material::Glass { color, ior: value }),
+                5 => Ok(Material::Emissive {
+                    color,
+                    intensity: value,
+                }),
+                _ => Err(format!("it has an unknown kind of material {kind}")),
+            }
+        })?;
+
+        let mut nodes = Vec::new();
+        for i in 0..self.u32()? as usize {
+            // Parents have to come first, like in scene files
+            let parent = match self.u32()? {
+                NO_PARENT => None,
+                parent if (parent as usize) < i => Some(parent as usize),
+                parent => return Err(format!("node {i} comes before its parent {parent}")),
+            };
+            let transform = self.transform()?;
+            nodes.push(Node { parent, transform });
+        }
+
+        // Instances come before the meshes they refer to, which are checked at the end
+        let mut num_meshes = 0;
+        let instances = self.list(|r| {
+            let mesh = r.u32()? as usize;
+            num_meshes = num_meshes.max(mesh + 1);
+            Ok(Instance {
+                mesh,
+                material: r.index(materials.len(), "material")?,
+                node: r.index(nodes.len(), "node")?,
+            })
+        })?;
+
+        let meshes = self.list(Self::mesh)?;
+        if num_meshes > meshes.len() {
+            return Err(format!(
+                "it refers to mesh {}, which doesn't exist",
+                num_meshes - 1
+            ));
+        }
+
+        if lights.is_empty() {
+            return Err("it has no lights".to_string());
+        }
+
+        Ok(SceneModel {
+            camera,
+            lights,
+            materials,
+            meshes,
+            nodes,
+            instances,
+            warnings,
+            sources,
+        })
+    }
+}
+
+/// Writes `scene` to `cache`, whose meshes all need a BVH. The cache is written next to where it
+/// goes and renamed into place, so nothing ever sees half a cache or has one change under its map.
+fn write(scene: &SceneModel, cache: &Path) -> io::Result<()> {
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(&MAGIC);
+    writer.u32(VERSION);
+    writer
+        .bytes
+        .extend_from_slice(&content_hash(&scene.sources)?);
+
+    let dir = cache.parent().unwrap_or(Path::new(""));
+    writer.u32(scene.sources.len() as u32);
+    for source in &scene.sources {
+        let relative = source.strip_prefix(dir).unwrap_or(source);
+        let relative = relative.to_str().ok_or_else(|| {
+            let message = format!("Can't store the path {}", source.display());
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })?;
+        writer.str(relative);
+    }
+
+    writer.u32(scene.warnings.len() as u32);
+    for warning in &scene.warnings {
+        writer.str(warning);
+    }
+
+    writer.camera(&scene.camera);
+
+    writer.u32(scene.lights.len() as u32);
+    for light in &scene.lights {
+        writer.light(light);
+    }
+
+    writer.u32(scene.materials.len() as u32);
+    for material in &scene.materials {
+        let (kind, color, value) = match *material {
+            Material::Faces => (0, [0.0; 3], 0.0),
+            Material::Mirror => (1, [0.0; 3], 0.0),
+            Material::Checker => (2, [0.0; 3], 0.0),
+            Material::Diffuse { color } => (3, color, 0.0),
+            Material::Glass { color, ior } => (4, color, ior),
+            Material::Emissive { color, intensity } => (5, color, intensity),
+        };
+        writer.u32(kind);
+        writer.vector(color);
+        writer.u32(value.to_bits());
+    }
+
+    writer.u32(scene.nodes.len() as u32);
+    for node in &scene.nodes {
+        writer.u32(node.parent.map_or(NO_PARENT, |parent| parent as u32));
+        writer.transform(&node.transform);
+    }
+
+    writer.u32(scene.instances.len() as u32);
+    for instance in &scene.instances {
+        writer.u32(instance.mesh as u32);
+        writer.u32(instance.material as u32);
+        writer.u32(instance.node as u32);
+    }
+
+    writer.u32(scene.meshes.len() as u32);
+    for mesh in &scene.meshes {
+        writer.mesh(mesh, mesh.bvh.as_ref().expect("Baked meshes have a BVH"));
+    }
+
+    let mut partial = cache.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, &writer.bytes)?;
+    fs::rename(&partial, cache)
+}
+
+/// Reads the scene back out of `cache`, or the reason it can't be used
+fn read(cache: &Path) -> Result<SceneModel, String> {
+    let file = File::open(cache).map_err(|error| error.to_string())?;
+    // Safety: caches are only ever replaced by renaming a new file over them, never modified in
+    // place, so the mapped bytes can't change while they're read
+    let map = unsafe { Mmap::map(&file) }.map_err(|error| error.to_string())?;
+    let mut reader = Reader { bytes: &map };
+
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err("it isn't a scene cache".to_string());
+    }
+
+    if reader.u32()? != VERSION {
+        return Err("it was baked by another version of the tracer".to_string());
+    }
+
+    let hash = reader.take(32)?;
+    let dir = cache.parent().unwrap_or(Path::new(""));
+    let sources = reader.list(|r| Ok(dir.join(r.str()?)))?;
+    match content_hash(&sources) {
+        Ok(current) if current == hash => (),
+        Ok(_) => return Err("its sources have changed".to_string()),
+        Err(error) => return Err(format!("couldn't read its sources: {error}")),
+    }
+
+    reader.scene(sources)
+}
+
+/// Loads the scene at `path` from its sources and builds the BVHs of all its meshes
+fn build(path: &Path) -> Result<SceneModel, SceneError> {
+    let mut scene = SceneModel::load(path)?;
+    for mesh in &mut scene.meshes {
+        let bvh = Bvh::build(&mesh.positions, &mesh.indices, &mesh.bvh_options());
+        mesh.bvh = Some(bvh);
+    }
+    Ok(scene)
+}
+
+/// Loads the scene at `path` from its sources, builds the BVHs of all its meshes and caches the lot
+pub fn bake(path: &Path) -> Result<SceneModel, SceneError> {
+    let scene = build(path)?;
+    let cache = cache_path(path);
+    write(&scene, &cache).map_err(|error| SceneError::new(&cache, error.to_string()))?;
+    Ok(scene)
+}
+
+/// Loads the scene at `path` from its cache when it has an up to date one, or like
+/// `SceneModel::load` when it has none. Caches that are out of date get baked again, and the scene
+/// still loads from its sources when the new cache can't be written.
+pub fn load(path: &Path) -> Result<SceneModel, SceneError> {
+    let cache = cache_path(path);
+    if !cache.exists() {
+        return SceneModel::load(path);
+    }
+
+    read(&cache).or_else(|reason| {
+        eprintln!("Baking {} again, {reason}", cache.display());
+        let scene = build(path)?;
+        if let Err(error) = write(&scene, &cache) {
+            eprintln!("Couldn't bake {}, {error}", cache.display());
+        }
+        Ok(scene)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const SCENE: &str = include_str!("../scenes/default.ron");
+
+    /// A directory of its own for each test, with the default scene in it
+    fn scene_dir(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        fs::write(dir.join("scene.ron"), SCENE).unwrap();
+        dir
+    }
+
+    #[test]
+    fn cached_scene_matches_its_source() {
+        let dir = scene_dir("cached-scene");
+        let path = dir.join("scene.ron");
+        let baked = bake(&path).unwrap();
+        let cached = read(&cache_path(&path)).unwrap();
+
+        assert_eq!(cached.sources, vec![path]);
+        assert_eq!(cached.camera, baked.camera);
+        assert_eq!(cached.lights, baked.lights);
+        assert_eq!(cached.materials, baked.materials);
+        assert_eq!(cached.nodes.len(), baked.nodes.len());
+        for (cached, baked) in cached.nodes.iter().zip(&baked.nodes) {
+            assert_eq!(cached.parent, baked.parent);
+            assert_eq!(cached.transform.matrix(1.5), baked.transform.matrix(1.5));
+        }
+        for (cached, baked) in cached.instances.iter().zip(&baked.instances) {
+            let fields = |i: &Instance| (i.mesh, i.material, i.node);
+            assert_eq!(fields(cached), fields(baked));
+        }
+        for (cached, baked) in cached.meshes.iter().zip(&baked.meshes) {
+            assert_eq!(cached.name, baked.name);
+            assert_eq!(cached.positions, baked.positions);
+            assert_eq!(cached.indices, baked.indices);
+
+            let [cached, baked] = [cached, baked].map(|m| m.bvh.as_ref().unwrap().hierarchy());
+            assert_eq!(cached.packed_nodes(), baked.packed_nodes());
+            assert_eq!(cached.order(), baked.order());
+        }
+    }
+
+    #[test]
+    fn changed_sources_make_the_cache_stale() {
+        let dir = scene_dir("stale-cache");
+        let path = dir.join("scene.ron");
+        bake(&path).unwrap();
+
+        let moved = SCENE.replace("(0.0, 1.5, -7.0)", "(0.0, 3.0, -7.0)");
+        fs::write(&path, moved).unwrap();
+        assert_eq!(
+            read(&cache_path(&path)).err().as_deref(),
+            Some("its sources have changed")
+        );
+
+        // Loading bakes it again
+        assert_eq!(load(&path).unwrap().camera.position.y, 3.0);
+        assert_eq!(read(&cache_path(&path)).unwrap().camera.position.y, 3.0);
+
+        fs::remove_file(&path).unwrap();
+        assert!(read(&cache_path(&path)).is_err());
+    }
+
+    #[test]
+    fn stale_caches_that_cant_be_rebaked_still_load() {
+        let dir = scene_dir("unwritable-cache");
+        let path = dir.join("scene.ron");
+        bake(&path).unwrap();
+
+        let moved = SCENE.replace("(0.0, 1.5, -7.0)", "(0.0, 3.0, -7.0)");
+        fs::write(&path, moved).unwrap();
+        // Caches are written next to themselves first, which fails with a directory in the way
+        fs::create_dir(dir.join("scene.ron.cache.partial")).unwrap();
+
+        assert_eq!(load(&path).unwrap().camera.position.y, 3.0);
+        assert_eq!(
+            read(&cache_path(&path)).err().as_deref(),
+            Some("its sources have changed")
+        );
+    }
+
+    #[test]
+    fn truncated_caches_are_rejected() {
+        let dir = scene_dir("truncated-cache");
+        let path = dir.join("scene.ron");
+        bake(&path).unwrap();
+
+        let cache = cache_path(&path);
+        let bytes = fs::read(&cache).unwrap();
+        for len in [0, 4, 20, 60, bytes.len() / 2, bytes.len() - 1] {
+            fs::write(&cache, &bytes[..len]).unwrap();
+            assert!(read(&cache).is_err());
+        }
+    }
+}
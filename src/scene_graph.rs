@@ -0,0 +1,302 @@
+//! The scene's nodes and instances as they change at runtime, resolved into the object to world
+//! matrices the instances are drawn with
+
+use crate::scene_model::{Instance, Node, SceneModel};
+use crate::tlas::InstanceDesc;
+use nalgebra::Matrix4;
+
+/// The InstanceMask of every instance
+const INSTANCE_MASK: u8 = 1;
+
+/// Names an instance for as long as it is in the scene, unlike its index which shifts as instances
+/// before it are removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceHandle(u32);
+
+pub struct SceneGraph {
+    num_meshes: usize,
+    num_materials: usize,
+    /// Parents always come before their children. Nodes are never removed, so anything can keep
+    /// hanging off them.
+    nodes: Vec<Node>,
+    instances: Vec<(InstanceHandle, Instance)>,
+    next_handle: u32,
+    revision: u64,
+}
+
+impl SceneGraph {
+    /// The scene's instances get handles counting up from zero in instance order
+    pub fn new(model: &SceneModel) -> Self {
+        let instances: Vec<_> = model
+            .instances
+            .iter()
+            .enumerate()
+            .map(|(i, instance)| (InstanceHandle(i as u32), *instance))
+            .collect();
+
+        Self {
+            num_meshes: model.meshes.len(),
+            num_materials: model.materials.len(),
+            nodes: model.nodes.clone(),
+            next_handle: instances.len() as u32,
+            instances,
+            revision: 0,
+        }
+    }
+
+    /// Goes up whenever an instance is added or removed, so acceleration structures can tell their
+    /// instance list is out of date even when the count is the same
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn instances(&self) -> impl ExactSizeIterator<Item = &Instance> {
+        self.instances.iter().map(|(_, instance)| instance)
+    }
+
+    /// The world matrix of every node at `time` seconds
+    pub fn world_matrices(&self, time: f32) -> Vec<Matrix4<f32>> {
+        let mut world: Vec<Matrix4<f32>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let local = node.transform.matrix(time);
+            world.push(match node.parent {
+                Some(parent) => world[parent] * local,
+                None => local,
+            });
+        }
+
+        world
+    }
+
+    /// The object to world matrix of every instance at `time` seconds, in instance order. These are
+    /// only ever affine, so the top three rows are all a D3D12_RAYTRACING_INSTANCE_DESC needs.
+    pub fn instance_matrices(&self, time: f32) -> Vec<Matrix4<f32>> {
+        let world = self.world_matrices(time);
+        self.instances()
+            .map(|instance| world[instance.node])
+            .collect()
+    }
+
+    /// The TLAS instance descs at `time`, in instance order. Each instance's InstanceID is its
+    /// material's index, which ClosestHit looks it up by, and its BLAS is its mesh's.
+    pub fn instance_descs(&self, time: f32) -> Vec<InstanceDesc> {
+        self.instances()
+            .zip(self.instance_matrices(time))
+            .map(|(instance, m)| {
+                InstanceDesc::new(m, instance.material as u32, INSTANCE_MASK, instance.mesh)
+            })
+            .collect()
+    }
+}
+
+// Nothing in the tracer itself edits the scene while it runs yet
+#[allow(dead_code)]
+impl SceneGraph {
+    /// Adds a node and returns its index. Its parent has to exist already.
+    pub fn add_node(&mut self, node: Node) -> Result<usize, String> {
+        if let Some(parent) = node.parent.filter(|&p| p >= self.nodes.len()) {
+            return Err(format!("Parent node {parent} doesn't exist"));
+        }
+
+        self.nodes.push(node);
+        Ok(self.nodes.len() - 1)
+    }
+
+    pub fn add_instance(&mut self, instance: Instance) -> Result<InstanceHandle, String> {
+        if instance.mesh >= self.num_meshes {
+            return Err(format!("Mesh {} doesn't exist", instance.mesh));
+        }
+
+        if instance.material >= self.num_materials {
+            return Err(format!("Material {} doesn't exist", instance.material));
+        }
+
+        if instance.node >= self.nodes.len() {
+            return Err(format!("Node {} doesn't exist", instance.node));
+        }
+
+        let handle = InstanceHandle(self.next_handle);
+        self.next_handle += 1;
+        self.instances.push((handle, instance));
+        self.revision += 1;
+        Ok(handle)
+    }
+
+    pub fn remove_instance(&mut self, handle: InstanceHandle) -> Result<(), String> {
+        let index = self
+            .instances
+            .iter()
+            .position(|&(h, _)| h == handle)
+            .ok_or(format!("{handle:?} isn't in the scene"))?;
+
+        self.instances.remove(index);
+        self.revision += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::affine::Affine3x4;
+    use crate::scene_model::{Animation, Transform};
+    use crate::tlas_policy::{TlasBuild, TlasPolicy};
+    use nalgebra::Vector3;
+
+    fn node_at(x: f32) -> Node {
+        Node {
+            parent: None,
+            transform: Transform {
+                translation: Vector3::new(x, 0.0, 0.0),
+                rotation: Vector3::zeros(),
+                scale: Vector3::repeat(1.0),
+                animation: None,
+            },
+        }
+    }
+
+    /// The default scene's graph with a node at `x` for each of `xs`
+    fn graph_with_nodes(xs: &[f32]) -> (SceneGraph, Vec<usize>) {
+        let mut graph = SceneGraph::new(&SceneModel::default_scene());
+        let nodes = xs
+            .iter()
+            .map(|&x| graph.add_node(node_at(x)).unwrap())
+            .collect();
+        (graph, nodes)
+    }
+
+    fn instance(node: usize) -> Instance {
+        Instance {
+            mesh: 0,
+            material: 0,
+            node,
+        }
+    }
+
+    fn translations(graph: &SceneGraph) -> Vec<f32> {
+        graph
+            .instance_matrices(0.0)
+            .iter()
+            .map(|m| m[(0, 3)])
+            .collect()
+    }
+
+    #[test]
+    fn children_move_with_their_animated_parents() {
+        let (mut graph, nodes) = graph_with_nodes(&[5.0]);
+        let parent = nodes[0];
+        graph.nodes[parent].transform.animation = Some(Animation::Spin {
+            rate: [0.0, 1.0, 0.0],
+        });
+        let local = Node {
+            parent: Some(parent),
+            transform: Transform {
+                translation: Vector3::new(0.0, 0.0, 2.0),
+                ..node_at(0.0).transform
+            },
+        };
+        let child = graph.add_node(local).unwrap();
+        graph.add_instance(instance(child)).unwrap();
+
+        for time in [0.0, 1.0] {
+            let world = graph.world_matrices(time);
+            assert_eq!(world[child], world[parent] * local.transform.matrix(time));
+
+            // Spinning about Y swings the child's offset round from +Z towards +X
+            let position = world[child].column(3).xyz();
+            let expected = Vector3::new(5.0 + 2.0 * time.sin(), 0.0, 2.0 * time.cos());
+            assert!(
+                (position - expected).norm() < 1e-5,
+                "{position:?} != {expected:?}"
+            );
+
+            let desc = graph.instance_descs(time).pop().unwrap();
+            assert_eq!(desc.transform, Affine3x4::from(world[child]));
+        }
+    }
+
+    #[test]
+    fn handles_outlive_removals_before_them() {
+        let (mut graph, nodes) = graph_with_nodes(&[10.0, 20.0, 30.0]);
+        let handles: Vec<_> = nodes
+            .iter()
+            .map(|&node| graph.add_instance(instance(node)).unwrap())
+            .collect();
+
+        graph.remove_instance(InstanceHandle(0)).unwrap();
+        graph.remove_instance(handles[0]).unwrap();
+        // Its index has shifted down by two, but the handle still finds the same instance
+        graph.remove_instance(handles[2]).unwrap();
+        assert_eq!(translations(&graph)[2..], [20.0]);
+
+        assert!(graph.remove_instance(handles[0]).is_err());
+        let handle = graph.add_instance(instance(nodes[0])).unwrap();
+        assert!(!handles.contains(&handle));
+    }
+
+    #[test]
+    fn every_instance_edit_is_a_new_revision() {
+        let (mut graph, nodes) = graph_with_nodes(&[10.0]);
+        // A node on its own doesn't change what's drawn
+        assert_eq!(graph.revision(), 0);
+
+        let handle = graph.add_instance(instance(nodes[0])).unwrap();
+        assert_eq!(graph.revision(), 1);
+        graph.remove_instance(handle).unwrap();
+        assert_eq!(graph.revision(), 2);
+
+        // Edits that are turned down leave the graph as it was
+        assert!(graph.remove_instance(handle).is_err());
+        assert!(graph.add_instance(instance(99)).is_err());
+        assert!(graph
+            .add_instance(Instance {
+                mesh: 99,
+                ..instance(0)
+            })
+            .is_err());
+        assert!(graph
+            .add_node(Node {
+                parent: Some(99),
+                ..node_at(0.0)
+            })
+            .is_err());
+        assert_eq!(graph.revision(), 2);
+    }
+
+    #[test]
+    fn removed_instances_are_no_longer_drawn() {
+        let (mut graph, nodes) = graph_with_nodes(&[10.0, 20.0]);
+        let first = graph.add_instance(instance(nodes[0])).unwrap();
+        graph.add_instance(instance(nodes[1])).unwrap();
+        assert_eq!(translations(&graph)[3..], [10.0, 20.0]);
+
+        graph.remove_instance(first).unwrap();
+        graph.remove_instance(InstanceHandle(1)).unwrap();
+        assert_eq!(translations(&graph), [-1.5, 0.0, 20.0]);
+        assert_eq!(graph.instance_descs(0.0).len(), 3);
+    }
+
+    #[test]
+    fn adding_or_removing_instances_rebuilds_the_tlas() {
+        let (mut graph, nodes) = graph_with_nodes(&[10.0, 20.0]);
+        let mut policy = TlasPolicy::default();
+        let mut decide = |graph: &SceneGraph| {
+            policy.decide(graph.revision(), &graph.instance_matrices(0.0), None)
+        };
+        assert_eq!(decide(&graph), TlasBuild::Rebuild);
+        assert_eq!(decide(&graph), TlasBuild::Refit);
+
+        let handle = graph.add_instance(instance(nodes[0])).unwrap();
+        assert_eq!(decide(&graph), TlasBuild::Rebuild);
+        assert_eq!(decide(&graph), TlasBuild::Refit);
+
+        // Swapping one instance for another at the same place keeps the count and the matrices
+        graph.remove_instance(handle).unwrap();
+        graph.add_instance(instance(nodes[0])).unwrap();
+        assert_eq!(decide(&graph), TlasBuild::Rebuild);
+
+        graph.remove_instance(InstanceHandle(0)).unwrap();
+        assert_eq!(decide(&graph), TlasBuild::Rebuild);
+        assert_eq!(decide(&graph), TlasBuild::Refit);
+    }
+}
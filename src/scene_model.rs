@@ -0,0 +1,1306 @@
+//! Scene description files and the validated, backend independent model they are loaded into.
+//!
+//! Scenes are written in RON, see scenes/default.ron for the scene the tracer renders when no other
+//! scene is given. Meshes and materials are declared once by name and referenced by instances.
+//! Meshes can also be loaded from Wavefront OBJ files, which bring their MTL materials with them.
+//! glTF files are whole scenes and can be loaded in place of a scene file.
+//!
+//! Instances can hang off named nodes, which can in turn hang off other nodes, so a group of
+//! instances moves and animates as a unit. See scenes/mirror_stand.ron.
+//!
+//! scenes/materials.ron shows off every kind of material and light.
+
+use crate::bvh::{Bvh, BvhOptions};
+use crate::camera::{Camera, DEFAULT_FOV};
+use crate::geometry::{CUBE_IDX, CUBE_VTX, QUAD_VTX};
+use crate::gltf_import;
+use crate::light::{Light, LightKind};
+use crate::obj;
+use crate::tlas::MAX_INSTANCE_ID;
+use nalgebra::{Matrix4, Vector3};
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SCENE: &str = include_str!("../scenes/default.ron");
+
+#[derive(Debug)]
+pub struct SceneError {
+    file: String,
+    position: Option<(usize, usize)>,
+    message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => {
+                write!(f, "{}:{}:{}: {}", self.file, line, column, self.message)
+            }
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+impl SceneError {
+    /// An error about the whole of `file` rather than somewhere in it
+    pub fn new(file: &Path, message: String) -> Self {
+        Self {
+            file: file.display().to_string(),
+            position: None,
+            message,
+        }
+    }
+}
+
+/// How a surface is shaded, see [`crate::material`] for how the shaders get at it
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Material {
+    /// Each face of the cube mesh gets its own color with dark edges, HitFaces in the shader
+    Faces,
+    /// Perfect reflection around the surface normal
+    Mirror,
+    /// World space checkerboard that receives shadows
+    Checker,
+    /// Lambertian surface of a flat color that receives shadows
+    Diffuse { color: [f32; 3] },
+    /// Reflects and refracts around the surface normal like Mirror, in the proportions the Fresnel
+    /// equations give for an index of refraction of `ior`. What's seen through it is tinted `color`.
+    Glass {
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "glass_ior")]
+        ior: f32,
+    },
+    /// Glows `color` times `intensity` whatever the lights are doing. It doesn't light anything else.
+    Emissive {
+        color: [f32; 3],
+        #[serde(default = "unit_intensity")]
+        intensity: f32,
+    },
+}
+
+fn glass_ior() -> f32 {
+    1.5
+}
+
+pub struct Mesh {
+    // Only used to name GPU resources so far
+    #[cfg_attr(not(d3d12), allow(dead_code))]
+    pub name: String,
+    /// R32G32B32 positions, the layout make_blas expects
+    pub positions: Vec<f32>,
+    /// Three floats per vertex, or empty when the mesh has no normals. The shaders get smooth ones
+    /// for meshes without, see [`crate::attributes`].
+    pub normals: Vec<f32>,
+    /// Two floats per vertex with V pointing down the texture, or empty when the mesh has no UVs
+    pub uvs: Vec<f32>,
+    pub indices: Vec<u32>,
+    /// Only meshes from a scene cache come with a BVH, the CPU backend builds its own for the rest
+    pub bvh: Option<Bvh>,
+    /// See [`BvhOptions::spatial_splits`]. D3D12 builds its own acceleration structures and ignores
+    /// this.
+    pub spatial_splits: Option<f32>,
+}
+
+impl Mesh {
+    /// What the mesh's BVH is built with on the CPU
+    pub fn bvh_options(&self) -> BvhOptions {
+        BvhOptions {
+            spatial_splits: self.spatial_splits,
+            ..BvhOptions::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Animation {
+    /// Adds Euler angles of `rate * time` radians on top of the static rotation
+    Spin { rate: [f32; 3] },
+    /// Offsets the static rotation vector by `amplitude * sin(frequency * time)`
+    Sway { amplitude: [f32; 3], frequency: f32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    /// Rotation as an axis scaled by the angle in radians
+    pub rotation: Vector3<f32>,
+    pub scale: Vector3<f32>,
+    pub animation: Option<Animation>,
+}
+
+impl Transform {
+    /// Local matrix at `time` seconds, applying scale, then rotation, then translation
+    pub fn matrix(&self, time: f32) -> Matrix4<f32> {
+        let rotation = match self.animation {
+            None => Matrix4::from_scaled_axis(self.rotation),
+            Some(Animation::Spin { rate }) => {
+                let [x, y, z] = rate.map(|r| r * time);
+                Matrix4::from_euler_angles(x, y, z) * Matrix4::from_scaled_axis(self.rotation)
+            }
+            Some(Animation::Sway {
+                amplitude,
+                frequency,
+            }) => Matrix4::from_scaled_axis(
+                self.rotation + Vector3::from(amplitude) * (frequency * time).sin(),
+            ),
+        };
+
+        (rotation * Matrix4::new_nonuniform_scaling(&self.scale))
+            .append_translation(&self.translation)
+    }
+}
+
+/// A transform in the scene graph, relative to its parent
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    /// Index into [`SceneModel::nodes`]
+    pub parent: Option<usize>,
+    pub transform: Transform,
+}
+
+/// A mesh and material placed in the world. An instance of an OBJ mesh in the scene file becomes one
+/// of these for every object in the file.
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
+    /// Index into [`SceneModel::meshes`]
+    pub mesh: usize,
+    /// Index into [`SceneModel::materials`]
+    pub material: usize,
+    /// Index into [`SceneModel::nodes`] of the node that places the instance
+    pub node: usize,
+}
+
+pub struct SceneModel {
+    pub camera: Camera,
+    pub lights: Vec<Light>,
+    pub materials: Vec<Material>,
+    pub meshes: Vec<Mesh>,
+    /// Parents always come before their children
+    pub nodes: Vec<Node>,
+    pub instances: Vec<Instance>,
+    /// Anything the importer couldn't represent and left out, for the caller to report
+    pub warnings: Vec<String>,
+    /// Every file the scene was loaded from, the scene file itself first. Empty for scenes that
+    /// didn't come from a file.
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+struct SceneFile {
+    camera: CameraFile,
+    lights: Vec<LightFile>,
+    materials: Vec<MaterialFile>,
+    meshes: Vec<MeshFile>,
+    #[serde(default)]
+    nodes: Vec<NodeFile>,
+    instances: Vec<InstanceFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraFile {
+    position: [f32; 3],
+    /// Where the camera looks, along +Z without one
+    #[serde(default)]
+    target: Option<[f32; 3]>,
+    /// Vertical field of view in degrees
+    #[serde(default = "default_fov")]
+    fov: f32,
+    #[serde(default)]
+    near: Option<f32>,
+    #[serde(default)]
+    far: Option<f32>,
+}
+
+fn default_fov() -> f32 {
+    DEFAULT_FOV
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+enum LightFile {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "unit_intensity")]
+        intensity: f32,
+    },
+    /// Like the sun, shining along `direction` everywhere
+    Directional {
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "unit_intensity")]
+        intensity: f32,
+    },
+    /// Shines along `direction`, fading out from `inner_angle` to `outer_angle` degrees off it
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default)]
+        inner_angle: f32,
+        outer_angle: f32,
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "unit_intensity")]
+        intensity: f32,
+    },
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn unit_intensity() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialFile {
+    name: String,
+    kind: Material,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshFile {
+    name: String,
+    source: MeshSource,
+    /// Lets the CPU BVH split triangles between nodes, adding up to this many references per
+    /// triangle. Worth it for meshes with long thin triangles.
+    #[serde(default)]
+    spatial_splits: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+enum MeshSource {
+    /// Two triangles spanning -1 to 1 on the XZ plane
+    Quad,
+    /// The unit cube from -1 to 1, two triangles per face
+    Cube,
+    Triangles {
+        positions: Vec<[f32; 3]>,
+        /// Without indices every three positions form a triangle
+        #[serde(default)]
+        indices: Option<Vec<u32>>,
+    },
+    /// Wavefront OBJ file, relative to the scene file. Every object in it becomes its own mesh.
+    Obj { path: String },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeFile {
+    name: String,
+    /// Has to be declared before this node
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    translation: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "unit_scale")]
+    scale: [f32; 3],
+    #[serde(default)]
+    animation: Option<Animation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstanceFile {
+    mesh: String,
+    /// Only optional for OBJ meshes, whose objects default to their MTL materials
+    #[serde(default)]
+    material: Option<String>,
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    translation: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "unit_scale")]
+    scale: [f32; 3],
+    #[serde(default)]
+    animation: Option<Animation>,
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.0; 3]
+}
+
+const SECTIONS: [&str; 6] = [
+    "camera",
+    "lights",
+    "materials",
+    "meshes",
+    "nodes",
+    "instances",
+];
+
+/// The 1-based line and column of byte `offset` in `source`
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+/// Finds the line and column of the `n`th `field: "value"` in the top level list `section`. The
+/// parser doesn't keep positions once deserialized, so this and `find_entry` are how validation
+/// errors point at what they are about.
+fn find_field(
+    source: &str,
+    section: &str,
+    field: &str,
+    value: &str,
+    n: usize,
+) -> Option<(usize, usize)> {
+    let start = source.find(&format!("{section}:"))?;
+    let end = SECTIONS
+        .iter()
+        .filter_map(|other| source[start + 1..].find(&format!("{other}:")))
+        .map(|offset| start + 1 + offset)
+        .min()
+        .unwrap_or(source.len());
+
+    let field = format!("{field}:");
+    let (offset, _) = source[..end]
+        .match_indices(&format!("\"{value}\""))
+        .filter(|&(offset, _)| offset > start && source[..offset].trim_end().ends_with(&field))
+        .nth(n)?;
+
+    Some(line_column(source, offset))
+}
+
+/// The offset of the first character at or after `offset` that isn't whitespace or in a comment
+fn skip_blank(source: &str, mut offset: usize) -> usize {
+    loop {
+        let rest = &source[offset..];
+        let trimmed = rest.trim_start();
+        offset += rest.len() - trimmed.len();
+        if !trimmed.starts_with("//") {
+            return offset;
+        }
+        offset += trimmed.find('\n').unwrap_or(trimmed.len());
+    }
+}
+
+/// Finds the byte range of entry `n` of the top level `section`. Sections that are a single entry
+/// rather than a list of them, like the camera, only have entry 0.
+fn find_entry(source: &str, section: &str, n: usize) -> Option<Range<usize>> {
+    let key = format!("{section}:");
+    let value = skip_blank(source, source.find(&key)? + key.len());
+    let bytes = source.as_bytes();
+    let is_list = bytes.get(value) == Some(&b'[');
+    if !is_list && n > 0 {
+        return None;
+    }
+
+    let mut index = 0;
+    let mut entry_start = if is_list { None } else { Some(value) };
+    let mut depth = 0;
+    let mut i = value;
+    while i < bytes.len() {
+        if entry_start.is_none() && depth == 1 {
+            i = skip_blank(source, i);
+            if bytes.get(i) == Some(&b']') {
+                return None;
+            }
+            entry_start = Some(i);
+        }
+
+        match bytes[i] {
+            b'"' => {
+                // Past the closing quote, skipping escaped ones
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i = skip_blank(source, i) - 1;
+            }
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    // The end of a single entry, or of a list whose last entry has no comma
+                    let end = if is_list { i } else { i + 1 };
+                    return entry_start.filter(|_| index == n).map(|start| start..end);
+                }
+            }
+            b',' if depth == usize::from(is_list) => {
+                if index == n {
+                    return entry_start.map(|start| start..i);
+                }
+                index += 1;
+                entry_start = None;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Where the value of the first `field` in `entry` starts, or where the entry starts when it leaves
+/// the field out
+fn find_value(source: &str, entry: Range<usize>, field: &str) -> usize {
+    let text = &source[entry.clone()];
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(field)
+        .find(|&(offset, _)| {
+            let before = text[..offset].chars().next_back();
+            let after = text[offset + field.len()..].trim_start();
+            !before.is_some_and(is_ident) && after.starts_with(':')
+        })
+        .map_or(entry.start, |(offset, _)| {
+            let colon = entry.start + offset + field.len();
+            skip_blank(source, colon + source[colon..].find(':').unwrap() + 1)
+        })
+}
+
+/// One of the meshes a mesh in the scene file was loaded into
+struct Part {
+    /// Index into [`SceneModel::meshes`]
+    mesh: usize,
+    /// Index into [`SceneModel::materials`] of the material the mesh file assigned to this part
+    material: Option<usize>,
+}
+
+/// A mesh without normals or UVs. Without indices every three positions form a triangle.
+fn triangles(name: &str, positions: Vec<f32>, indices: Option<Vec<u32>>) -> Mesh {
+    let num_vertices = positions.len() as u32 / 3;
+    Mesh {
+        name: name.to_string(),
+        positions,
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        indices: indices.unwrap_or_else(|| (0..num_vertices).collect()),
+        bvh: None,
+        spatial_splits: None,
+    }
+}
+
+/// The quad facing +Y, with UVs from (0, 0) at its -X, +Z corner to (1, 1) at its +X, -Z corner
+fn quad(name: &str) -> Mesh {
+    let mut mesh = triangles(name, QUAD_VTX.to_vec(), None);
+    mesh.normals = [0.0, 1.0, 0.0].repeat(QUAD_VTX.len() / 3);
+    mesh.uvs = QUAD_VTX
+        .chunks_exact(3)
+        .flat_map(|p| [(p[0] + 1.0) / 2.0, (1.0 - p[2]) / 2.0])
+        .collect();
+    mesh
+}
+
+/// The cube with four vertices of its own on each face, so every face is flat with UVs covering it
+/// from corner to corner. The triangles are the ones CUBE_IDX makes, in the same order.
+fn cube(name: &str) -> Mesh {
+    let corner = |i: u16| Vector3::from_column_slice(&CUBE_VTX[i as usize * 3..][..3]);
+
+    let mut mesh = triangles(name, Vec::new(), Some(Vec::new()));
+    for face in CUBE_IDX.chunks_exact(6) {
+        let mut corners = Vec::new();
+        for &i in face {
+            if !corners.contains(&i) {
+                corners.push(i);
+            }
+        }
+
+        // All four corners are on the side of the cube the face looks out of
+        let normal = corners.iter().map(|&i| corner(i)).sum::<Vector3<f32>>() / 4.0;
+        let axis = normal.iamax();
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        let first = mesh.positions.len() as u32 / 3;
+        for &i in &corners {
+            let p = corner(i);
+            mesh.positions.extend(p.iter());
+            mesh.normals.extend(normal.iter());
+            mesh.uvs.extend([(p[u] + 1.0) / 2.0, (1.0 - p[v]) / 2.0]);
+        }
+        mesh.indices.extend(
+            face.iter()
+                .map(|i| first + corners.iter().position(|c| c == i).unwrap() as u32),
+        );
+    }
+
+    mesh
+}
+
+/// Checks the positions are finite, the indices make whole triangles of existing vertices and the
+/// normals and UVs that are there go with the positions
+pub fn check_mesh(mesh: &Mesh) -> Result<(), String> {
+    if mesh.positions.iter().any(|c| !c.is_finite()) {
+        return Err(format!(
+            "Mesh {:?} has vertices that aren't finite",
+            mesh.name
+        ));
+    }
+
+    let num_vertices = mesh.positions.len() / 3;
+    for (what, values, per_vertex) in [("normals", &mesh.normals, 3), ("UVs", &mesh.uvs, 2)] {
+        if !values.is_empty() && values.len() != num_vertices * per_vertex {
+            return Err(format!(
+                "Mesh {:?} has {} {what} for {num_vertices} vertices",
+                mesh.name,
+                values.len() / per_vertex
+            ));
+        }
+
+        if values.iter().any(|c| !c.is_finite()) {
+            return Err(format!(
+                "Mesh {:?} has {what} that aren't finite",
+                mesh.name
+            ));
+        }
+    }
+
+    if mesh.indices.is_empty() {
+        return Err(format!("Mesh {:?} has no triangles", mesh.name));
+    }
+
+    if !mesh.indices.len().is_multiple_of(3) {
+        return Err(format!(
+            "Mesh {:?} has {} indices, which isn't a whole number of triangles",
+            mesh.name,
+            mesh.indices.len()
+        ));
+    }
+
+    if let Some(&index) = mesh.indices.iter().find(|&&i| i as usize >= num_vertices) {
+        return Err(format!(
+            "Mesh {:?} uses vertex {index} but only has {num_vertices} vertices",
+            mesh.name
+        ));
+    }
+
+    Ok(())
+}
+
+/// The names declared in each section, in declaration order
+struct Names {
+    meshes: Vec<String>,
+    materials: Vec<String>,
+    nodes: Vec<String>,
+}
+
+/// Entry `index` of the top level `section` of the scene file, for errors to point at
+#[derive(Clone, Copy)]
+struct Entry {
+    section: &'static str,
+    index: usize,
+}
+
+struct Validator<'a> {
+    file: &'a str,
+    source: &'a str,
+    /// Where paths in the scene file are relative to
+    dir: PathBuf,
+}
+
+impl Validator<'_> {
+    fn error(&self, position: Option<(usize, usize)>, message: String) -> SceneError {
+        SceneError {
+            file: self.file.to_string(),
+            position,
+            message,
+        }
+    }
+
+    fn error_at(
+        &self,
+        section: &str,
+        field: &str,
+        value: &str,
+        n: usize,
+        message: String,
+    ) -> SceneError {
+        let position = find_field(self.source, section, field, value, n);
+        self.error(position, message)
+    }
+
+    /// An error pointing at `field` of `entry`, or at the entry when it leaves the field out
+    fn error_in(&self, entry: Entry, field: &str, message: String) -> SceneError {
+        let position = find_entry(self.source, entry.section, entry.index)
+            .map(|range| line_column(self.source, find_value(self.source, range, field)));
+        self.error(position, message)
+    }
+
+    /// An error pointing at the start of a top level section
+    fn error_in_section(&self, section: &str, message: String) -> SceneError {
+        let position = self
+            .source
+            .find(&format!("{section}:"))
+            .map(|offset| line_column(self.source, offset));
+        self.error(position, message)
+    }
+
+    fn check_vector(
+        &self,
+        entry: Entry,
+        field: &str,
+        what: &str,
+        v: [f32; 3],
+    ) -> Result<Vector3<f32>, SceneError> {
+        if v.iter().all(|c| c.is_finite()) {
+            Ok(Vector3::from(v))
+        } else {
+            Err(self.error_in(entry, field, format!("{what} must be finite, got {v:?}")))
+        }
+    }
+
+    fn material(&self, i: usize, file: &MaterialFile) -> Result<Material, SceneError> {
+        let entry = Entry {
+            section: "materials",
+            index: i,
+        };
+        let name = &file.name;
+        match file.kind {
+            Material::Glass { ior, .. } if !(ior > 0.0 && ior.is_finite()) => Err(self.error_in(
+                entry,
+                "ior",
+                format!("Material {name:?} needs a finite, positive ior, got {ior}"),
+            )),
+            Material::Emissive { intensity, .. }
+                if !(intensity >= 0.0 && intensity.is_finite()) =>
+            {
+                Err(self.error_in(
+                    entry,
+                    "intensity",
+                    format!(
+                        "Material {name:?} needs a finite intensity that isn't negative, got \
+                         {intensity}"
+                    ),
+                ))
+            }
+            kind => Ok(kind),
+        }
+    }
+
+    fn light_direction(
+        &self,
+        entry: Entry,
+        direction: [f32; 3],
+    ) -> Result<Vector3<f32>, SceneError> {
+        let direction = self.check_vector(entry, "direction", "Light direction", direction)?;
+        if direction == Vector3::zeros() {
+            let message = "Light direction can't be zero".to_string();
+            return Err(self.error_in(entry, "direction", message));
+        }
+
+        Ok(direction.normalize())
+    }
+
+    fn light(&self, i: usize, file: LightFile) -> Result<Light, SceneError> {
+        let entry = Entry {
+            section: "lights",
+            index: i,
+        };
+        let (kind, color, intensity) = match file {
+            LightFile::Point {
+                position,
+                color,
+                intensity,
+            } => {
+                let position = self
+                    .check_vector(entry, "position", "Light position", position)?
+                    .into();
+                (LightKind::Point { position }, color, intensity)
+            }
+            LightFile::Directional {
+                direction,
+                color,
+                intensity,
+            } => {
+                let direction = self.light_direction(entry, direction)?;
+                (LightKind::Directional { direction }, color, intensity)
+            }
+            LightFile::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+                color,
+                intensity,
+            } => {
+                if !(0.0 <= inner_angle && inner_angle < outer_angle && outer_angle <= 90.0) {
+                    return Err(self.error_in(
+                        entry,
+                        "outer_angle",
+                        format!(
+                            "Spot light angles must go 0 <= inner_angle < outer_angle <= 90 \
+                             degrees, got {inner_angle} and {outer_angle}"
+                        ),
+                    ));
+                }
+
+                let kind = LightKind::Spot {
+                    position: self
+                        .check_vector(entry, "position", "Light position", position)?
+                        .into(),
+                    direction: self.light_direction(entry, direction)?,
+                    inner_angle: inner_angle.to_radians(),
+                    outer_angle: outer_angle.to_radians(),
+                };
+                (kind, color, intensity)
+            }
+        };
+
+        let color = self.check_vector(entry, "color", "Light color", color)?;
+        if color.iter().any(|&c| c < 0.0) {
+            return Err(self.error_in(
+                entry,
+                "color",
+                format!("Light color can't be negative, got {color:?}"),
+            ));
+        }
+        if !(intensity >= 0.0 && intensity.is_finite()) {
+            return Err(self.error_in(
+                entry,
+                "intensity",
+                format!("Light intensity must be finite and not negative, got {intensity}"),
+            ));
+        }
+
+        Ok(Light {
+            kind,
+            color,
+            intensity,
+        })
+    }
+
+    fn camera(&self, file: CameraFile) -> Result<Camera, SceneError> {
+        let entry = Entry {
+            section: "camera",
+            index: 0,
+        };
+        let position = self.check_vector(entry, "position", "Camera position", file.position)?;
+        let mut camera = Camera::new(position.into());
+        if let Some(target) = file.target {
+            let target = self.check_vector(entry, "target", "Camera target", target)?;
+            let direction = target - camera.position.coords;
+            if direction == Vector3::zeros() {
+                let message = "Camera target can't be where the camera is".to_string();
+                return Err(self.error_in(entry, "target", message));
+            }
+            camera.look_to(&direction, &Vector3::y());
+        }
+
+        if !(file.fov > 0.0 && file.fov < 180.0) {
+            return Err(self.error_in(
+                entry,
+                "fov",
+                format!(
+                    "Camera field of view must be between 0 and 180 degrees, got {}",
+                    file.fov
+                ),
+            ));
+        }
+        camera.fov_y = file.fov.to_radians();
+
+        camera.near = file.near.unwrap_or(camera.near);
+        camera.far = file.far.unwrap_or(camera.far);
+        if !(camera.near > 0.0 && camera.near < camera.far && camera.far.is_finite()) {
+            return Err(self.error_in(
+                entry,
+                "near",
+                format!(
+                    "Camera near and far planes must be in front of the camera and in order, got \
+                     {} and {}",
+                    camera.near, camera.far
+                ),
+            ));
+        }
+
+        Ok(camera)
+    }
+
+    /// Checks the names declared in `section` are unique, returning them in declaration order
+    fn check_names<'n>(
+        &self,
+        section: &str,
+        names: impl Iterator<Item = &'n String>,
+    ) -> Result<Vec<String>, SceneError> {
+        let mut seen = HashSet::new();
+        let mut ordered = Vec::new();
+        for name in names {
+            if !seen.insert(name) {
+                let message = format!("Duplicate name {name:?} in {section}");
+                return Err(self.error_at(section, "name", name, 1, message));
+            }
+            ordered.push(name.clone());
+        }
+
+        Ok(ordered)
+    }
+
+    fn mesh(
+        &self,
+        mesh: MeshFile,
+        meshes: &mut Vec<Mesh>,
+        materials: &mut Vec<Material>,
+        sources: &mut Vec<PathBuf>,
+    ) -> Result<Vec<Part>, SceneError> {
+        let invalid = |message: String| self.error_at("meshes", "name", &mesh.name, 0, message);
+        let single = |positions, indices| vec![(triangles(&mesh.name, positions, indices), None)];
+
+        let spatial_splits = mesh.spatial_splits;
+        if let Some(budget) = spatial_splits.filter(|b| !(b.is_finite() && *b >= 0.0)) {
+            return Err(invalid(format!(
+                "Mesh {:?} can't add {budget} references per triangle with spatial splits",
+                mesh.name
+            )));
+        }
+
+        let loaded = match mesh.source {
+            MeshSource::Quad => vec![(quad(&mesh.name), None)],
+            MeshSource::Cube => vec![(cube(&mesh.name), None)],
+            MeshSource::Triangles { positions, indices } => single(positions.concat(), indices),
+            MeshSource::Obj { path } => {
+                let obj = obj::load(&self.dir.join(&path)).map_err(|error| {
+                    invalid(format!(
+                        "Couldn't load mesh {:?} from {path}: {error}",
+                        mesh.name
+                    ))
+                })?;
+
+                if obj.parts.is_empty() {
+                    return Err(invalid(format!("Mesh {:?} has no triangles", mesh.name)));
+                }
+
+                // The MTL materials go after the ones declared in the scene file
+                let first_material = materials.len();
+                materials.extend(obj.materials);
+                sources.extend(obj.sources);
+
+                obj.parts
+                    .into_iter()
+                    .map(|part| {
+                        let part_mesh = Mesh {
+                            name: format!("{}/{}", mesh.name, part.mesh.name),
+                            ..part.mesh
+                        };
+                        (part_mesh, part.material.map(|m| first_material + m))
+                    })
+                    .collect()
+            }
+        };
+
+        loaded
+            .into_iter()
+            .map(|(part_mesh, material)| {
+                check_mesh(&part_mesh).map_err(invalid)?;
+                meshes.push(Mesh {
+                    spatial_splits,
+                    ..part_mesh
+                });
+                Ok(Part {
+                    mesh: meshes.len() - 1,
+                    material,
+                })
+            })
+            .collect()
+    }
+
+    fn transform(
+        &self,
+        entry: Entry,
+        what: impl Fn(&str) -> String,
+        translation: [f32; 3],
+        rotation: [f32; 3],
+        scale: [f32; 3],
+        animation: Option<Animation>,
+    ) -> Result<Transform, SceneError> {
+        let check = |field, name, v| self.check_vector(entry, field, &what(name), v);
+        let translation = check("translation", "translation", translation)?;
+        let rotation = check("rotation", "rotation", rotation)?;
+        let scale_vector = check("scale", "scale", scale)?;
+        if scale_vector.iter().any(|&s| s == 0.0) {
+            let message = format!("{} can't be zero, got {scale:?}", what("scale"));
+            return Err(self.error_in(entry, "scale", message));
+        }
+
+        match animation {
+            Some(Animation::Spin { rate }) => {
+                check("rate", "spin rate", rate)?;
+            }
+            Some(Animation::Sway {
+                amplitude,
+                frequency,
+            }) => {
+                check("amplitude", "sway amplitude", amplitude)?;
+                check("frequency", "sway frequency", [frequency; 3])?;
+            }
+            None => (),
+        }
+
+        Ok(Transform {
+            translation,
+            rotation,
+            scale: scale_vector,
+            animation,
+        })
+    }
+
+    fn node(&self, i: usize, node: NodeFile, names: &Names) -> Result<Node, SceneError> {
+        let parent = match &node.parent {
+            Some(parent) => {
+                let index = names
+                    .nodes
+                    .iter()
+                    .position(|n| n == parent)
+                    .ok_or_else(|| {
+                        let message = format!("Node {:?} has unknown parent {parent:?}", node.name);
+                        self.error_at("nodes", "parent", parent, 0, message)
+                    })?;
+
+                // Declaring parents first keeps cycles out and lets world matrices be resolved in
+                // a single pass
+                if index >= i {
+                    let message = format!(
+                        "Node {:?} has to be declared after its parent {parent:?}",
+                        node.name
+                    );
+                    return Err(self.error_at("nodes", "name", &node.name, 0, message));
+                }
+
+                Some(index)
+            }
+            None => None,
+        };
+
+        let entry = Entry {
+            section: "nodes",
+            index: i,
+        };
+        let what = |field: &str| format!("The {field} of node {:?}", node.name);
+        let transform = self.transform(
+            entry,
+            what,
+            node.translation,
+            node.rotation,
+            node.scale,
+            node.animation,
+        )?;
+
+        Ok(Node { parent, transform })
+    }
+
+    fn instance(
+        &self,
+        i: usize,
+        instance: InstanceFile,
+        parts: &[Vec<Part>],
+        names: &Names,
+        nodes: &mut Vec<Node>,
+    ) -> Result<Vec<Instance>, SceneError> {
+        let lookup = |names: &[String], field: &str, name: &String| {
+            names.iter().position(|n| n == name).ok_or_else(|| {
+                // An unknown name is only ever written in the instances, so the first one is this
+                let message = format!("Instance {i} uses unknown {field} {name:?}");
+                self.error_at("instances", field, name, 0, message)
+            })
+        };
+
+        let mesh = lookup(&names.meshes, "mesh", &instance.mesh)?;
+        let material = match &instance.material {
+            Some(material) => Some(lookup(&names.materials, "material", material)?),
+            None => None,
+        };
+        let parent = match &instance.parent {
+            Some(parent) => Some(lookup(&names.nodes, "parent", parent)?),
+            None => None,
+        };
+
+        let entry = Entry {
+            section: "instances",
+            index: i,
+        };
+        let what = |field: &str| format!("The {field} of instance {i}");
+        let transform = self.transform(
+            entry,
+            what,
+            instance.translation,
+            instance.rotation,
+            instance.scale,
+            instance.animation,
+        )?;
+
+        // Every instance gets a node of its own, shared by all the objects of an OBJ mesh
+        nodes.push(Node { parent, transform });
+        let node = nodes.len() - 1;
+
+        parts[mesh]
+            .iter()
+            .map(|part| {
+                let material = material.or(part.material).ok_or_else(|| {
+                    let message = format!(
+                        "Instance {i} needs a material, mesh {:?} doesn't bring its own",
+                        instance.mesh
+                    );
+                    self.error_in(entry, "mesh", message)
+                })?;
+
+                Ok(Instance {
+                    mesh: part.mesh,
+                    material,
+                    node,
+                })
+            })
+            .collect()
+    }
+
+    fn scene(&self, scene: SceneFile) -> Result<SceneModel, SceneError> {
+        let names = Names {
+            meshes: self.check_names("meshes", scene.meshes.iter().map(|m| &m.name))?,
+            materials: self.check_names("materials", scene.materials.iter().map(|m| &m.name))?,
+            nodes: self.check_names("nodes", scene.nodes.iter().map(|n| &n.name))?,
+        };
+
+        let mut materials = scene
+            .materials
+            .iter()
+            .enumerate()
+            .map(|(i, m)| self.material(i, m))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut meshes = Vec::new();
+        let mut sources = Vec::new();
+        let parts = scene
+            .meshes
+            .into_iter()
+            .map(|mesh| self.mesh(mesh, &mut meshes, &mut materials, &mut sources))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut nodes = scene
+            .nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| self.node(i, node, &names))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let instances = scene
+            .instances
+            .into_iter()
+            .enumerate()
+            .map(|(i, instance)| self.instance(i, instance, &parts, &names, &mut nodes))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        // Instances find their material by their InstanceID
+        let max_materials = MAX_INSTANCE_ID as usize + 1;
+        if materials.len() > max_materials {
+            return Err(self.error_in_section(
+                "materials",
+                format!("Scenes can't have more than {max_materials} materials"),
+            ));
+        }
+
+        if scene.lights.is_empty() {
+            let message = "Scenes need at least one light".to_string();
+            return Err(self.error_in_section("lights", message));
+        }
+
+        let lights = scene
+            .lights
+            .into_iter()
+            .enumerate()
+            .map(|(i, light)| self.light(i, light))
+            .collect::<Result<_, _>>()?;
+
+        let camera = self.camera(scene.camera)?;
+
+        Ok(SceneModel {
+            camera,
+            lights,
+            materials,
+            meshes,
+            nodes,
+            instances,
+            warnings: Vec::new(),
+            sources,
+        })
+    }
+}
+
+impl SceneModel {
+    /// Parses and validates a scene, `file` is used in error messages and to find the files the
+    /// scene refers to. Only the files it refers to end up in the sources.
+    pub fn parse(file: &str, source: &str) -> Result<Self, SceneError> {
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        let scene: SceneFile = options.from_str(source).map_err(|error| SceneError {
+            file: file.to_string(),
+            position: Some((error.position.line, error.position.col)),
+            message: error.code.to_string(),
+        })?;
+
+        let dir = Path::new(file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        Validator { file, source, dir }.scene(scene)
+    }
+
+    /// Loads a scene file, or a glTF file when the extension is .gltf or .glb
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+
+        if let Some("gltf" | "glb") = path.extension().and_then(|e| e.to_str()) {
+            return gltf_import::load(path).map_err(|message| SceneError {
+                file,
+                position: None,
+                message,
+            });
+        }
+
+        let source = std::fs::read_to_string(path).map_err(|error| SceneError {
+            file: file.clone(),
+            position: None,
+            message: error.to_string(),
+        })?;
+
+        let mut scene = Self::parse(&file, &source)?;
+        scene.sources.insert(0, path.to_path_buf());
+        Ok(scene)
+    }
+
+    /// The built in scene from scenes/default.ron
+    pub fn default_scene() -> Self {
+        Self::parse("scenes/default.ron", DEFAULT_SCENE).expect("The default scene is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every section with a couple of entries, and a comment with brackets in it to skip over
+    const SCENE: &str = r#"Scene(
+    camera: (position: (0.0, 1.0, -5.0)),
+    lights: [
+        // Straight down (like noon)
+        Directional(direction: (0.0, -1.0, 0.0)),
+        Point(position: (0.0, 3.0, 0.0), intensity: 2.0),
+    ],
+    materials: [
+        (name: "floor", kind: Checker),
+        (name: "glass", kind: Glass(ior: 1.5)),
+    ],
+    meshes: [
+        (name: "quad", source: Quad),
+        (name: "cube", source: Cube),
+    ],
+    nodes: [
+        (name: "stand", translation: (0.0, 1.0, 0.0)),
+    ],
+    instances: [
+        (mesh: "quad", material: "floor", scale: (5.0, 5.0, 5.0)),
+        (mesh: "cube", material: "glass", parent: "stand"),
+    ],
+)"#;
+
+    /// The position of the error in SCENE with `from` replaced by `to`
+    fn error_position(from: &str, to: &str) -> Option<(usize, usize)> {
+        assert!(SCENE.contains(from), "{from:?} isn't in the scene");
+        let source = SCENE.replace(from, to);
+        SceneModel::parse("test.ron", &source)
+            .err()
+            .unwrap()
+            .position
+    }
+
+    #[test]
+    fn scenes_parse() {
+        let scene = SceneModel::parse("test.ron", SCENE).unwrap();
+        assert_eq!(scene.lights.len(), 2);
+        assert_eq!(
+            scene.materials[1],
+            Material::Glass {
+                color: [1.0; 3],
+                ior: 1.5
+            }
+        );
+        assert_eq!(scene.meshes.len(), 2);
+        // Every instance gets a node of its own after the declared ones
+        assert_eq!(scene.nodes.len(), 3);
+        assert_eq!(scene.nodes[2].parent, Some(0));
+        assert_eq!(scene.instances[1].node, 2);
+
+        for (file, source) in [
+            (
+                "scenes/materials.ron",
+                include_str!("../scenes/materials.ron"),
+            ),
+            (
+                "scenes/mirror_stand.ron",
+                include_str!("../scenes/mirror_stand.ron"),
+            ),
+        ] {
+            SceneModel::parse(file, source).unwrap();
+        }
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_problem() {
+        let missing_comma = error_position(r#""cube", source"#, r#""cube" source"#);
+        assert_eq!(missing_comma, Some((14, 23)));
+
+        let unknown_field = error_position("source: Quad", "source: Quad, size: 2");
+        assert_eq!(unknown_field.map(|(line, _)| line), Some(13));
+    }
+
+    #[test]
+    fn validation_errors_point_at_the_field() {
+        let zero_scale = error_position("scale: (5.0, 5.0, 5.0)", "scale: (5.0, 0.0, 5.0)");
+        assert_eq!(zero_scale, Some((20, 50)));
+
+        // Nodes are found by position as well as instances
+        let node = error_position("translation: (0.0, 1.0, 0.0)", "scale: (0.0, 1.0, 1.0)");
+        assert_eq!(node, Some((17, 32)));
+
+        // Past the comment in the lights
+        let direction = error_position("(0.0, -1.0, 0.0)", "(0.0, 0.0, 0.0)");
+        assert_eq!(direction, Some((5, 32)));
+        let intensity = error_position("intensity: 2.0", "intensity: -2.0");
+        assert_eq!(intensity, Some((6, 53)));
+
+        assert_eq!(error_position("ior: 1.5", "ior: 0.0"), Some((10, 42)));
+
+        let duplicate = error_position(r#""cube", source"#, r#""quad", source"#);
+        assert_eq!(duplicate, Some((14, 16)));
+    }
+
+    #[test]
+    fn errors_about_missing_fields_point_at_their_entry() {
+        // The near plane is left at its default
+        let far = error_position("-5.0))", "-5.0), far: -1.0)");
+        assert_eq!(far, Some((2, 13)));
+
+        let lights = &SCENE[SCENE.find("lights:").unwrap()..SCENE.find("materials:").unwrap()];
+        let no_lights = error_position(lights, "lights: [],\n    ");
+        assert_eq!(no_lights, Some((3, 5)));
+
+        let error = SceneModel::parse("test.ron", &SCENE.replace(lights, "lights: [],\n    "));
+        assert_eq!(
+            error.err().unwrap().to_string(),
+            "test.ron:3:5: Scenes need at least one light"
+        );
+    }
+}
@@ -1,15 +1,79 @@
 struct Payload
 {
     float3 color;
-    bool allowReflection;
+    // Mirror and glass bounces on the way to this ray
+    uint depth;
     bool missed;
 };
 
 RaytracingAccelerationStructure scene : register(t0, space0);
+
+// See PackedMaterial in material.rs
+struct Material
+{
+    // Diffuse color, glass tint or emitted light times its intensity
+    float3 color;
+    uint type;
+    // Glass's index of refraction
+    float ior;
+};
+
+// Indexed by InstanceID()
+StructuredBuffer<Material> materials : register(t1, space0);
+
+// See PackedLight in light.rs
+struct Light
+{
+    float3 position;
+    uint type;
+    // The unit vector the light travels along
+    float3 direction;
+    float cosOuter;
+    // Already scaled by the intensity
+    float3 color;
+    float cosInner;
+};
+
+StructuredBuffer<Light> lights : register(t2, space0);
+
+// Every mesh's normals and UVs, see PackedAttributes in attributes.rs. attributeIndices picks out a
+// triangle's vertices, starting from where its instance's mesh begins in instanceFirstIndices.
+StructuredBuffer<uint> attributeIndices : register(t3, space0);
+StructuredBuffer<float3> normals : register(t4, space0);
+StructuredBuffer<float2> uvs : register(t5, space0);
+// Indexed by InstanceIndex()
+StructuredBuffer<uint> instanceFirstIndices : register(t6, space0);
 RWTexture2D<float4> outputTexture : register(u0);
 
-static const float3 camera = float3(0, 1.5, -7);
-static const float3 light = float3(0, 200, 0);
+// Root constants, see CameraConstants in camera.rs. cameraForward doesn't fit in the rest of
+// cameraUp's register, so it starts the next one.
+cbuffer CameraConstants : register(b0)
+{
+    float3 cameraPosition;
+    float cameraNear;
+    // From the centre of the image to its right and top edges, one unit along cameraForward
+    float3 cameraRight;
+    float cameraFar;
+    float3 cameraUp;
+    float3 cameraForward;
+};
+
+static const uint LIGHT_POINT = 0;
+static const uint LIGHT_DIRECTIONAL = 1;
+static const uint LIGHT_SPOT = 2;
+
+static const uint MATERIAL_FACES = 0;
+static const uint MATERIAL_MIRROR = 1;
+static const uint MATERIAL_CHECKER = 2;
+static const uint MATERIAL_DIFFUSE = 3;
+static const uint MATERIAL_GLASS = 4;
+static const uint MATERIAL_EMISSIVE = 5;
+
+// Rays that would bounce off mirrors or through glass more often than this come out black. Every
+// bounce is another level of TraceRay recursion, and the primary and shadow rays need two more of
+// the pipeline's MaxTraceRecursionDepth.
+static const uint MAX_BOUNCES = 6;
+
 static const float3 skyTop = float3(0.24, 0.44, 0.72);
 static const float3 skyBottom = float3(0.75, 0.86, 0.93);
 
@@ -18,20 +82,19 @@ void RayGeneration() {
     uint2 idx = DispatchRaysIndex().xy;
     float2 size = DispatchRaysDimensions().xy;
 
-    float2 uv = idx / size;
-    // 1.8 is an arbitrary FOV value
-    float3 target = float3((uv.x * 2 - 1) * 1.8 * (size.x / size.y),
-                               (1 - uv.y) * 4 - 2 + camera.y,
-                               0);
+    // Through the pixel's centre, from -1 at the left and top edges to 1 at the right and bottom.
+    // Rows count down the image, so y is flipped to go up.
+    float2 ndc = (idx + 0.5) / size * 2 - 1;
 
     RayDesc ray;
-    ray.Origin = camera;
-    ray.Direction = target - camera;
-    ray.TMin = 0.001;
-    ray.TMax = 1000;
+    ray.Origin = cameraPosition;
+    ray.Direction = cameraForward + ndc.x * cameraRight - ndc.y * cameraUp;
+    ray.TMin = cameraNear;
+    ray.TMax = cameraFar;
 
     Payload payload;
-    payload.allowReflection = true;
+    payload.color = 0;
+    payload.depth = 0;
     payload.missed = false;
 
     TraceRay(scene, RAY_FLAG_NONE, 0xFF, 0, 0, 0, ray, payload);
@@ -46,28 +109,149 @@ void Miss(inout Payload payload) {
     payload.missed = true;
 }
 
-void HitCube(inout Payload payload, float2 uv) {
-    uint tri = PrimitiveIndex();
-    tri /= 2;
-    float3 normal = (tri.xxx % 3 == uint3(0, 1, 2)) * (tri < 3 ? -1 : 1);
-    float3 worldNormal = normalize(mul(normal, (float3x3)ObjectToWorld4x3()));
-    float3 color = abs(normal) / 3 + 0.5;
-    if (uv.x < 0.03 || uv.y < 0.03) {
+struct LightSample
+{
+    // Unit vector towards the light
+    float3 direction;
+    float distance;
+    float3 radiance;
+};
+
+LightSample SampleLight(Light light, float3 pos) {
+    LightSample s;
+    if (light.type == LIGHT_DIRECTIONAL) {
+        s.direction = -light.direction;
+        s.distance = 1000;
+        s.radiance = light.color;
+        return s;
+    }
+
+    float3 toLight = light.position - pos;
+    s.distance = length(toLight);
+    s.direction = toLight / s.distance;
+    s.radiance = light.color / (s.distance * s.distance);
+    if (light.type == LIGHT_SPOT) {
+        s.radiance *= smoothstep(light.cosOuter, light.cosInner, dot(-s.direction, light.direction));
+    }
+
+    return s;
+}
+
+bool InShadow(float3 pos, LightSample s) {
+    RayDesc shadowRay;
+    shadowRay.Origin = pos;
+    shadowRay.Direction = s.direction;
+    shadowRay.TMin = 0.001;
+    shadowRay.TMax = s.distance;
+
+    // Only Miss runs, so any hit will do and nothing gets shaded
+    Payload shadow;
+    shadow.color = 0;
+    shadow.depth = 0;
+    shadow.missed = false;
+    TraceRay(scene, RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH | RAY_FLAG_SKIP_CLOSEST_HIT_SHADER, 0xFF,
+             0, 0, 0, shadowRay, shadow);
+    return !shadow.missed;
+}
+
+// The light from every light that reaches pos on a surface facing normal, scaled by the cosine of
+// the angle it comes in at
+float3 DirectLight(float3 pos, float3 normal) {
+    uint count, stride;
+    lights.GetDimensions(count, stride);
+
+    float3 total = 0;
+    for (uint i = 0; i < count; i++) {
+        LightSample s = SampleLight(lights[i], pos);
+        float cosine = saturate(dot(normal, s.direction));
+        if (cosine > 0 && any(s.radiance > 0) && !InShadow(pos, s)) {
+            total += s.radiance * cosine;
+        }
+    }
+
+    return total;
+}
+
+// What a surface of color looks like with light arriving at it. It's fully lit by a white light of
+// intensity 1 shining straight at it and half lit in shadow.
+float3 Diffuse(float3 color, float3 light) {
+    return color * (light + 1) / 2;
+}
+
+// The world space checkerboard in one unit squares
+float3 Checker(float3 pos) {
+    bool2 pattern = frac(pos.xz) > 0.5;
+    return pattern.x ^ pattern.y ? 0.6.xxx : 0.4.xxx;
+}
+
+// Schlick's approximation of how much light is reflected rather than refracted when it arrives at
+// cosine to the normal, with eta as in refract. Leaving the denser side goes by the angle on the far
+// side, so it's 1 past the critical angle.
+float Fresnel(float cosine, float eta) {
+    float r0 = (1 - eta) / (1 + eta);
+    r0 *= r0;
+    if (eta > 1) {
+        float k = 1 - eta * eta * (1 - cosine * cosine);
+        if (k < 0) {
+            return 1;
+        }
+        cosine = sqrt(k);
+    }
+
+    return r0 + (1 - r0) * pow(1 - cosine, 5);
+}
+
+// The mesh's attributes where the ray hit it
+struct Attributes
+{
+    // Unit length, in object space
+    float3 normal;
+    float2 uv;
+};
+
+// Blends the hit triangle's vertex attributes with the hit barycentrics, which weight its second and
+// third vertices and leave the rest to the first
+Attributes InterpolateAttributes(float2 barycentrics) {
+    uint first = instanceFirstIndices[InstanceIndex()] + PrimitiveIndex() * 3;
+    float3 weights = float3(1 - barycentrics.x - barycentrics.y, barycentrics.x, barycentrics.y);
+
+    Attributes attributes;
+    attributes.normal = 0;
+    attributes.uv = 0;
+    for (uint i = 0; i < 3; i++) {
+        uint vertex = attributeIndices[first + i];
+        attributes.normal += normals[vertex] * weights[i];
+        attributes.uv += uvs[vertex] * weights[i];
+    }
+
+    attributes.normal = normalize(attributes.normal);
+    return attributes;
+}
+
+// The unit world space normal where attributes were interpolated
+float3 WorldNormal(Attributes attributes) {
+    return normalize(mul(attributes.normal, (float3x3)WorldToObject3x4()));
+}
+
+void HitFaces(inout Payload payload, Attributes attributes) {
+    float3 pos = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
+    float3 color = abs(attributes.normal) / 3 + 0.5;
+    if (any(attributes.uv < 0.03) || any(attributes.uv > 0.97)) {
         color = 0.25.xxx;
     }
 
-    color *= saturate(dot(worldNormal, normalize(light))) + 0.33;
+    color *= DirectLight(pos, WorldNormal(attributes)) + 0.33;
     payload.color = color;
 }
 
-void HitMirror(inout Payload payload, float2 uv) {
-    if (!payload.allowReflection) {
+void HitMirror(inout Payload payload, Attributes attributes) {
+    if (payload.depth == MAX_BOUNCES) {
+        payload.color = 0;
         return;
     }
 
     float3 pos = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
-    float3 normal = normalize(mul(float3(0, 1, 0), (float3x3)ObjectToWorld4x3()));
-    float3 reflected = reflect(normalize(WorldRayDirection()), normal);
+    float3 reflected = reflect(normalize(WorldRayDirection()), WorldNormal(attributes));
 
     RayDesc ray;
     ray.Origin = pos;
@@ -75,37 +259,70 @@ void HitMirror(inout Payload payload, float2 uv) {
     ray.TMin = 0.001;
     ray.TMax = 1000;
 
-    payload.allowReflection = false;
+    payload.depth++;
     TraceRay(scene, RAY_FLAG_NONE, 0xFF, 0, 0, 0, ray, payload);
 }
 
-void HitFloor(inout Payload payload, float2 uv) {
+void HitChecker(inout Payload payload, Attributes attributes) {
     float3 pos = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
-    bool2 pattern = frac(pos.xz) > 0.5;
-    payload.color = pattern.x ^ pattern.y ? 0.6.xxx : 0.4.xxx;
+    payload.color = Diffuse(Checker(pos), DirectLight(pos, WorldNormal(attributes)));
+}
 
-    RayDesc shadowRay;
-    shadowRay.Origin = pos;
-    shadowRay.Direction = light - pos;
-    shadowRay.TMin = 0.001;
-    shadowRay.TMax = 1;
+void HitDiffuse(inout Payload payload, Attributes attributes, Material material) {
+    float3 pos = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
+    payload.color = Diffuse(material.color, DirectLight(pos, WorldNormal(attributes)));
+}
 
-    Payload shadow;
-    shadow.allowReflection = false;
-    shadow.missed = false;
-    TraceRay(scene, RAY_FLAG_NONE, 0xFF, 0, 0, 0, shadowRay, shadow);
+void HitGlass(inout Payload payload, Attributes attributes, Material material) {
+    if (payload.depth == MAX_BOUNCES) {
+        payload.color = 0;
+        return;
+    }
+
+    float3 pos = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
+    float3 direction = normalize(WorldRayDirection());
+    float3 normal = WorldNormal(attributes);
+    float cosine = -dot(direction, normal);
+    float eta = 1 / material.ior;
+    // Leaving the glass
+    if (cosine < 0) {
+        normal = -normal;
+        cosine = -cosine;
+        eta = material.ior;
+    }
+
+    float reflectance = Fresnel(cosine, eta);
+    payload.depth++;
+    Payload reflection = payload;
+    Payload transmission = payload;
+
+    RayDesc ray;
+    ray.Origin = pos;
+    ray.Direction = reflect(direction, normal);
+    ray.TMin = 0.001;
+    ray.TMax = 1000;
+    TraceRay(scene, RAY_FLAG_NONE, 0xFF, 0, 0, 0, ray, reflection);
 
-    if (!shadow.missed) {
-        payload.color /= 2;
+    // Zero when it's all reflected
+    ray.Direction = refract(direction, normal, eta);
+    if (any(ray.Direction != 0)) {
+        TraceRay(scene, RAY_FLAG_NONE, 0xFF, 0, 0, 0, ray, transmission);
     }
+
+    payload.color = lerp(transmission.color * material.color, reflection.color, reflectance);
 }
 
 [shader("closesthit")]
 void ClosestHit(inout Payload payload, BuiltInTriangleIntersectionAttributes attrib) {
-    switch (InstanceID()) {
-        case 0: HitCube(payload, attrib.barycentrics); break;
-        case 1: HitMirror(payload, attrib.barycentrics); break;
-        case 2: HitFloor(payload, attrib.barycentrics); break;
+    Material material = materials[InstanceID()];
+    Attributes attributes = InterpolateAttributes(attrib.barycentrics);
+    switch (material.type) {
+        case MATERIAL_FACES: HitFaces(payload, attributes); break;
+        case MATERIAL_MIRROR: HitMirror(payload, attributes); break;
+        case MATERIAL_CHECKER: HitChecker(payload, attributes); break;
+        case MATERIAL_DIFFUSE: HitDiffuse(payload, attributes, material); break;
+        case MATERIAL_GLASS: HitGlass(payload, attributes, material); break;
+        case MATERIAL_EMISSIVE: payload.color = material.color; break;
         default: payload.color = float3(1, 0, 1); break;
     }
-}
\ No newline at end of file
+}
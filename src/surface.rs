@@ -1,13 +1,21 @@
 use crate::device_interface::DeviceInterface;
+use crate::image::Image;
 use crate::imports::*;
-use crate::resource::NO_AA;
+use crate::resource::{UploadResource, NO_AA};
 use std::cmp::max;
 use windows::Win32::UI::WindowsAndMessaging::GetClientRect;
 
-pub struct Surface {
-    pub target: ID3D12Resource,
+/// The window and swap chain a surface presents to
+struct SwapChain {
     window: HWND,
     swap_chain: IDXGISwapChain4,
+}
+
+/// A render target for the ray tracing output. Offscreen surfaces have no swap chain and use a
+/// float format so they can be read back without losing precision.
+pub struct Surface {
+    pub target: ID3D12Resource,
+    swap_chain: Option<SwapChain>,
     uav_heap: ID3D12DescriptorHeap,
 }
 
@@ -49,13 +57,23 @@ fn internal_resize(
 
     unsafe { swap_chain.ResizeBuffers(0, width, height, DXGI_FORMAT_UNKNOWN, 0)? };
 
+    create_target(interface, width, height, DXGI_FORMAT_R8G8B8A8_UNORM, uav_heap)
+}
+
+fn create_target(
+    interface: &DeviceInterface,
+    width: u32,
+    height: u32,
+    format: DXGI_FORMAT,
+    uav_heap: &ID3D12DescriptorHeap,
+) -> Result<ID3D12Resource> {
     let rt_desc = D3D12_RESOURCE_DESC {
         Dimension: D3D12_RESOURCE_DIMENSION_TEXTURE2D,
         Width: width as u64,
         Height: height,
         DepthOrArraySize: 1,
         MipLevels: 1,
-        Format: DXGI_FORMAT_R8G8B8A8_UNORM,
+        Format: format,
         SampleDesc: *NO_AA,
         Flags: D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
         ..Default::default()
@@ -85,7 +103,7 @@ fn internal_resize(
     let render_target: ID3D12Resource = render_target.unwrap();
 
     let uav_desc = D3D12_UNORDERED_ACCESS_VIEW_DESC {
-        Format: DXGI_FORMAT_R8G8B8A8_UNORM,
+        Format: format,
         ViewDimension: D3D12_UAV_DIMENSION_TEXTURE2D,
         ..Default::default()
     };
@@ -102,6 +120,17 @@ fn internal_resize(
     Ok(render_target)
 }
 
+fn create_uav_heap(interface: &DeviceInterface) -> Result<ID3D12DescriptorHeap> {
+    let uav_heap_desc = D3D12_DESCRIPTOR_HEAP_DESC {
+        Type: D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV,
+        NumDescriptors: 1,
+        Flags: D3D12_DESCRIPTOR_HEAP_FLAG_SHADER_VISIBLE,
+        ..Default::default()
+    };
+
+    unsafe { interface.device.CreateDescriptorHeap(&uav_heap_desc) }
+}
+
 impl Surface {
     pub fn from_handle(interface: &DeviceInterface, window: HWND) -> Result<Self> {
         let factory: IDXGIFactory2 = if cfg!(debug_assertions) {
@@ -124,26 +153,46 @@ impl Surface {
                 .cast()?
         };
 
-        let uav_heap_desc = D3D12_DESCRIPTOR_HEAP_DESC {
-            Type: D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV,
-            NumDescriptors: 1,
-            Flags: D3D12_DESCRIPTOR_HEAP_FLAG_SHADER_VISIBLE,
-            ..Default::default()
-        };
-
-        let uav_heap = unsafe { interface.device.CreateDescriptorHeap(&uav_heap_desc)? };
+        let uav_heap = create_uav_heap(interface)?;
         let target = internal_resize(interface, window, &swap_chain, &uav_heap)?;
 
         Ok(Self {
             target,
-            window,
-            swap_chain,
+            swap_chain: Some(SwapChain { window, swap_chain }),
             uav_heap,
         })
     }
 
-    pub fn resize(&mut self, interface: &DeviceInterface) -> Result<()> {
-        self.target = internal_resize(interface, self.window, &self.swap_chain, &self.uav_heap)?;
+    pub fn offscreen(interface: &DeviceInterface, width: u32, height: u32) -> Result<Self> {
+        let uav_heap = create_uav_heap(interface)?;
+        let target = create_target(
+            interface,
+            width,
+            height,
+            DXGI_FORMAT_R32G32B32A32_FLOAT,
+            &uav_heap,
+        )?;
+
+        Ok(Self {
+            target,
+            swap_chain: None,
+            uav_heap,
+        })
+    }
+
+    /// Windowed surfaces take their size from the window, offscreen ones use `width` and `height`
+    pub fn resize(&mut self, interface: &DeviceInterface, width: u32, height: u32) -> Result<()> {
+        self.target = match &self.swap_chain {
+            Some(SwapChain { window, swap_chain }) => {
+                internal_resize(interface, *window, swap_chain, &self.uav_heap)?
+            }
+            None => {
+                interface.wait_for_gpu()?;
+                let format = unsafe { self.target.GetDesc() }.Format;
+                create_target(interface, width, height, format, &self.uav_heap)?
+            }
+        };
+
         Ok(())
     }
 
@@ -158,12 +207,17 @@ impl Surface {
     }
 
     pub fn present(&self, interface: &DeviceInterface) -> Result<()> {
-        let command_list = &interface.command_list;
-        let back_buffer: ID3D12Resource = unsafe {
-            self.swap_chain
-                .GetBuffer(self.swap_chain.GetCurrentBackBufferIndex())?
+        let Some(SwapChain { swap_chain, .. }) = &self.swap_chain else {
+            return Err(Error::new(
+                E_UNEXPECTED,
+                "Offscreen surfaces can't be presented".into(),
+            ));
         };
 
+        let command_list = &interface.command_list;
+        let back_buffer: ID3D12Resource =
+            unsafe { swap_chain.GetBuffer(swap_chain.GetCurrentBackBufferIndex())? };
+
         unsafe { back_buffer.SetName(w!("Back Buffer"))? };
 
         barrier(
@@ -205,6 +259,96 @@ impl Surface {
         }
 
         interface.wait_for_gpu()?;
-        unsafe { self.swap_chain.Present(1, 0).ok() }
+        unsafe { swap_chain.Present(1, 0).ok() }
+    }
+
+    /// Copies the render target into CPU memory, submitting any work recorded so far
+    pub fn readback(&self, interface: &DeviceInterface) -> Result<Image> {
+        let command_list = &interface.command_list;
+        let desc = unsafe { self.target.GetDesc() };
+
+        let mut footprint = D3D12_PLACED_SUBRESOURCE_FOOTPRINT::default();
+        let mut total_size = 0;
+        unsafe {
+            interface.device.GetCopyableFootprints(
+                &desc,
+                0,
+                1,
+                0,
+                Some(&mut footprint),
+                None,
+                None,
+                Some(&mut total_size),
+            )
+        };
+
+        let readback: UploadResource<u8> = interface
+            .resource_factory
+            .create_readback_resource(w!("Readback Buffer"), total_size)?;
+
+        let dst = D3D12_TEXTURE_COPY_LOCATION {
+            pResource: unsafe { std::mem::transmute_copy(readback.get_resource()) },
+            Type: D3D12_TEXTURE_COPY_TYPE_PLACED_FOOTPRINT,
+            Anonymous: D3D12_TEXTURE_COPY_LOCATION_0 {
+                PlacedFootprint: footprint,
+            },
+        };
+
+        let src = D3D12_TEXTURE_COPY_LOCATION {
+            pResource: unsafe { std::mem::transmute_copy(&self.target) },
+            Type: D3D12_TEXTURE_COPY_TYPE_SUBRESOURCE_INDEX,
+            Anonymous: D3D12_TEXTURE_COPY_LOCATION_0 {
+                SubresourceIndex: 0,
+            },
+        };
+
+        barrier(
+            command_list,
+            &self.target,
+            D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
+            D3D12_RESOURCE_STATE_COPY_SOURCE,
+        );
+
+        unsafe { command_list.CopyTextureRegion(&dst, 0, 0, 0, &src, None) };
+
+        barrier(
+            command_list,
+            &self.target,
+            D3D12_RESOURCE_STATE_COPY_SOURCE,
+            D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
+        );
+
+        unsafe {
+            command_list.Close()?;
+            let command_list = Some(command_list.can_clone_into());
+            interface.queue.ExecuteCommandLists(&[command_list]);
+        }
+
+        interface.wait_for_gpu()?;
+
+        let width = desc.Width as u32;
+        let height = desc.Height;
+        let row_pitch = footprint.Footprint.RowPitch as usize;
+        let float = desc.Format == DXGI_FORMAT_R32G32B32A32_FLOAT;
+        let texel_size = if float { 16 } else { 4 };
+        let data = readback.get_buffer()?;
+
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let offset = y as usize * row_pitch + x as usize * texel_size;
+                let texel: [f32; 4] = std::array::from_fn(|c| {
+                    if float {
+                        let start = offset + c * 4;
+                        f32::from_le_bytes(std::array::from_fn(|i| data[start + i]))
+                    } else {
+                        data[offset + c] as f32 / 255.0
+                    }
+                });
+                image.set(x, y, texel);
+            }
+        }
+
+        Ok(image)
     }
 }
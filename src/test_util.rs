@@ -0,0 +1,39 @@
+//! Helpers shared by the tests of several modules
+
+use nalgebra::Vector3;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory of its own for a test, removed along with everything in it when dropped, so it's
+/// cleaned up after failing tests as well
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// An empty directory named after `test`, which has to be unique among the tests
+    pub fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tracer-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Asserts that two vectors are the same to within rounding
+pub fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+    assert!((a - b).norm() < 1e-4, "{a:?} != {b:?}");
+}
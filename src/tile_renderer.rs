@@ -0,0 +1,270 @@
+//! Renders frames a tile at a time on a work stealing thread pool. Every tile covers the same pixels
+//! and is rendered the same way whichever thread picks it up, so the frame comes out bit for bit the
+//! same with any number of threads.
+
+use crate::image::Image;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tiles are squares this wide, apart from the ones cut off by the right and bottom edges
+pub const TILE_SIZE: u32 = 32;
+
+/// A rectangle of the frame, in pixels from the top left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    /// The tiles covering a frame, row by row from the top left
+    pub fn cover(width: u32, height: u32) -> Vec<Self> {
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(TILE_SIZE as usize) {
+            for x in (0..width).step_by(TILE_SIZE as usize) {
+                tiles.push(Self {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(width - x),
+                    height: TILE_SIZE.min(height - y),
+                });
+            }
+        }
+
+        tiles
+    }
+}
+
+/// How far along a render is, reported after every tile
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub tiles_done: usize,
+    pub num_tiles: usize,
+    /// Every ray traced so far, primary or not
+    pub rays_traced: u64,
+    pub elapsed: Duration,
+    /// Extrapolated from how long the tiles done so far took
+    pub eta: Duration,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = 100 * self.tiles_done / self.num_tiles.max(1);
+        write!(
+            f,
+            "{percent:>3}% ({}/{} tiles, {:.1}M rays in {:.1}s, ETA {:.1}s)",
+            self.tiles_done,
+            self.num_tiles,
+            self.rays_traced as f64 / 1e6,
+            self.elapsed.as_secs_f32(),
+            self.eta.as_secs_f32()
+        )
+    }
+}
+
+/// Lets another thread stop a render. Clones share the flag, and once it's set every render
+/// checking it stops before starting another tile.
+#[derive(Clone, Debug, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    #[allow(dead_code)] // Frames are only traced on the thread that would cancel them so far
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rendering was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+pub struct TileRenderer {
+    pool: ThreadPool,
+}
+
+impl TileRenderer {
+    /// Starts a pool of `threads` threads, or one per core for 0
+    pub fn new(threads: usize) -> Result<Self, ThreadPoolBuildError> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("tile renderer {i}"))
+            .build()?;
+        Ok(Self { pool })
+    }
+
+    /// Renders a `width` by `height` frame with `render_tile`, which fills in a tile's pixels row
+    /// by row and returns how many rays it traced. `progress` is called after each tile, one call
+    /// at a time.
+    pub fn render(
+        &self,
+        width: u32,
+        height: u32,
+        cancel: &CancelFlag,
+        progress: &(dyn Fn(&Progress) + Sync),
+        render_tile: impl Fn(Tile, &mut [[f32; 4]]) -> u64 + Sync,
+    ) -> Result<Image, Cancelled> {
+        let tiles = Tile::cover(width, height);
+        let start = Instant::now();
+        // Tiles done and rays traced so far
+        let done = Mutex::new((0, 0));
+
+        let rendered: Option<Vec<Vec<[f32; 4]>>> = self.pool.install(|| {
+            tiles
+                .par_iter()
+                .map(|&tile| {
+                    if cancel.is_cancelled() {
+                        return None;
+                    }
+
+                    let mut pixels = vec![[0.0; 4]; (tile.width * tile.height) as usize];
+                    let rays = render_tile(tile, &mut pixels);
+
+                    let mut done = done.lock().unwrap();
+                    done.0 += 1;
+                    done.1 += rays;
+                    let elapsed = start.elapsed();
+                    progress(&Progress {
+                        tiles_done: done.0,
+                        num_tiles: tiles.len(),
+                        rays_traced: done.1,
+                        elapsed,
+                        eta: elapsed.mul_f64((tiles.len() - done.0) as f64 / done.0 as f64),
+                    });
+
+                    Some(pixels)
+                })
+                .collect()
+        });
+
+        let mut image = Image::new(width, height);
+        for (tile, pixels) in tiles.iter().zip(rendered.ok_or(Cancelled)?) {
+            for (row, pixels) in pixels.chunks_exact(tile.width as usize).enumerate() {
+                let start = ((tile.y + row as u32) * width + tile.x) as usize;
+                image.pixels[start..start + pixels.len()].copy_from_slice(pixels);
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuScene;
+    use crate::scene_model::SceneModel;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn tiles_cover_the_frame_once() {
+        let tiles = Tile::cover(100, 40);
+        assert_eq!(tiles.len(), 4 * 2);
+        assert_eq!(
+            tiles[3],
+            Tile {
+                x: 96,
+                y: 0,
+                width: 4,
+                height: 32
+            }
+        );
+        let area: u32 = tiles.iter().map(|t| t.width * t.height).sum();
+        assert_eq!(area, 100 * 40);
+        assert!(Tile::cover(0, 10).is_empty());
+    }
+
+    #[test]
+    fn output_is_the_same_on_any_number_of_threads() {
+        let mut scene = CpuScene::build(&SceneModel::default_scene());
+        scene.update(1.5);
+
+        let render = |threads| {
+            let renderer = TileRenderer::new(threads).unwrap();
+            scene
+                .render(&renderer, 203, 97, &CancelFlag::default(), &|_| {})
+                .unwrap()
+                .pixels
+        };
+
+        let single = render(1);
+        for threads in [2, 3, 8] {
+            assert!(render(threads) == single, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn progress_counts_every_tile_and_ray() {
+        let renderer = TileRenderer::new(4).unwrap();
+        let reports = Mutex::new(Vec::new());
+        let image = renderer
+            .render(
+                70,
+                70,
+                &CancelFlag::default(),
+                &|progress| reports.lock().unwrap().push(*progress),
+                |tile, pixels| {
+                    pixels.fill([tile.x as f32, tile.y as f32, 0.0, 1.0]);
+                    pixels.len() as u64
+                },
+            )
+            .unwrap();
+
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.len(), 9);
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report.tiles_done, i + 1);
+            assert_eq!(report.num_tiles, 9);
+        }
+        assert_eq!(reports[8].rays_traced, 70 * 70);
+        assert_eq!(reports[8].eta, Duration::ZERO);
+
+        assert_eq!(image.get(69, 69), [64.0, 64.0, 0.0, 1.0]);
+        assert_eq!(image.get(33, 5), [32.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn cancelling_from_another_thread_stops_the_render() {
+        let renderer = TileRenderer::new(2).unwrap();
+        let cancel = CancelFlag::default();
+        let started = AtomicUsize::new(0);
+
+        let result = thread::scope(|scope| {
+            let render = scope.spawn(|| {
+                renderer.render(640, 640, &cancel, &|_| {}, |_, _| {
+                    started.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(Duration::from_millis(5));
+                    0
+                })
+            });
+
+            while started.load(Ordering::Relaxed) == 0 {
+                thread::yield_now();
+            }
+            cancel.cancel();
+            render.join().unwrap()
+        });
+
+        assert_eq!(result.err(), Some(Cancelled));
+        // Tiles already under way finish, but no more start
+        assert!(started.into_inner() < Tile::cover(640, 640).len());
+    }
+}
@@ -0,0 +1,518 @@
+//! CPU top-level acceleration structure over instances of `Bvh`s. Instances are described the way
+//! D3D12_RAYTRACING_INSTANCE_DESC describes them, so the D3D12 scene fills its instance descs from
+//! the same `InstanceDesc`s the CPU path traces.
+
+use crate::affine::Affine3x4;
+use crate::bvh::{Aabb, Bvh, BvhOptions, Hierarchy, TriangleHit};
+use crate::ray::{is_coherent, Ray};
+use nalgebra::{Matrix4, Point3};
+
+/// InstanceID only has 24 bits
+pub const MAX_INSTANCE_ID: u32 = (1 << 24) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceDesc {
+    /// The object to world transform, laid out like the desc's Transform
+    pub transform: Affine3x4,
+    /// InstanceID in the low 24 bits and InstanceMask in the high 8, packed like `_bitfield1`
+    pub id_and_mask: u32,
+    /// Which of the BLASes this is an instance of, in place of AccelerationStructure
+    pub blas: usize,
+}
+
+impl InstanceDesc {
+    pub fn new(object_to_world: impl Into<Affine3x4>, id: u32, mask: u8, blas: usize) -> Self {
+        assert!(
+            id <= MAX_INSTANCE_ID,
+            "InstanceID {id} doesn't fit in 24 bits"
+        );
+
+        Self {
+            transform: object_to_world.into(),
+            id_and_mask: id | (mask as u32) << 24,
+            blas,
+        }
+    }
+
+    /// Equivalent of InstanceID()
+    pub fn instance_id(&self) -> u32 {
+        self.id_and_mask & MAX_INSTANCE_ID
+    }
+
+    pub fn instance_mask(&self) -> u8 {
+        (self.id_and_mask >> 24) as u8
+    }
+
+    /// Equivalent of ObjectToWorld4x3(), as a 4x4 matrix for transforming column vectors
+    pub fn object_to_world(&self) -> Matrix4<f32> {
+        self.transform.into()
+    }
+}
+
+/// A hit on one of the TLAS's instances
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceHit {
+    /// Equivalent of InstanceIndex(), the instance's position in the list the TLAS was built from
+    pub instance: usize,
+    pub triangle: TriangleHit,
+}
+
+pub struct Tlas {
+    instances: Vec<InstanceDesc>,
+    /// Equivalent of WorldToObject4x3() for each instance
+    world_to_object: Vec<Affine3x4>,
+    hierarchy: Hierarchy,
+}
+
+/// The world space bounds of `blas` under `object_to_world`
+fn world_bounds(blas: &Bvh, object_to_world: &Affine3x4) -> Aabb {
+    let bounds = blas.bounds();
+    let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|corner| {
+        let pick = |axis, min: f32, max: f32| if corner & (1 << axis) == 0 { min } else { max };
+        object_to_world.transform_point(&Point3::new(
+            pick(0, bounds.min.x, bounds.max.x),
+            pick(1, bounds.min.y, bounds.max.y),
+            pick(2, bounds.min.z, bounds.max.z),
+        ))
+    });
+
+    Aabb::from_points(&corners)
+}
+
+/// The world to object matrix and world space bounds of each of `instances`
+fn place_instances(blases: &[Bvh], instances: &[InstanceDesc]) -> (Vec<Affine3x4>, Vec<Aabb>) {
+    instances
+        .iter()
+        .map(|instance| {
+            // Instances that can't be inverted are squashed flat, so nothing can hit them anyway
+            let world_to_object = instance.transform.inverse().unwrap_or(Affine3x4::ZERO);
+
+            let bounds = world_bounds(&blases[instance.blas], &instance.transform);
+            (world_to_object, bounds)
+        })
+        .unzip()
+}
+
+impl Tlas {
+    /// Builds over `instances` of `blases`, which have to be passed back in when tracing
+    pub fn build(blases: &[Bvh], instances: Vec<InstanceDesc>) -> Self {
+        let (world_to_object, bounds) = place_instances(blases, &instances);
+        Self {
+            instances,
+            world_to_object,
+            hierarchy: Hierarchy::build(&bounds, &BvhOptions::default()),
+        }
+    }
+
+    /// Moves the instances to `instances` without rebuilding the tree, like a PERFORM_UPDATE build.
+    /// There has to be the same number of them as the TLAS was built with. BLASes that have been
+    /// refit since are picked up too.
+    pub fn refit(&mut self, blases: &[Bvh], instances: &[InstanceDesc]) {
+        assert_eq!(
+            instances.len(),
+            self.instances.len(),
+            "Refits can't change the number of instances"
+        );
+
+        let (world_to_object, bounds) = place_instances(blases, instances);
+        self.instances.copy_from_slice(instances);
+        self.world_to_object = world_to_object;
+
+        let bounds: Vec<Aabb> = self
+            .hierarchy
+            .order()
+            .iter()
+            .map(|&i| bounds[i as usize])
+            .collect();
+        self.hierarchy.refit(|i| bounds[i]);
+    }
+
+    /// See `Hierarchy::degradation`
+    pub fn degradation(&self) -> f32 {
+        self.hierarchy.degradation()
+    }
+
+    /// The instance at InstanceIndex() `index`
+    pub fn instance(&self, index: usize) -> &InstanceDesc {
+        &self.instances[index]
+    }
+
+    /// The closest hit along `ray` on instances with a mask that shares a bit with `mask`, like
+    /// TraceRay's InstanceInclusionMask
+    pub fn closest_hit(&self, blases: &[Bvh], ray: &Ray, mask: u8) -> Option<InstanceHit> {
+        self.traverse(blases, ray, mask, false)
+    }
+
+    /// `closest_hit` for each of a packet of `rays` that mostly go the same way, like the primary
+    /// rays of a tile of pixels, which share the walk through both levels of the tree. Packets that
+    /// aren't coherent are traced a ray at a time instead.
+    pub fn closest_hits(&self, blases: &[Bvh], rays: &[Ray], mask: u8) -> Vec<Option<InstanceHit>> {
+        if !is_coherent(rays) {
+            return rays
+                .iter()
+                .map(|ray| self.closest_hit(blases, ray, mask))
+                .collect();
+        }
+
+        let mut rays = rays.to_vec();
+        let mut hits = vec![None; rays.len()];
+        let mut object_rays = Vec::with_capacity(rays.len());
+        let mut blas_hits = Vec::with_capacity(rays.len());
+        self.hierarchy
+            .traverse_packet(&mut rays, |leaf, active, rays| {
+                for &i in &self.hierarchy.order()[leaf] {
+                    let i = i as usize;
+                    let instance = &self.instances[i];
+                    if instance.instance_mask() & mask == 0 {
+                        continue;
+                    }
+
+                    let world_to_object = &self.world_to_object[i];
+                    object_rays.clear();
+                    object_rays.extend(rays[active.clone()].iter().map(|ray| Ray {
+                        origin: world_to_object.transform_point(&ray.origin),
+                        direction: world_to_object.transform_vector(&ray.direction),
+                        ..*ray
+                    }));
+                    blas_hits.clear();
+                    blas_hits.resize(object_rays.len(), None);
+                    blases[instance.blas].closest_hits(&mut object_rays, &mut blas_hits);
+
+                    for (j, hit) in active.clone().zip(&blas_hits) {
+                        if let Some(triangle) = *hit {
+                            rays[j].t_max = triangle.t;
+                            hits[j] = Some(InstanceHit {
+                                instance: i,
+                                triangle,
+                            });
+                        }
+                    }
+                }
+            });
+
+        hits
+    }
+
+    /// Like `closest_hit`, but ends at the first hit found like
+    /// RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH
+    pub fn any_hit(&self, blases: &[Bvh], ray: &Ray, mask: u8) -> Option<InstanceHit> {
+        self.traverse(blases, ray, mask, true)
+    }
+
+    fn traverse(
+        &self,
+        blases: &[Bvh],
+        ray: &Ray,
+        mask: u8,
+        accept_first_hit: bool,
+    ) -> Option<InstanceHit> {
+        let mut closest = None;
+        self.hierarchy.traverse(ray, |leaf, ray| {
+            for &i in &self.hierarchy.order()[leaf] {
+                let i = i as usize;
+                let instance = &self.instances[i];
+                if instance.instance_mask() & mask == 0 {
+                    continue;
+                }
+
+                // Directions aren't normalized, so t means the same thing in both spaces
+                let world_to_object = &self.world_to_object[i];
+                let object_ray = Ray {
+                    origin: world_to_object.transform_point(&ray.origin),
+                    direction: world_to_object.transform_vector(&ray.direction),
+                    ..*ray
+                };
+
+                let blas = &blases[instance.blas];
+                let hit = if accept_first_hit {
+                    blas.any_hit(&object_ray)
+                } else {
+                    blas.closest_hit(&object_ray)
+                };
+
+                // ray.t_max shrinks with every hit, so any hit is the new closest
+                if let Some(triangle) = hit {
+                    ray.t_max = triangle.t;
+                    closest = Some(InstanceHit {
+                        instance: i,
+                        triangle,
+                    });
+
+                    if accept_first_hit {
+                        return true;
+                    }
+                }
+            }
+
+            false
+        });
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{CUBE_IDX, CUBE_VTX};
+    use nalgebra::{Similarity3, Vector3};
+
+    fn cube() -> Vec<Bvh> {
+        vec![Bvh::build(&CUBE_VTX, &CUBE_IDX, &BvhOptions::default())]
+    }
+
+    fn at(x: f32) -> Matrix4<f32> {
+        Matrix4::new_translation(&Vector3::new(x, 0.0, 0.0))
+    }
+
+    /// Straight down the Z axis at `x`
+    fn ray_at(x: f32) -> Ray {
+        Ray {
+            origin: Point3::new(x, 0.0, -10.0),
+            direction: Vector3::z(),
+            t_min: 0.001,
+            t_max: 1000.0,
+        }
+    }
+
+    #[test]
+    fn packs_id_and_mask_like_the_instance_desc() {
+        let desc = InstanceDesc::new(Matrix4::identity(), 3, 1, 0);
+        assert_eq!(desc.id_and_mask, 3 | (1 << 24));
+        assert_eq!(desc.instance_id(), 3);
+        assert_eq!(desc.instance_mask(), 1);
+
+        let desc = InstanceDesc::new(Matrix4::identity(), MAX_INSTANCE_ID, 0xFF, 0);
+        assert_eq!(desc.instance_id(), MAX_INSTANCE_ID);
+        assert_eq!(desc.instance_mask(), 0xFF);
+    }
+
+    #[test]
+    #[should_panic]
+    fn instance_ids_over_24_bits_panic() {
+        InstanceDesc::new(Matrix4::identity(), MAX_INSTANCE_ID + 1, 1, 0);
+    }
+
+    #[test]
+    fn transform_round_trips() {
+        let m = at(2.0)
+            * Matrix4::new_rotation(Vector3::new(0.1, 0.2, 0.3))
+            * Matrix4::new_scaling(2.0);
+        let desc = InstanceDesc::new(m, 0, 1, 0);
+        assert_eq!(desc.object_to_world(), m);
+
+        // Row major, so the translation is the last column of each row
+        assert_eq!(
+            [
+                desc.transform.0[3],
+                desc.transform.0[7],
+                desc.transform.0[11]
+            ],
+            [2.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn traces_instances_through_the_packed_transform() {
+        let blases = cube();
+        let turned = Similarity3::new(
+            Vector3::new(5.0, 0.0, 0.0),
+            Vector3::y() * std::f32::consts::FRAC_PI_2,
+            2.0,
+        );
+        let squashed = at(-5.0) * Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 1.0, 0.5));
+        let descs = [
+            InstanceDesc::new(turned, 0, 1, 0),
+            InstanceDesc::new(squashed, 1, 1, 0),
+        ];
+
+        // What the D3D12 scene copies into D3D12_RAYTRACING_INSTANCE_DESC::Transform
+        let gpu: Vec<[f32; 12]> = descs.iter().map(|desc| desc.transform.0).collect();
+        let unpacked = gpu
+            .iter()
+            .zip(&descs)
+            .map(|(&transform, desc)| InstanceDesc {
+                transform: Affine3x4(transform),
+                ..*desc
+            })
+            .collect();
+        let tlas = Tlas::build(&blases, unpacked);
+
+        let hit = tlas.closest_hit(&blases, &ray_at(5.0), 0xFF).unwrap();
+        assert_eq!(hit.instance, 0);
+        assert!((hit.triangle.t - 8.0).abs() < 1e-5);
+        let hit = tlas.closest_hit(&blases, &ray_at(-5.0), 0xFF).unwrap();
+        assert_eq!(hit.instance, 1);
+        assert_eq!(hit.triangle.t, 9.5);
+
+        // Out past the sides of the turned cube, which are 2 either side of its centre
+        assert!(tlas.closest_hit(&blases, &ray_at(7.1), 0xFF).is_none());
+        assert!(tlas.closest_hit(&blases, &ray_at(6.9), 0xFF).is_some());
+    }
+
+    #[test]
+    fn hits_instances_in_world_space() {
+        let blases = cube();
+        let tlas = Tlas::build(
+            &blases,
+            vec![
+                InstanceDesc::new(at(-5.0), 7, 1, 0),
+                InstanceDesc::new(at(5.0), 9, 1, 0),
+            ],
+        );
+
+        let hit = tlas.closest_hit(&blases, &ray_at(5.0), 0xFF).unwrap();
+        assert_eq!(hit.instance, 1);
+        assert_eq!(tlas.instance(hit.instance).instance_id(), 9);
+        assert_eq!(hit.triangle.t, 9.0);
+
+        assert!(tlas.closest_hit(&blases, &ray_at(0.0), 0xFF).is_none());
+    }
+
+    #[test]
+    fn closest_instance_wins() {
+        let blases = cube();
+        let behind = Matrix4::new_translation(&Vector3::new(0.0, 0.0, 5.0));
+        let tlas = Tlas::build(
+            &blases,
+            vec![
+                InstanceDesc::new(behind, 0, 1, 0),
+                InstanceDesc::new(Matrix4::identity(), 1, 1, 0),
+            ],
+        );
+
+        let hit = tlas.closest_hit(&blases, &ray_at(0.0), 0xFF).unwrap();
+        assert_eq!(hit.instance, 1);
+        assert!(tlas.any_hit(&blases, &ray_at(0.0), 0xFF).is_some());
+    }
+
+    #[test]
+    fn honours_instance_masks() {
+        let blases = cube();
+        let tlas = Tlas::build(
+            &blases,
+            vec![
+                InstanceDesc::new(at(0.0), 0, 0b01, 0),
+                InstanceDesc::new(at(0.0), 1, 0b10, 0),
+            ],
+        );
+
+        let hit = |mask| {
+            tlas.closest_hit(&blases, &ray_at(0.0), mask)
+                .map(|h| h.instance)
+        };
+        assert_eq!(hit(0b01), Some(0));
+        assert_eq!(hit(0b10), Some(1));
+        assert_eq!(hit(0), None);
+        assert!(tlas.any_hit(&blases, &ray_at(0.0), 0b100).is_none());
+    }
+
+    #[test]
+    fn refit_follows_moved_instances() {
+        let blases = cube();
+        let mut tlas = Tlas::build(
+            &blases,
+            vec![
+                InstanceDesc::new(at(-5.0), 0, 1, 0),
+                InstanceDesc::new(at(5.0), 1, 1, 0),
+            ],
+        );
+
+        let moved = [
+            InstanceDesc::new(at(-20.0), 0, 1, 0),
+            InstanceDesc::new(at(-5.0), 1, 1, 0),
+        ];
+        tlas.refit(&blases, &moved);
+        let rebuilt = Tlas::build(&blases, moved.to_vec());
+
+        for x in [-20.0, -5.0, 0.0, 5.0] {
+            let hit = tlas.closest_hit(&blases, &ray_at(x), 0xFF);
+            assert_eq!(hit, rebuilt.closest_hit(&blases, &ray_at(x), 0xFF));
+        }
+        assert_eq!(
+            tlas.closest_hit(&blases, &ray_at(-5.0), 0xFF)
+                .unwrap()
+                .instance,
+            1
+        );
+    }
+
+    #[test]
+    fn refits_degrade_as_instances_cross() {
+        let blases = cube();
+        // Instance i at slot `i * step % 8` along a row
+        let row = |step: usize| -> Vec<InstanceDesc> {
+            (0..8)
+                .map(|i| InstanceDesc::new(at((i * step % 8) as f32 * 3.0), 0, 1, 0))
+                .collect()
+        };
+
+        let mut tlas = Tlas::build(&blases, row(1));
+        assert_eq!(tlas.degradation(), 1.0);
+
+        // Every node's instances end up spread across the whole row
+        tlas.refit(&blases, &row(3));
+        assert!(tlas.degradation() > 1.5);
+
+        tlas.refit(&blases, &row(1));
+        assert!((tlas.degradation() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn empty_tlas_misses() {
+        let blases = cube();
+        let tlas = Tlas::build(&blases, Vec::new());
+        assert!(tlas.closest_hit(&blases, &ray_at(0.0), 0xFF).is_none());
+    }
+
+    #[test]
+    fn packets_hit_what_single_rays_do() {
+        let blases = cube();
+        let instances = (0..16)
+            .map(|i| {
+                let (x, y) = ((i % 4) as f32 * 3.0 - 4.5, (i / 4) as f32 * 3.0 - 4.5);
+                let m = Matrix4::new_translation(&Vector3::new(x, y, i as f32))
+                    * Matrix4::new_rotation(Vector3::new(0.3, i as f32 * 0.4, 0.0));
+                InstanceDesc::new(m, i, if i % 5 == 0 { 0b10 } else { 0b01 }, 0)
+            })
+            .collect();
+        let tlas = Tlas::build(&blases, instances);
+
+        // A tile of camera rays, and the same rays going every which way
+        let camera = Point3::new(0.0, 0.0, -40.0);
+        let tile: Vec<Ray> = (0..64)
+            .map(|i| Ray {
+                origin: camera,
+                direction: Vector3::new((i % 8) as f32 - 3.5, (i / 8) as f32 - 3.5, 40.0),
+                t_min: 0.001,
+                t_max: 1000.0,
+            })
+            .collect();
+        let scattered: Vec<Ray> = (0..64)
+            .map(|i| Ray {
+                origin: Point3::new(0.0, 0.0, 7.5),
+                direction: Vector3::new(
+                    (i % 4) as f32 * 3.0 - 4.5,
+                    (i / 4 % 4) as f32 * 3.0 - 4.5,
+                    (i / 16) as f32 * 2.0 - 3.0,
+                ),
+                t_min: 0.001,
+                t_max: 1000.0,
+            })
+            .collect();
+        assert!(is_coherent(&tile));
+        assert!(!is_coherent(&scattered));
+
+        for rays in [&tile, &scattered] {
+            for mask in [0b01, 0b11] {
+                let single: Vec<_> = rays
+                    .iter()
+                    .map(|ray| tlas.closest_hit(&blases, ray, mask))
+                    .collect();
+                assert!(single.iter().any(Option::is_some));
+                assert!(single.iter().any(Option::is_none));
+                assert_eq!(tlas.closest_hits(&blases, rays, mask), single);
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Decides whether the TLAS can be refit to this frame's instances or has to be built again. A refit
+//! is much cheaper but keeps the tree's topology, so it only works for the same instances and gets
+//! looser the further they move from where they were when the tree was built. Both backends follow
+//! the same policy, though only the CPU one can measure how loose its TLAS has got.
+
+use nalgebra::{Matrix4, Point3};
+
+/// Refits in a row before the TLAS is rebuilt anyway
+const DEFAULT_MAX_REFITS: u32 = 120;
+
+/// World units an instance can move from where it was at the last rebuild before a refit would make
+/// the tree too loose
+const DEFAULT_MAX_MOTION: f32 = 2.0;
+
+/// How many times more a refit TLAS can cost to trace than when it was built, by the SAH
+const DEFAULT_MAX_DEGRADATION: f32 = 1.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlasBuild {
+    Rebuild,
+    Refit,
+}
+
+pub struct TlasPolicy {
+    pub max_refits: u32,
+    pub max_motion: f32,
+    pub max_degradation: f32,
+    refits: u32,
+    /// The scene graph revision the TLAS was last built for
+    built_revision: Option<u64>,
+    /// Where each instance was when the TLAS was last built
+    built_origins: Vec<Point3<f32>>,
+}
+
+impl Default for TlasPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_REFITS, DEFAULT_MAX_MOTION)
+    }
+}
+
+fn origin(matrix: &Matrix4<f32>) -> Point3<f32> {
+    matrix.transform_point(&Point3::origin())
+}
+
+impl TlasPolicy {
+    pub fn new(max_refits: u32, max_motion: f32) -> Self {
+        Self {
+            max_refits,
+            max_motion,
+            max_degradation: DEFAULT_MAX_DEGRADATION,
+            refits: 0,
+            built_revision: None,
+            built_origins: Vec::new(),
+        }
+    }
+
+    /// Picks how to build the TLAS for instances at `matrices`, `revision` being the scene graph's
+    /// revision. `degradation` is how much the current TLAS has degraded, for backends that can
+    /// tell. The caller has to follow the decision, as it's remembered for the next frame.
+    pub fn decide(
+        &mut self,
+        revision: u64,
+        matrices: &[Matrix4<f32>],
+        degradation: Option<f32>,
+    ) -> TlasBuild {
+        let moved_too_far = || {
+            self.built_origins
+                .iter()
+                .zip(matrices)
+                .any(|(built, matrix)| (origin(matrix) - built).norm() > self.max_motion)
+        };
+
+        let rebuild = self.built_revision != Some(revision)
+            || self.built_origins.len() != matrices.len()
+            || self.refits >= self.max_refits
+            || degradation.is_some_and(|d| d > self.max_degradation)
+            || moved_too_far();
+
+        if rebuild {
+            self.refits = 0;
+            self.built_revision = Some(revision);
+            self.built_origins = matrices.iter().map(origin).collect();
+            TlasBuild::Rebuild
+        } else {
+            self.refits += 1;
+            TlasBuild::Refit
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn at(x: f32) -> Matrix4<f32> {
+        Matrix4::new_translation(&Vector3::new(x, 0.0, 0.0))
+    }
+
+    #[test]
+    fn first_build_is_a_rebuild() {
+        let mut policy = TlasPolicy::default();
+        assert_eq!(policy.decide(0, &[at(0.0)], None), TlasBuild::Rebuild);
+    }
+
+    #[test]
+    fn unchanged_instances_are_refit() {
+        let mut policy = TlasPolicy::default();
+        policy.decide(0, &[at(0.0), at(1.0)], None);
+        assert_eq!(
+            policy.decide(0, &[at(0.0), at(1.0)], None),
+            TlasBuild::Refit
+        );
+        assert_eq!(
+            policy.decide(0, &[at(0.5), at(1.5)], None),
+            TlasBuild::Refit
+        );
+    }
+
+    #[test]
+    fn changing_the_instances_rebuilds() {
+        let mut policy = TlasPolicy::default();
+        policy.decide(0, &[at(0.0)], None);
+        assert_eq!(
+            policy.decide(1, &[at(0.0), at(1.0)], None),
+            TlasBuild::Rebuild
+        );
+
+        // Swapping one instance for another keeps the count but still needs a rebuild
+        assert_eq!(
+            policy.decide(3, &[at(0.0), at(1.0)], None),
+            TlasBuild::Rebuild
+        );
+        assert_eq!(
+            policy.decide(3, &[at(0.0), at(1.0)], None),
+            TlasBuild::Refit
+        );
+    }
+
+    #[test]
+    fn count_mismatch_rebuilds() {
+        let mut policy = TlasPolicy::default();
+        policy.decide(0, &[at(0.0)], None);
+        assert_eq!(policy.decide(0, &[], None), TlasBuild::Rebuild);
+    }
+
+    #[test]
+    fn rebuilds_after_max_refits() {
+        let mut policy = TlasPolicy::new(3, f32::INFINITY);
+        assert_eq!(policy.decide(0, &[at(0.0)], None), TlasBuild::Rebuild);
+        for _ in 0..3 {
+            assert_eq!(policy.decide(0, &[at(0.0)], None), TlasBuild::Refit);
+        }
+
+        assert_eq!(policy.decide(0, &[at(0.0)], None), TlasBuild::Rebuild);
+        assert_eq!(policy.decide(0, &[at(0.0)], None), TlasBuild::Refit);
+    }
+
+    #[test]
+    fn large_motion_rebuilds() {
+        let mut policy = TlasPolicy::new(u32::MAX, 1.0);
+        policy.decide(0, &[at(0.0), at(5.0)], None);
+        assert_eq!(
+            policy.decide(0, &[at(0.9), at(5.0)], None),
+            TlasBuild::Refit
+        );
+        assert_eq!(
+            policy.decide(0, &[at(0.0), at(6.5)], None),
+            TlasBuild::Rebuild
+        );
+
+        // Motion is measured from the last rebuild, not the last frame
+        assert_eq!(
+            policy.decide(0, &[at(0.0), at(7.0)], None),
+            TlasBuild::Refit
+        );
+        assert_eq!(
+            policy.decide(0, &[at(0.0), at(7.4)], None),
+            TlasBuild::Refit
+        );
+        assert_eq!(
+            policy.decide(0, &[at(0.0), at(7.6)], None),
+            TlasBuild::Rebuild
+        );
+    }
+
+    #[test]
+    fn degraded_tlas_rebuilds() {
+        let mut policy = TlasPolicy::default();
+        policy.decide(0, &[at(0.0)], Some(1.0));
+        assert_eq!(policy.decide(0, &[at(0.0)], Some(1.2)), TlasBuild::Refit);
+        assert_eq!(policy.decide(0, &[at(0.0)], Some(1.6)), TlasBuild::Rebuild);
+        assert_eq!(policy.decide(0, &[at(0.0)], Some(1.0)), TlasBuild::Refit);
+    }
+}
@@ -0,0 +1,385 @@
+//! Wide bounding volume hierarchies for tracing on the CPU. A `WideHierarchy` is collapsed from a
+//! binary `Hierarchy` and keeps its leaves and leaf order, but each of its nodes has up to N
+//! children with their bounds stored side by side, so a ray is tested against all of them at once.
+//! On x86_64 the box tests run four at a time with SSE, which every x86_64 CPU has. Everywhere else
+//! they fall back to scalar code doing the same sums.
+
+use crate::bvh::{Hierarchy, NodeContents, MAX_DEPTH};
+use crate::ray::Ray;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+
+/// The widest hierarchy there can be, which sizes the traversal stack
+pub const MAX_WIDTH: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Child {
+    /// The first item of a leaf in leaf order, or the index of an interior node
+    offset: u32,
+    /// Number of items in a leaf, zero for interior nodes
+    count: u32,
+}
+
+const NO_CHILD: Child = Child {
+    offset: u32::MAX,
+    count: 0,
+};
+
+#[derive(Clone)]
+struct WideNode<const N: usize> {
+    /// The bounds of each child, by axis and then by child
+    min: [[f32; N]; 3],
+    max: [[f32; N]; 3],
+    children: [Child; N],
+    /// A bit for each slot that has a child, nodes with fewer than N children leave the last empty
+    occupied: u32,
+}
+
+#[derive(Clone)]
+pub struct WideHierarchy<const N: usize> {
+    nodes: Vec<WideNode<N>>,
+}
+
+impl<const N: usize> WideHierarchy<N> {
+    /// Collapses `binary` into a hierarchy whose nodes have up to N children each. The items are in
+    /// the same leaf order.
+    pub fn build(binary: &Hierarchy) -> Self {
+        const { assert!(N == 4 || N == 8, "Wide hierarchies are 4 or 8 wide") };
+
+        let mut hierarchy = Self { nodes: Vec::new() };
+        if binary.num_nodes() > 0 {
+            // Even a root that's a leaf gets a node, so traversal always starts at one
+            hierarchy.node(binary, &[0]);
+        }
+
+        hierarchy
+    }
+
+    /// Adds a node over the binary nodes `children`, pulling up grandchildren until there are N or
+    /// there's nothing left to pull up, and returns its index
+    fn node(&mut self, binary: &Hierarchy, children: &[usize]) -> u32 {
+        let mut children = children.to_vec();
+
+        // Opening up the biggest interior child first keeps the tree close to the binary one's SAH
+        while children.len() < N {
+            let biggest = children
+                .iter()
+                .enumerate()
+                .filter_map(|(i, &child)| match binary.node(child) {
+                    (bounds, NodeContents::Interior(grandchildren)) => {
+                        Some((i, bounds.surface_area(), grandchildren))
+                    }
+                    (_, NodeContents::Leaf(_)) => None,
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            let Some((i, _, [first, second])) = biggest else {
+                break;
+            };
+            children[i] = first;
+            children.push(second);
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(WideNode {
+            min: [[f32::INFINITY; N]; 3],
+            max: [[f32::INFINITY; N]; 3],
+            children: [NO_CHILD; N],
+            occupied: (1 << children.len()) - 1,
+        });
+
+        for (lane, &child) in children.iter().enumerate() {
+            let (bounds, contents) = binary.node(child);
+            let child = match contents {
+                NodeContents::Leaf(items) => Child {
+                    offset: items.start as u32,
+                    count: items.len() as u32,
+                },
+                NodeContents::Interior(grandchildren) => Child {
+                    offset: self.node(binary, &grandchildren),
+                    count: 0,
+                },
+            };
+
+            let node = &mut self.nodes[index];
+            for axis in 0..3 {
+                node.min[axis][lane] = bounds.min[axis];
+                node.max[axis][lane] = bounds.max[axis];
+            }
+            node.children[lane] = child;
+        }
+
+        index as u32
+    }
+
+    /// Calls `leaf` with the leaf order positions of the items in each leaf `ray` reaches, roughly
+    /// front to back, like `Hierarchy::traverse`
+    pub fn traverse(&self, ray: &Ray, mut leaf: impl FnMut(Range<usize>, &mut Ray) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_direction = ray.inv_direction();
+        let origin = ray.origin.coords.into();
+        let inv_direction = inv_direction.into();
+        let mut ray = *ray;
+
+        // Children still to visit, with where the ray enters them, the nearest on top. Each node
+        // visited on the way down leaves at most N - 1 of its children behind. The stack is left
+        // uninitialized, clearing all of it for every ray costs as much as tracing the ray.
+        let mut stack = [const { MaybeUninit::<(Child, f32)>::uninit() }; MAX_DEPTH * MAX_WIDTH];
+        stack[0].write((
+            Child {
+                offset: 0,
+                count: 0,
+            },
+            ray.t_min,
+        ));
+        let mut stack_size = 1;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            // Safety: everything below stack_size has been written
+            let (child, t) = unsafe { stack[stack_size].assume_init() };
+
+            // Hits found since the child was pushed may have put it out of reach
+            if t > ray.t_max {
+                continue;
+            }
+
+            if child.count > 0 {
+                let first = child.offset as usize;
+                if leaf(first..first + child.count as usize, &mut ray) {
+                    return;
+                }
+
+                continue;
+            }
+
+            let node = &self.nodes[child.offset as usize];
+            let mut near = [0.0; N];
+            let mut hits = 0;
+            for chunk in 0..N / 4 {
+                let lanes = chunk * 4..chunk * 4 + 4;
+                let boxes = [0, 1, 2].map(|axis| {
+                    let min = node.min[axis][lanes.clone()].try_into().unwrap();
+                    let max = node.max[axis][lanes.clone()].try_into().unwrap();
+                    (min, max)
+                });
+                let (chunk_near, chunk_hits) =
+                    intersect4(&boxes, origin, inv_direction, ray.t_min, ray.t_max);
+                near[lanes].copy_from_slice(&chunk_near);
+                hits |= chunk_hits << (chunk * 4);
+            }
+            hits &= node.occupied;
+
+            // Push the children that were hit furthest first, so the nearest gets popped next
+            let first = stack_size;
+            while hits != 0 {
+                let lane = hits.trailing_zeros() as usize;
+                hits &= hits - 1;
+
+                let entry = (node.children[lane], near[lane]);
+                let mut i = stack_size;
+                // Safety: everything below stack_size has been written
+                while i > first && unsafe { stack[i - 1].assume_init() }.1 < entry.1 {
+                    stack[i] = stack[i - 1];
+                    i -= 1;
+                }
+                stack[i].write(entry);
+                stack_size += 1;
+            }
+        }
+    }
+}
+
+/// The bounds of four boxes along each axis, as the minimums and maximums of each box
+type Boxes4 = [([f32; 4], [f32; 4]); 3];
+
+/// Slab tests four boxes at once, like `Aabb::intersect`, returning where the ray enters each box
+/// and a mask with a bit set for each box it enters between `t_min` and `t_max`.
+/// `inv_direction` has to be finite.
+#[cfg(target_arch = "x86_64")]
+fn intersect4(
+    boxes: &Boxes4,
+    origin: [f32; 3],
+    inv_direction: [f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> ([f32; 4], u32) {
+    use std::arch::x86_64::*;
+
+    // Safety: SSE is part of x86_64, so these are always available
+    unsafe {
+        let mut near = _mm_set1_ps(t_min);
+        let mut far = _mm_set1_ps(t_max);
+        for axis in 0..3 {
+            let (min, max) = &boxes[axis];
+            let o = _mm_set1_ps(origin[axis]);
+            let inv = _mm_set1_ps(inv_direction[axis]);
+            let t0 = _mm_mul_ps(_mm_sub_ps(_mm_loadu_ps(min.as_ptr()), o), inv);
+            let t1 = _mm_mul_ps(_mm_sub_ps(_mm_loadu_ps(max.as_ptr()), o), inv);
+            near = _mm_max_ps(near, _mm_min_ps(t0, t1));
+            far = _mm_min_ps(far, _mm_max_ps(t0, t1));
+        }
+
+        let mut entry = [0.0; 4];
+        _mm_storeu_ps(entry.as_mut_ptr(), near);
+        let hits = _mm_movemask_ps(_mm_cmple_ps(near, far)) as u32;
+        (entry, hits)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn intersect4(
+    boxes: &Boxes4,
+    origin: [f32; 3],
+    inv_direction: [f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> ([f32; 4], u32) {
+    intersect4_scalar(boxes, origin, inv_direction, t_min, t_max)
+}
+
+#[cfg(any(not(target_arch = "x86_64"), test))]
+fn intersect4_scalar(
+    boxes: &Boxes4,
+    origin: [f32; 3],
+    inv_direction: [f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> ([f32; 4], u32) {
+    let mut entry = [0.0; 4];
+    let mut hits = 0;
+    for lane in 0..4 {
+        let (mut near, mut far) = (t_min, t_max);
+        for axis in 0..3 {
+            let (min, max) = &boxes[axis];
+            let t0 = (min[lane] - origin[axis]) * inv_direction[axis];
+            let t1 = (max[lane] - origin[axis]) * inv_direction[axis];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+
+        entry[lane] = near;
+        if near <= far {
+            hits |= 1 << lane;
+        }
+    }
+
+    (entry, hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::{Aabb, BvhOptions};
+    use nalgebra::Point3;
+    use proptest::prelude::*;
+
+    fn point(extent: f32) -> impl Strategy<Value = Point3<f32>> {
+        [-extent..extent, -extent..extent, -extent..extent].prop_map(Point3::from)
+    }
+
+    fn boxes(points: &[(Point3<f32>, Point3<f32>)]) -> Vec<Aabb> {
+        points
+            .iter()
+            .map(|(a, b)| Aabb::from_points(&[*a, *b]))
+            .collect()
+    }
+
+    /// Every leaf item a traversal is given, in the order it's given them
+    fn visited(ray: &Ray, traverse: impl Fn(&Ray, &mut dyn FnMut(Range<usize>))) -> Vec<usize> {
+        let mut items = Vec::new();
+        traverse(ray, &mut |leaf| items.extend(leaf));
+        items.sort();
+        items
+    }
+
+    fn ray(origin: Point3<f32>, target: Point3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: target - origin,
+            t_min: 0.0,
+            t_max: f32::INFINITY,
+        }
+    }
+
+    fn visited_by<const N: usize>(wide: &WideHierarchy<N>, ray: &Ray) -> Vec<usize> {
+        visited(ray, |ray, visit| {
+            wide.traverse(ray, |leaf, _| {
+                visit(leaf);
+                false
+            });
+        })
+    }
+
+    proptest! {
+        /// Without any culling both widths visit exactly the leaves the binary hierarchy visits
+        #[test]
+        fn wide_hierarchies_visit_the_same_items(
+            corners in prop::collection::vec((point(10.0), point(10.0)), 1..100),
+            max_leaf_size in 1..6usize,
+            origin in point(12.0),
+            target in point(12.0),
+        ) {
+            let options = BvhOptions { max_leaf_size, ..Default::default() };
+            let binary = Hierarchy::build(&boxes(&corners), &options);
+            let ray = ray(origin, target);
+
+            let expected = visited(&ray, |ray, visit| {
+                binary.traverse(ray, |leaf, _| {
+                    visit(leaf);
+                    false
+                });
+            });
+            prop_assert_eq!(visited_by(&WideHierarchy::<4>::build(&binary), &ray), expected.clone());
+            prop_assert_eq!(visited_by(&WideHierarchy::<8>::build(&binary), &ray), expected);
+        }
+
+        #[test]
+        fn sse_matches_scalar(
+            corners in prop::array::uniform4((point(10.0), point(10.0))),
+            origin in point(12.0),
+            inv_direction in [-1e3f32..1e3, -1e3f32..1e3, -1e3f32..1e3],
+            t_min in 0.0f32..5.0,
+            t_max in 0.0f32..50.0,
+        ) {
+            let boxes: Boxes4 = [0, 1, 2].map(|axis| {
+                (
+                    corners.map(|(a, b)| a[axis].min(b[axis])),
+                    corners.map(|(a, b)| a[axis].max(b[axis])),
+                )
+            });
+            let origin = origin.coords.into();
+            let simd = intersect4(&boxes, origin, inv_direction, t_min, t_max);
+            let scalar = intersect4_scalar(&boxes, origin, inv_direction, t_min, t_max);
+            prop_assert_eq!(simd, scalar);
+        }
+    }
+
+    /// Rays parallel to the slabs of a box get in when they start between them and miss otherwise
+    #[test]
+    fn axis_parallel_rays() {
+        let unit = Aabb::from_points(&[Point3::origin(), Point3::new(1.0, 1.0, 1.0)]);
+        let binary = Hierarchy::build(&[unit], &BvhOptions::default());
+        let wide = WideHierarchy::<4>::build(&binary);
+
+        let along_x = |y, z| ray(Point3::new(-1.0, y, z), Point3::new(2.0, y, z));
+        assert_eq!(visited_by(&wide, &along_x(0.5, 0.5)), vec![0]);
+        assert_eq!(visited_by(&wide, &along_x(1e-6, 1.0 - 1e-6)), vec![0]);
+        assert_eq!(visited_by(&wide, &along_x(1.5, 0.5)), Vec::<usize>::new());
+        assert_eq!(visited_by(&wide, &along_x(0.5, -1e-6)), Vec::<usize>::new());
+
+        let backwards = ray(Point3::new(2.0, 0.5, 0.5), Point3::new(3.0, 0.5, 0.5));
+        assert_eq!(visited_by(&wide, &backwards), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn empty_hierarchies_visit_nothing() {
+        let binary = Hierarchy::build(&[], &BvhOptions::default());
+        let wide = WideHierarchy::<4>::build(&binary);
+        let ray = ray(Point3::origin(), Point3::new(1.0, 0.0, 0.0));
+        wide.traverse(&ray, |_, _| panic!("There are no leaves"));
+    }
+}